@@ -27,12 +27,25 @@ fn algorithm_indexer_canister(config: AlgorithmIndexerConfig) -> proc_macro2::To
         .iter()
         .map(|val| {
             let name = format_ident!("{}", val.name);
+            let key = val.key_type.as_ref().map(|key| {
+                let key: syn::Type = syn::parse_str(key).expect("Failed to parse key_type");
+                quote! { , #key }
+            });
+            let indexes = val.indexes.as_ref().map(|indexes| {
+                let fields = indexes.iter().map(|index| {
+                    let field = format_ident!("{}", index.field);
+                    let ty: syn::Type =
+                        syn::parse_str(&index.type_).expect("Failed to parse type_ of index");
+                    quote! { #field: #ty }
+                });
+                quote! { , index(#(#fields),*) }
+            });
             match val.type_ {
                 AlgorithmOutputType::KeyValue => {
-                    quote! { generate_queries_for_key_value_store_struct!(#name) }
+                    quote! { generate_queries_for_key_value_store_struct!(#name #key #indexes) }
                 }
                 AlgorithmOutputType::KeyValues => {
-                    quote! { generate_queries_for_key_values_store_struct!(#name) }
+                    quote! { generate_queries_for_key_values_store_struct!(#name #key #indexes) }
                 }
             }
         })
@@ -56,7 +69,7 @@ fn algorithm_indexer_canister(config: AlgorithmIndexerConfig) -> proc_macro2::To
         // NOTE: The memory id in canister is used from a number that does not duplicate the memory id declared in the storage module of the cdk.
         // https://github.com/horizonx-tech/chainsight-sdk/blob/8aa1d1dd1cb8e3d0adde2fa9d27f374d430f663a/chainsight-cdk/src/storage/storage.rs#L97
         init_in!(11);
-        prepare_stable_structure!();
+        prepare_stable_structure!(export(stores));
         stable_memory_for_scalar!("target_addr", String, 12, false);
         setup_func!({ target_addr: String, config: IndexingConfig }, 13);
         timer_task_func!("set_task", "index", 14);
@@ -104,7 +117,7 @@ mod test {
     use chainsight_cdk::{
         config::components::{
             AlgorithmIndexerInput, AlgorithmIndexerOutput, AlgorithmIndexerOutputIdentifier,
-            CommonConfig,
+            AlgorithmIndexerOutputIndex, CommonConfig,
         },
         indexer::IndexingConfig,
     };
@@ -122,6 +135,8 @@ mod test {
             indexing: IndexingConfig {
                 start_from: 1222222,
                 chunk_size: None,
+                confirmations: None,
+                instruction_budget: None,
             },
             input: AlgorithmIndexerInput {
                 method_name: "get_list".to_string(),
@@ -133,10 +148,17 @@ mod test {
                     AlgorithmIndexerOutputIdentifier {
                         name: "OutputType1".to_string(),
                         type_: AlgorithmOutputType::KeyValue,
+                        key_type: None,
+                        indexes: Some(vec![AlgorithmIndexerOutputIndex {
+                            field: "owner".to_string(),
+                            type_: "String".to_string(),
+                        }]),
                     },
                     AlgorithmIndexerOutputIdentifier {
                         name: "OutputType2".to_string(),
                         type_: AlgorithmOutputType::KeyValues,
+                        key_type: Some("(candid::Principal, u64)".to_string()),
+                        indexes: None,
                     },
                 ],
             },
@@ -11,7 +11,7 @@ use syn::parse_macro_input;
 
 use crate::canisters::utils::camel_to_snake;
 
-use super::utils::extract_contract_name_from_path;
+use super::utils::{extract_contract_name_from_path, retention_args};
 
 pub fn def_event_indexer_canister(input: TokenStream) -> TokenStream {
     let input_json_string: String = parse_macro_input!(input as syn::LitStr).value();
@@ -20,8 +20,21 @@ pub fn def_event_indexer_canister(input: TokenStream) -> TokenStream {
     event_indexer_canister(config).into()
 }
 
+/// Memory id of the index of the first indexed field, next to those of the canister and below the cdk's own.
+const INDEX_MEMORY_ID_START: u8 = 20;
+/// Indexes fit between `INDEX_MEMORY_ID_START` and the memory ids reserved by the cdk.
+const MAX_INDEXES: usize = 9;
+
+fn index_memory_ids(indexes: &Option<Vec<String>>) -> Vec<u8> {
+    let count = indexes.as_ref().map_or(0, |indexes| indexes.len());
+    if count > MAX_INDEXES {
+        panic!("At most {} fields of an event can be indexed", MAX_INDEXES);
+    }
+    (INDEX_MEMORY_ID_START..).take(count).collect()
+}
+
 fn event_indexer_canister(config: EventIndexerConfig) -> proc_macro2::TokenStream {
-    let common = common_code(&config.common);
+    let common = common_code(&config.common, &index_memory_ids(&config.indexes));
     let custom = custom_code(config);
 
     quote! {
@@ -30,8 +43,16 @@ fn event_indexer_canister(config: EventIndexerConfig) -> proc_macro2::TokenStrea
     }
 }
 
-fn common_code(common: &CommonConfig) -> proc_macro2::TokenStream {
+fn common_code(common: &CommonConfig, index_memory_ids: &[u8]) -> proc_macro2::TokenStream {
     let CommonConfig { canister_name } = common;
+    let prepare_stable_structure = if index_memory_ids.is_empty() {
+        quote! { prepare_stable_structure!(export(stores)); }
+    } else {
+        let ids = index_memory_ids
+            .iter()
+            .map(|id| proc_macro2::Literal::u8_unsuffixed(*id));
+        quote! { prepare_stable_structure!(export(stores, indexes = [#(#ids),*])); }
+    };
 
     quote! {
         use candid::{CandidType, Decode, Encode};
@@ -64,7 +85,7 @@ fn common_code(common: &CommonConfig) -> proc_macro2::TokenStream {
         define_web3_ctx!(12);
         define_transform_for_web3!();
         define_get_ethereum_address!();
-        prepare_stable_structure!();
+        #prepare_stable_structure
         stable_memory_for_scalar!("target_addr", String, 13, false);
         setup_func!({
             target_addr: String,
@@ -83,7 +104,17 @@ fn custom_code(config: EventIndexerConfig) -> proc_macro2::TokenStream {
                 identifier,
                 abi_file_path,
             },
+        retention,
+        indexes,
     } = config;
+    if retention
+        .as_ref()
+        .is_some_and(|r| r.policy.max_age_secs.is_some())
+    {
+        panic!("retention.max_age_secs is not supported by event indexers, as events have no timestamp");
+    }
+    let retention_args = retention_args(&retention);
+    let index_memory_ids = index_memory_ids(&indexes);
 
     let contract_struct_name_ident =
         format_ident!("{}", extract_contract_name_from_path(&abi_file_path));
@@ -103,12 +134,32 @@ fn custom_code(config: EventIndexerConfig) -> proc_macro2::TokenStream {
     };
 
     let call_func_ident = format_ident!("event_{}", camel_to_snake(&event.name));
+    let index_args = indexes.map(|indexes| {
+        let fields = indexes
+            .iter()
+            .zip(index_memory_ids)
+            .map(|(field, memory_id)| {
+                let param = event
+                    .inputs
+                    .iter()
+                    .find(|input| &input.name == field)
+                    .unwrap_or_else(|| panic!("Event {} has no field {}", &event.name, field));
+                let field_ident = format_ident!("{}", field);
+                let ty =
+                    convert_event_param_type_to_field_ty_ident(&param.kind).unwrap_or_else(|_| {
+                        panic!("Failed to convert the type of the indexed field {}", field)
+                    });
+                let memory_id = proc_macro2::Literal::u8_unsuffixed(memory_id);
+                quote! { #field_ident: #ty = #memory_id }
+            });
+        quote! { , index(#(#fields),*) }
+    });
     let (event_struct_ident, event_struct) = generate_event_struct(event);
 
     quote! {
         ic_solidity_bindgen::contract_abi!(#abi_file_path);
         web3_event_indexer_source!(#event_struct_ident);
-        web3_event_indexer!(#event_struct_ident, 16);
+        web3_event_indexer!(#event_struct_ident, 16 #index_args #retention_args);
         #event_struct
 
         fn get_logs(
@@ -130,6 +181,23 @@ fn custom_code(config: EventIndexerConfig) -> proc_macro2::TokenStream {
                 }
             }.boxed()
         }
+
+        fn get_block_header(
+            number: u64,
+            call_options: CallOptions,
+        ) -> BoxFuture<'static, Result<Option<chainsight_cdk::web3::BlockHeader>, chainsight_cdk::indexer::Error>> {
+            async move {
+                chainsight_cdk::web3::find_block_header(&get_web3_ctx_param().url, number, call_options).await
+            }.boxed()
+        }
+
+        fn get_chain_head(
+            call_options: CallOptions,
+        ) -> BoxFuture<'static, Result<u64, chainsight_cdk::indexer::Error>> {
+            async move {
+                chainsight_cdk::web3::find_block_number(&get_web3_ctx_param().url, call_options).await
+            }.boxed()
+        }
     }
 }
 
@@ -175,15 +243,14 @@ fn generate_event_struct(event: ethabi::Event) -> (proc_macro2::Ident, proc_macr
 
 fn convert_event_param_type_to_field_ty_ident(
     param_type: &ethabi::ParamType,
-) -> anyhow::Result<proc_macro2::Ident> {
+) -> anyhow::Result<syn::Type> {
     let field_ty =
         convert_type_from_ethabi_param_type(param_type).map_err(|e| anyhow::anyhow!(e))?;
-    let field_ty_ident = match field_ty.as_str() {
-        ADDRESS_TYPE => format_ident!("String"),
-        U256_TYPE => format_ident!("U256"),
-        _ => format_ident!("{}", field_ty),
-    };
-    Ok(field_ty_ident)
+    // addresses and uint256, also as elements of arrays and tuples, are held as String and chainsight's U256
+    let field_ty = field_ty
+        .replace(ADDRESS_TYPE, "String")
+        .replace(U256_TYPE, "U256");
+    Ok(syn::parse_str(&field_ty)?)
 }
 
 #[cfg(test)]
@@ -205,6 +272,8 @@ mod test {
                 abi_file_path: "examples/minimum_indexers/src/event_indexer/abi/ERC20.json"
                     .to_string(),
             },
+            retention: None,
+            indexes: None,
         };
         let generated = event_indexer_canister(config);
         let formatted = RustFmt::default()
@@ -212,4 +281,47 @@ mod test {
             .expect("rustfmt failed");
         assert_snapshot!("snapshot__event_indexer", formatted);
     }
+
+    #[test]
+    fn test_snapshot_with_indexes() {
+        let config = EventIndexerConfig {
+            common: CommonConfig {
+                canister_name: "app".to_string(),
+            },
+            def: EventIndexerEventDefinition {
+                identifier: "Transfer".to_string(),
+                abi_file_path: "examples/minimum_indexers/src/event_indexer/abi/ERC20.json"
+                    .to_string(),
+            },
+            retention: None,
+            indexes: Some(vec!["to".to_string()]),
+        };
+        let generated = event_indexer_canister(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__event_indexer_with_indexes", formatted);
+    }
+
+    #[test]
+    fn test_config_with_retention() {
+        let config: EventIndexerConfig = serde_json::from_str(
+            r#"{
+                "common": { "canister_name": "app" },
+                "def": { "identifier": "Transfer", "abi_file_path": "ERC20.json" },
+                "retention": { "max_block_distance": 7200, "prune_interval_secs": 3600, "max_prune_per_call": 500 }
+            }"#,
+        )
+        .unwrap();
+        let retention = config.retention.unwrap();
+        assert_eq!(retention.policy.max_block_distance, Some(7200));
+        assert_eq!(retention.policy.max_entries, None);
+        assert_eq!(retention.prune_interval_secs, Some(3600));
+        assert_eq!(retention.max_prune_per_call, Some(500));
+        assert_eq!(
+            retention_args(&Some(retention)).to_string(),
+            quote! { , retention(max_block_distance = 7200, prune_interval_secs = 3600, max_prune_per_call = 500) }
+                .to_string()
+        );
+    }
 }
@@ -3,10 +3,11 @@ use std::ops::Deref;
 use candid::types::{internal::is_primitive, Type, TypeInner};
 use chainsight_cdk::{
     config::components::{
-        LensParameter, RelayerConfig, RelayerConversionParameter, LENS_FUNCTION_ARGS_TYPE,
+        LensParameter, RelayerConfig, RelayerConversionParameter, RelayerDestination,
+        LENS_FUNCTION_ARGS_TYPE,
     },
     convert::candid::{extract_elements, get_candid_type_from_str},
-    web3::ContractFunction,
+    web3::{ContractFunction, EIP1559FeeConfig, GasBudget, RelayThreshold},
 };
 use proc_macro::TokenStream;
 use proc_macro2::Ident;
@@ -42,13 +43,20 @@ fn custom_code(config: RelayerConfig) -> proc_macro2::TokenStream {
         lens_parameter,
         conversion_parameter,
         method_name,
+        fee_parameter,
+        threshold_parameter,
+        destinations,
+        gas_budget_parameter,
         ..
     } = config;
 
     let canister_name_ident = format_ident!("{}", common.canister_name);
     let (source_method_name, _, canister_response_type) =
         extract_elements(&method_identifier).expect("Failed to extract_elements");
-    let call_option_ident = call_option();
+    let destinations = destinations.unwrap_or_default();
+    let destinations_ident = relay_destinations(&method_name, gas_budget_parameter, &destinations);
+    let (measure_ident, threshold_ident) = threshold(threshold_parameter);
+    let call_option_ident = call_option(fee_parameter);
 
     let call_args_ident =
         inter_canister_call_args_ident(canister_name_ident.clone(), lens_parameter.clone());
@@ -91,28 +99,50 @@ fn custom_code(config: RelayerConfig) -> proc_macro2::TokenStream {
             (quote! { datum }, quote! { datum })
         };
 
-    let contract_call = ContractCall::new(ContractFunction::new(
-        abi_file_path.clone(),
-        method_name.clone(),
-    ));
-    let method_call_ident = method_call(
-        contract_call.clone(),
-        &abi_file_path.clone(),
-        &canister_response_type,
-        canister_name_ident.clone(),
-    );
+    let relay_idents = std::iter::once(method_name)
+        .chain(destinations.into_iter().map(|d| d.method_name))
+        .enumerate()
+        .map(|(i, method_name)| {
+            let contract_call =
+                ContractCall::new(ContractFunction::new(abi_file_path.clone(), method_name));
+            let method_call_ident = method_call(
+                contract_call,
+                &abi_file_path.clone(),
+                &canister_response_type,
+                canister_name_ident.clone(),
+            );
+            relay(i, &threshold_ident, &call_option_ident, method_call_ident)
+        });
 
     let generated = quote! {
         ic_solidity_bindgen::contract_abi!(#abi_file_path);
         use #canister_name_ident::{CallCanisterResponse, filter};
         #call_args_ident
         #source_ident
+        #destinations_ident
         #[ic_cdk::update]
         #[candid::candid_method(update)]
         async fn index() {
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted");
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
+            let relay_tracker = relay_tracker();
+            let key_name = get_env().ecdsa_key_name();
+            let sender = ethereum_address(key_name.clone()).await.expect("Failed to get ethereum address");
+            let destinations = relay_destinations();
+            for destination in destinations.iter() {
+                if let Err(e) = relay_tracker.poll(&destination.rpc_url, destination.chain_id, &destination.address, sender).await {
+                    ic_cdk::println!("Failed to poll relays to {} on chain {}: {:?}", destination.address, destination.chain_id, e);
+                }
+            }
+            relay_tracker.prune();
             let target_canister = candid::Principal::from_text(get_target_canister()).expect("Failed to parse to candid::Principal");
             let call_result = CallProvider::new()
                 .call(
@@ -133,25 +163,275 @@ fn custom_code(config: RelayerConfig) -> proc_macro2::TokenStream {
             ic_cdk::println!("val extracted from response = {:?}", datum.clone());
             let datum = #converted_datum_ident;
             ic_cdk::println!("val converted from extracted = {:?}", datum.clone());
-
-            #call_option_ident
-            #method_call_ident
+            #measure_ident
+            #(#relay_idents)*
         }
     };
 
     generated
 }
 
-fn call_option() -> proc_macro2::TokenStream {
+fn call_option(fee_parameter: Option<EIP1559FeeConfig>) -> proc_macro2::TokenStream {
+    let fee_config_ident = match fee_parameter {
+        Some(EIP1559FeeConfig {
+            block_count,
+            reward_percentile,
+            base_fee_multiplier,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        }) => {
+            let max_fee_per_gas = optional_ident(max_fee_per_gas);
+            let max_priority_fee_per_gas = optional_ident(max_priority_fee_per_gas);
+            quote! {
+                .with_fee_config(chainsight_cdk::web3::EIP1559FeeConfig {
+                    block_count: #block_count,
+                    reward_percentile: #reward_percentile,
+                    base_fee_multiplier: #base_fee_multiplier,
+                    max_fee_per_gas: #max_fee_per_gas,
+                    max_priority_fee_per_gas: #max_priority_fee_per_gas,
+                })
+            }
+        }
+        None => quote! {},
+    };
     quote! {
-        let w3_ctx_param = get_web3_ctx_param();
         let call_option_builder = chainsight_cdk::web3::EVMTransactionOptionBuilder::new(
-            w3_ctx_param.url,
-            w3_ctx_param.chain_id,
-            w3_ctx_param.env.ecdsa_key_name(),
-        );
+            destination.rpc_url.clone(),
+            destination.chain_id,
+            key_name.clone(),
+        )
+        .with_sender_address(sender)
+        .with_nonce_manager(nonce_manager())
+        #fee_config_ident;
+        let call_option_builder = match &stuck {
+            Some((_, relay)) => call_option_builder.with_nonce(relay.nonce),
+            None => call_option_builder,
+        };
         use chainsight_cdk::web3::TransactionOptionBuilder;
-        let call_option = call_option_builder.build().await.expect("Failed to build call_option");
+        let call_option = call_option_builder.build().await.map_err(|e| e.to_string())?;
+        let call_option = match &stuck {
+            Some((id, relay)) => {
+                ic_cdk::println!("replacing stuck relay {} with nonce {}", id, relay.nonce);
+                call_option.map(|option| relay_tracker.replacement_options(relay, option))
+            }
+            None => call_option,
+        };
+    }
+}
+
+fn relay_destinations(
+    method_name: &str,
+    gas_budget: Option<GasBudget>,
+    destinations: &[RelayerDestination],
+) -> proc_macro2::TokenStream {
+    let primary_gas_budget_ident = gas_budget_ident(gas_budget);
+    let destination_idents = destinations.iter().map(
+        |RelayerDestination {
+             chain_id,
+             rpc_url,
+             address,
+             method_name,
+             gas_budget,
+         }| {
+            let gas_budget_ident = gas_budget_ident(gas_budget.clone());
+            quote! {
+                chainsight_cdk::config::components::RelayerDestination {
+                    chain_id: #chain_id,
+                    rpc_url: #rpc_url.to_string(),
+                    address: #address.to_string(),
+                    method_name: #method_name.to_string(),
+                    gas_budget: #gas_budget_ident,
+                }
+            }
+        },
+    );
+    quote! {
+        fn relay_destinations() -> Vec<chainsight_cdk::config::components::RelayerDestination> {
+            let w3_ctx_param = get_web3_ctx_param();
+            vec![
+                chainsight_cdk::config::components::RelayerDestination {
+                    chain_id: w3_ctx_param.chain_id,
+                    rpc_url: w3_ctx_param.url,
+                    address: get_target_addr(),
+                    method_name: #method_name.to_string(),
+                    gas_budget: #primary_gas_budget_ident,
+                },
+                #(#destination_idents),*
+            ]
+        }
+
+        #[ic_cdk::query]
+        #[candid::candid_method(query)]
+        fn get_relay_destinations() -> Vec<chainsight_cdk::web3::RelayDestinationStatus> {
+            let relay_tracker = relay_tracker();
+            relay_destinations()
+                .into_iter()
+                .map(|d| relay_tracker.destination_status(d.chain_id, d.address, &d.method_name))
+                .collect()
+        }
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        async fn get_gas_budgets() -> Result<Vec<chainsight_cdk::web3::GasBudgetState>, String> {
+            let relay_tracker = relay_tracker();
+            let sender = ethereum_address(get_env().ecdsa_key_name()).await?;
+            let since = chainsight_cdk::time::TimeStamper::now_sec().saturating_sub(chainsight_cdk::web3::GAS_BUDGET_WINDOW_SECS);
+            Ok(relay_destinations()
+                .into_iter()
+                .map(|d| {
+                    let spent = relay_tracker.spent_since(d.chain_id, sender, since);
+                    d.gas_budget.unwrap_or_default().state(d.chain_id, format!("0x{}", hex::encode(sender)), d.address, spent)
+                })
+                .collect())
+        }
+    }
+}
+
+fn gas_budget_ident(gas_budget: Option<GasBudget>) -> proc_macro2::TokenStream {
+    match gas_budget {
+        Some(GasBudget {
+            max_gas_price,
+            max_priority_fee_per_gas,
+            daily_budget,
+            gas_limit,
+        }) => {
+            let max_gas_price = optional_ident(max_gas_price);
+            let max_priority_fee_per_gas = optional_ident(max_priority_fee_per_gas);
+            let daily_budget = optional_ident(daily_budget);
+            let gas_limit = optional_ident(gas_limit);
+            quote! {
+                Some(chainsight_cdk::web3::GasBudget {
+                    max_gas_price: #max_gas_price,
+                    max_priority_fee_per_gas: #max_priority_fee_per_gas,
+                    daily_budget: #daily_budget,
+                    gas_limit: #gas_limit,
+                })
+            }
+        }
+        None => quote! { None },
+    }
+}
+
+/// Relays the value to the i-th destination; failures are logged so that other destinations are still relayed to.
+fn relay(
+    index: usize,
+    threshold_ident: &proc_macro2::TokenStream,
+    call_option_ident: &proc_macro2::TokenStream,
+    method_call_ident: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    quote! {
+        let destination = &destinations[#index];
+        let relayed: Result<u64, String> = async {
+            let stuck = relay_tracker.stuck(destination.chain_id, &destination.address);
+            #threshold_ident
+            #call_option_ident
+            let call_option = match &destination.gas_budget {
+                Some(gas_budget) => {
+                    let call_option = call_option.map(|option| gas_budget.limit_gas(option));
+                    let since = chainsight_cdk::time::TimeStamper::now_sec().saturating_sub(chainsight_cdk::web3::GAS_BUDGET_WINDOW_SECS);
+                    let spent = relay_tracker.spent_since(destination.chain_id, sender, since);
+                    if let Err(e) = gas_budget.check(&call_option.clone().unwrap_or_default(), spent) {
+                        return Err(format!("gas budget exceeded: {}", e));
+                    }
+                    call_option
+                }
+                None => call_option,
+            };
+            let web3_ctx = ic_solidity_bindgen::Web3Context::new(
+                &destination.rpc_url,
+                sender,
+                destination.chain_id,
+                key_name.clone(),
+                None,
+            ).map_err(|e| e.to_string())?;
+            // reserved last so that any failure before the transaction is sent releases it on drop
+            let nonce_reservation = call_option_builder.reserve_nonce().await.map_err(|e| e.to_string())?;
+            let call_option = match &nonce_reservation {
+                Some(reservation) => call_option.map(|mut option| {
+                    option.nonce = Some(reservation.nonce().into());
+                    option
+                }),
+                None => call_option,
+            };
+            let relay_option = call_option.clone().unwrap_or_default();
+            #method_call_ident
+            let (tx_hash, send_error) = result;
+            let send_error = send_error.map(|e| e.to_string());
+            // the signed transaction may have been broadcast even if sending failed,
+            // so its nonce is kept until the pending count passes it or the gap timeout resyncs it
+            if let Some(reservation) = nonce_reservation {
+                reservation.commit();
+            }
+            let relay_id = relay_tracker.record(
+                destination.chain_id,
+                sender,
+                &destination.address,
+                tx_hash,
+                &relay_option,
+                measured,
+                send_error.clone(),
+            );
+            // a failed relay leaves the stuck one in place to be replaced on the next attempt
+            if let Some(e) = send_error {
+                return Err(format!("relay {} failed: tx_hash={:?}, {}", relay_id, tx_hash, e));
+            }
+            if let Some((stuck_id, _)) = stuck {
+                relay_tracker.replace(stuck_id, relay_id);
+            }
+            ic_cdk::println!("relay {} sent: tx_hash={:?}", relay_id, tx_hash);
+            Ok(relay_id)
+        }.await;
+        if let Err(e) = relayed {
+            ic_cdk::println!("Not relayed to {} on chain {}: {}", destination.address, destination.chain_id, e);
+        }
+    }
+}
+
+/// Returns the measurement of the value, and the check skipping destinations where it is within the threshold.
+fn threshold(
+    threshold_parameter: Option<RelayThreshold>,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let RelayThreshold {
+        relative_deviation,
+        absolute_deviation,
+        heartbeat_sec,
+    } = match threshold_parameter {
+        Some(threshold_parameter) => threshold_parameter,
+        None => {
+            return (
+                quote! {
+                    let measured: Option<f64> = None;
+                },
+                quote! {},
+            )
+        }
+    };
+    let relative_deviation = optional_ident(relative_deviation);
+    let absolute_deviation = optional_ident(absolute_deviation);
+    let heartbeat_sec = optional_ident(heartbeat_sec);
+    (
+        quote! {
+            let measured = chainsight_cdk::convert::scalar::Measurable::measure(&datum);
+            let threshold = chainsight_cdk::web3::RelayThreshold {
+                relative_deviation: #relative_deviation,
+                absolute_deviation: #absolute_deviation,
+                heartbeat_sec: #heartbeat_sec,
+            };
+        },
+        // a stuck relay is replaced with the current value even if it is within the threshold
+        quote! {
+            let last_relayed = relay_tracker.last_relayed(destination.chain_id, &destination.address);
+            if stuck.is_none() && !threshold.is_hit(&last_relayed, measured, chainsight_cdk::time::TimeStamper::now_sec()) {
+                return Err(format!("value within threshold = {:?}", measured));
+            }
+        },
+    )
+}
+
+fn optional_ident<T: quote::ToTokens>(value: Option<T>) -> proc_macro2::TokenStream {
+    match value {
+        Some(value) => quote! { Some(#value) },
+        None => quote! { None },
     }
 }
 
@@ -231,9 +511,9 @@ fn method_call(
         0 => {
             quote! {
                 let result = #oracle_ident::new(
-                    Address::from_str(&get_target_addr()).expect("Failed to parse target addr to Address"),
-                    &relayer_web3_ctx().await.expect("Failed to get web3_ctx")
-                ).#oracle_func_ident(call_option).await.expect("Failed to call update_state for oracle");
+                    Address::from_str(&destination.address).map_err(|e| e.to_string())?,
+                    &web3_ctx
+                ).#oracle_func_ident(call_option).await.map_err(|e| e.to_string())?;
             }
         }
         1 => {
@@ -248,9 +528,9 @@ fn method_call(
             };
             quote! {
                 let result = #oracle_ident::new(
-                    Address::from_str(&get_target_addr()).expect("Failed to parse target addr to Address"),
-                    &relayer_web3_ctx().await.expect("Failed to get web3_ctx")
-                ).#oracle_func_ident(#data, call_option).await.expect("Failed to call update_state for oracle");
+                    Address::from_str(&destination.address).map_err(|e| e.to_string())?,
+                    &web3_ctx
+                ).#oracle_func_ident(#data, call_option).await.map_err(|e| e.to_string())?;
                 ic_cdk::println!("value_to_sync={:?}", result);
             }
         }
@@ -263,9 +543,9 @@ fn method_call(
             quote! {
                 let value =  #canister_name_ident::convert(&datum.clone());
                 let result = #oracle_ident::new(
-                    Address::from_str(&get_target_addr()).expect("Failed to parse target addr to Address"),
-                    &relayer_web3_ctx().await.expect("Failed to get web3_ctx")
-                ).#oracle_func_ident(#(value.#args_ident),*, call_option).await.expect("Failed to call update_state for oracle");
+                    Address::from_str(&destination.address).map_err(|e| e.to_string())?,
+                    &web3_ctx
+                ).#oracle_func_ident(#(value.#args_ident),*, call_option).await.map_err(|e| e.to_string())?;
             }
         }
     }
@@ -317,7 +597,7 @@ fn common_code(config: RelayerConfig) -> proc_macro2::TokenStream {
         use candid::{Decode, Encode};
         use ic_cdk::api::call::result;
         use std::str::FromStr;
-        use chainsight_cdk_macros::{manage_single_state, setup_func, init_in, timer_task_func, define_web3_ctx, define_relayer_web3_ctx, define_transform_for_web3, define_get_ethereum_address, chainsight_common, did_export, prepare_stable_structure, stable_memory_for_scalar, StableMemoryStorable, CborSerde, relayer_source};
+        use chainsight_cdk_macros::{manage_single_state, setup_func, init_in, timer_task_func, define_web3_ctx, define_relayer_web3_ctx, define_transform_for_web3, define_get_ethereum_address, define_nonce_manager, define_relay_tracker, chainsight_common, did_export, prepare_stable_structure, stable_memory_for_scalar, StableMemoryStorable, CborSerde, relayer_source};
         use chainsight_cdk::rpc::{CallProvider, Caller, Message};
         use chainsight_cdk::web3::Encoder;
         use chainsight_cdk::convert::scalar::{Convertible, Scalable};
@@ -330,7 +610,9 @@ fn common_code(config: RelayerConfig) -> proc_macro2::TokenStream {
         stable_memory_for_scalar!("target_addr", String, 3, false);
         define_get_ethereum_address!();
         stable_memory_for_scalar!("target_canister", String, 4, false);
-        prepare_stable_structure!();
+        prepare_stable_structure!(export(memories = [8, 9, 10]));
+        define_nonce_manager!(8);
+        define_relay_tracker!(9, 10);
         timer_task_func!("set_task", "index", 7);
         init_in!(1);
         setup_func!({
@@ -402,6 +684,10 @@ mod test {
             method_name: "update_state".to_string(),
             conversion_parameter: None,
             lens_parameter: None,
+            fee_parameter: None,
+            threshold_parameter: None,
+            destinations: None,
+            gas_budget_parameter: None,
         }
     }
 
@@ -436,6 +722,58 @@ mod test {
         assert_snapshot!("snapshot__relayer__with_lens_with_args", formatted);
     }
 
+    #[test]
+    fn test_snapshot_with_fee_parameter() {
+        let mut config = config();
+        config.fee_parameter = Some(EIP1559FeeConfig {
+            reward_percentile: 75.0,
+            max_fee_per_gas: Some(100_000_000_000),
+            ..Default::default()
+        });
+        let generated = relayer_canister(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__relayer__with_fee_parameter", formatted);
+    }
+
+    #[test]
+    fn test_snapshot_with_threshold_parameter() {
+        let mut config = config();
+        config.threshold_parameter = Some(RelayThreshold {
+            relative_deviation: Some(0.005),
+            absolute_deviation: None,
+            heartbeat_sec: Some(3600),
+        });
+        let generated = relayer_canister(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__relayer__with_threshold_parameter", formatted);
+    }
+
+    #[test]
+    fn test_snapshot_with_destinations() {
+        let mut config = config();
+        config.destinations = Some(vec![RelayerDestination {
+            chain_id: 137,
+            rpc_url: "https://polygon-rpc.com".to_string(),
+            address: "0x539a0EF3F5bA0bA7b0a0f5c0E0d5a3b0a0c0d0e0".to_string(),
+            method_name: "update_state".to_string(),
+            gas_budget: Some(GasBudget {
+                max_gas_price: Some(500_000_000_000),
+                daily_budget: Some(10_000_000_000_000_000_000),
+                gas_limit: Some(200_000),
+                ..Default::default()
+            }),
+        }]);
+        let generated = relayer_canister(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__relayer__with_destinations", formatted);
+    }
+
     #[test]
     fn test_snapshot_with_extracted_val_from_response() {
         let mut config = config();
@@ -1,8 +1,13 @@
 use std::{borrow::Borrow, str::FromStr as _};
 
 use anyhow::Error;
-use chainsight_cdk::config::components::{CommonConfig, SnapshotIndexerEVMConfig};
-use ic_web3_rs::ethabi::{ParamType, StateMutability};
+use chainsight_cdk::{
+    config::components::{
+        CommonConfig, RetentionConfig, SnapshotIndexerEVMCall, SnapshotIndexerEVMConfig,
+    },
+    convert::evm::convert_candid_type_from_ethabi_param_type,
+};
+use ic_web3_rs::ethabi::{Function, ParamType, StateMutability, Token};
 use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span};
 use quote::{format_ident, quote, ToTokens as _};
@@ -10,6 +15,7 @@ use syn::parse_macro_input;
 
 use crate::canisters::utils::{
     camel_to_snake, extract_contract_name_from_path, generate_queries_without_timestamp,
+    snapshot_retention,
 };
 
 pub fn def_snapshot_indexer_evm(input: TokenStream) -> TokenStream {
@@ -20,7 +26,7 @@ pub fn def_snapshot_indexer_evm(input: TokenStream) -> TokenStream {
 }
 
 fn snapshot_indexer_evm(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenStream {
-    let common = common_code(&config.common);
+    let common = common_code(&config.common, &config.retention);
     let custom = custom_code(config);
     quote! {
         #common
@@ -28,8 +34,13 @@ fn snapshot_indexer_evm(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenS
     }
 }
 
-fn common_code(config: &CommonConfig) -> proc_macro2::TokenStream {
+fn common_code(
+    config: &CommonConfig,
+    retention: &Option<RetentionConfig>,
+) -> proc_macro2::TokenStream {
     let CommonConfig { canister_name } = config;
+    let (retention_args, retainable_impl) =
+        snapshot_retention(retention, quote! { self.timestamp });
 
     quote! {
         use std::str::FromStr;
@@ -51,8 +62,9 @@ fn common_code(config: &CommonConfig) -> proc_macro2::TokenStream {
             web3_ctx_param: chainsight_cdk::web3::Web3CtxParam
         }, 5);
 
-        prepare_stable_structure!();
-        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true);
+        prepare_stable_structure!(export(memories = [1]));
+        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true #retention_args);
+        #retainable_impl
         timer_task_func!("set_task", "index", 6);
     }
 }
@@ -62,43 +74,25 @@ fn custom_code(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenStream {
         method_identifier,
         method_args,
         abi_file_path,
+        calls,
+        multicall_address,
         ..
     } = config;
 
-    let abi_bytes = std::fs::read(&abi_file_path)
-        .map_err(|e| anyhow::anyhow!("Failed to load abi: {}", e))
-        .unwrap();
-    let contract = ic_web3_rs::ethabi::Contract::load(&abi_bytes[..])
-        .map_err(|e| anyhow::anyhow!("Failed to parse abi: {}", e))
-        .unwrap();
+    let calls = calls.unwrap_or_default();
+    if !calls.is_empty() {
+        let primary = SnapshotIndexerEVMCall {
+            address: None,
+            method_identifier,
+            method_args,
+            abi_file_path: None,
+        };
+        let calls = std::iter::once(primary).chain(calls).collect::<Vec<_>>();
+        return multicall_code(&abi_file_path, calls, multicall_address);
+    }
 
-    let signature: String = method_identifier
-        .chars()
-        .filter(|c| !c.is_whitespace())
-        .collect();
-    let name = signature
-        .split('(')
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Invalid function identifier: {}", signature))
-        .unwrap();
-    let functions = contract
-        .functions_by_name(name)
-        .unwrap_or_else(|_| panic!("function not found. name: {}", name));
-    let function = functions
-        .iter()
-        .find(|f| f.signature() == signature)
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "function not found. identifier: {}, available: {}",
-                signature,
-                functions
-                    .iter()
-                    .map(|f| f.signature())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            )
-        })
-        .unwrap();
+    let function = load_function(&abi_file_path, &method_identifier);
+    let name = function.name.as_str();
 
     let method_ident_str = camel_to_snake(name);
     let method_ident = format_ident!("{}", method_ident_str);
@@ -130,7 +124,7 @@ fn custom_code(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenStream {
     let response_types: Vec<proc_macro2::TokenStream> = function
         .outputs
         .iter()
-        .map(|p| to_candid_type(&p.kind).0)
+        .map(|p| to_candid_type(&p.kind))
         .collect();
 
     let response_values = to_candid_values(
@@ -169,6 +163,13 @@ fn custom_code(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenStream {
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
 
             let current_ts_sec = ic_cdk::api::time() / 1000000;
             let res = #contract_struct_ident::new(
@@ -187,6 +188,173 @@ fn custom_code(config: SnapshotIndexerEVMConfig) -> proc_macro2::TokenStream {
     }
 }
 
+fn load_function(abi_file_path: &str, method_identifier: &str) -> Function {
+    let abi_bytes = std::fs::read(abi_file_path)
+        .map_err(|e| anyhow::anyhow!("Failed to load abi: {}", e))
+        .unwrap();
+    let contract = ic_web3_rs::ethabi::Contract::load(&abi_bytes[..])
+        .map_err(|e| anyhow::anyhow!("Failed to parse abi: {}", e))
+        .unwrap();
+
+    let signature: String = method_identifier
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let name = signature
+        .split('(')
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Invalid function identifier: {}", signature))
+        .unwrap();
+    let functions = contract
+        .functions_by_name(name)
+        .unwrap_or_else(|_| panic!("function not found. name: {}", name));
+    functions
+        .iter()
+        .find(|f| f.signature() == signature)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "function not found. identifier: {}, available: {}",
+                signature,
+                functions
+                    .iter()
+                    .map(|f| f.signature())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .unwrap()
+        .clone()
+}
+
+fn multicall_code(
+    abi_file_path: &str,
+    calls: Vec<SnapshotIndexerEVMCall>,
+    multicall_address: Option<String>,
+) -> proc_macro2::TokenStream {
+    let multicall_address =
+        multicall_address.unwrap_or_else(|| chainsight_cdk::web3::MULTICALL3_ADDRESS.to_string());
+
+    let queries = generate_queries_without_timestamp(format_ident!("SnapshotValue"));
+
+    let mut value_types = vec![];
+    let mut sources = vec![];
+    let mut call3s = vec![];
+    let mut decodes = vec![];
+    let mut values = vec![];
+    for (i, call) in calls.iter().enumerate() {
+        let call_abi_file_path = call.abi_file_path.as_deref().unwrap_or(abi_file_path);
+        let function = load_function(call_abi_file_path, &call.method_identifier);
+        assert!(function.inputs.len() == call.method_args.len(), "datatource.method is not valid: The number of params in 'identifier' and 'args' must be the same");
+        assert!(
+            !function.outputs.is_empty(),
+            "function without outputs can not be snapshotted: {}",
+            function.signature()
+        );
+
+        let tokens = serde_to_ethabi_tokens(
+            function
+                .inputs
+                .iter()
+                .map(|p| p.kind.clone())
+                .collect::<Vec<_>>()
+                .as_slice(),
+            &call.method_args,
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to parse args: {}", e))
+        .unwrap();
+        let call_data = function
+            .encode_input(&tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to encode args: {}", e))
+            .unwrap()
+            .into_iter()
+            .map(proc_macro2::Literal::u8_unsuffixed);
+
+        let func_name = camel_to_snake(&function.name);
+        let target = match &call.address {
+            Some(address) => {
+                sources.push(quote! { (#address, #func_name) });
+                quote! { Address::from_str(#address).expect("Failed to parse call addr to Address") }
+            }
+            None => {
+                sources.push(quote! { #func_name });
+                quote! { Address::from_str(&get_target_addr()).expect("Failed to parse target addr to Address") }
+            }
+        };
+        call3s.push(quote! { chainsight_cdk::web3::Call3::new(#target, vec![#(#call_data),*]) });
+
+        let outputs = function
+            .outputs
+            .iter()
+            .map(|p| p.kind.clone())
+            .collect::<Vec<_>>();
+        let response_types = outputs.iter().map(to_candid_type);
+        let rust_types = outputs.iter().map(to_rust_type);
+        let (value_type, rust_type) = if outputs.len() == 1 {
+            (quote! { #(#response_types)* }, quote! { #(#rust_types)* })
+        } else {
+            (
+                quote! { (#(#response_types),*) },
+                quote! { (#(#rust_types),*) },
+            )
+        };
+        value_types.push(value_type);
+        let output_types = outputs.iter().map(|k| k.to_string());
+        let res_ident = format_ident!("res_{}", i);
+        let idx_lit = proc_macro2::Literal::usize_unsuffixed(i);
+        let decode_error = format!("Failed to decode the result of {}", function.signature());
+        decodes.push(quote! {
+            let #res_ident: #rust_type = results[#idx_lit].decode(&[#(#output_types),*]).expect(#decode_error);
+        });
+        values.push(to_candid_values(&outputs, quote! { #res_ident }));
+    }
+
+    quote! {
+        #[derive(Debug, Clone, candid::CandidType, candid::Deserialize, serde::Serialize, StableMemoryStorable)]
+        pub struct Snapshot {
+            pub value: SnapshotValue,
+            pub timestamp: u64,
+        }
+        type SnapshotValue = (#(#value_types),*);
+
+        #queries
+
+        snapshot_indexer_web3_source!(#(#sources),*);
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        async fn index() {
+            if ic_cdk::caller() != proxy() {
+                panic!("Not permitted")
+            }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
+
+            let current_ts_sec = ic_cdk::api::time() / 1000000;
+            let calls = vec![#(#call3s),*];
+            let results = chainsight_cdk::web3::aggregate3(
+                &get_web3_ctx_param().url,
+                Address::from_str(#multicall_address).expect("Failed to parse multicall addr to Address"),
+                &calls,
+                Default::default(),
+            ).await.expect("Failed to call contracts");
+            #(#decodes)*
+
+            let datum = Snapshot {
+                value: (#(#values,)*),
+                timestamp: current_ts_sec,
+            };
+            add_snapshot(datum.clone());
+
+            ic_cdk::println!("timestamp={}, value={:?}", datum.timestamp, datum.value);
+        }
+    }
+}
+
 pub fn serde_to_token_streams(
     inputs: &[ParamType],
     method_args: &[serde_json::Value],
@@ -332,56 +500,156 @@ fn ident<S: Borrow<str>>(name: S) -> Ident {
     Ident::new(name.borrow(), Span::call_site())
 }
 
-fn to_candid_type(kind: &ParamType) -> (proc_macro2::TokenStream, usize) {
+/// Converts the args in the config to ethabi tokens to encode the call data at compile time.
+fn serde_to_ethabi_tokens(
+    inputs: &[ParamType],
+    method_args: &[serde_json::Value],
+) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    for (input, value) in inputs.iter().zip(method_args) {
+        let t = match input {
+            ParamType::Bool => Token::Bool(
+                value
+                    .as_bool()
+                    .ok_or_else(|| anyhow::anyhow!("Expected bool: {}", value))?,
+            ),
+            ParamType::Uint(_) => Token::Uint(match value.as_str() {
+                Some(value_str) => match value_str.strip_prefix("0x") {
+                    Some(stripped) => ic_web3_rs::types::U256::from_str_radix(stripped, 16)?,
+                    None => ic_web3_rs::types::U256::from_dec_str(value_str)?,
+                },
+                None => ic_web3_rs::types::U256::from(
+                    value
+                        .as_u64()
+                        .ok_or_else(|| anyhow::anyhow!("Expected uint: {}", value))?,
+                ),
+            }),
+            ParamType::Int(_) => {
+                let val = match value.as_str() {
+                    Some(value_str) => match value_str.strip_prefix("0x") {
+                        Some(stripped) => i128::from_str_radix(stripped, 16)?,
+                        None => i128::from_str(value_str)?,
+                    },
+                    None => value
+                        .as_i64()
+                        .ok_or_else(|| anyhow::anyhow!("Expected int: {}", value))?
+                        as i128,
+                };
+                // two's complement
+                Token::Int(if val < 0 {
+                    !ic_web3_rs::types::U256::from((-(val + 1)) as u128)
+                } else {
+                    ic_web3_rs::types::U256::from(val as u128)
+                })
+            }
+            ParamType::Address => Token::Address(ic_web3_rs::types::Address::from_str(
+                value
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Expected address: {}", value))?,
+            )?),
+            ParamType::Tuple(param_types) => Token::Tuple(serde_to_ethabi_tokens(
+                param_types,
+                value
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("Expected array: {}", value))?,
+            )?),
+            ParamType::FixedArray(param_type, _) | ParamType::Array(param_type) => {
+                let args = value
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("Expected array: {}", value))?;
+                let param_types = vec![param_type.as_ref().clone(); args.len()];
+                let elems = serde_to_ethabi_tokens(&param_types, args)?;
+                match input {
+                    ParamType::Array(_) => Token::Array(elems),
+                    _ => Token::FixedArray(elems),
+                }
+            }
+            ParamType::FixedBytes(_) => Token::FixedBytes(serde_to_bytes(value)?),
+            ParamType::Bytes => Token::Bytes(serde_to_bytes(value)?),
+            ParamType::String => Token::String(
+                value
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Expected string: {}", value))?
+                    .to_string(),
+            ),
+        };
+        tokens.push(t);
+    }
+    Ok(tokens)
+}
+
+fn serde_to_bytes(value: &serde_json::Value) -> Result<Vec<u8>, Error> {
+    if let Some(value_str) = value.as_str() {
+        return Ok(match value_str.strip_prefix("0x") {
+            Some(stripped) => hex::decode(stripped)?,
+            None => value_str.as_bytes().to_vec(),
+        });
+    }
+    value
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("Unexpected value for bytes: {}", value))?
+        .iter()
+        .map(|v| {
+            v.as_u64()
+                .map(|b| b as u8)
+                .ok_or_else(|| anyhow::anyhow!("Unexpected value for bytes: {}", value))
+        })
+        .collect()
+}
+
+/// The Rust type of a value decoded from the output of a call aggregated by Multicall3,
+/// following ic_solidity_bindgen except for small uints, which are decoded as is into the types of `to_candid_type`.
+fn to_rust_type(kind: &ParamType) -> proc_macro2::TokenStream {
     match kind {
-        ParamType::Bool => (quote! { bool }, 0),
-        ParamType::Uint(size) => match size {
-            129..=256 => (quote! {  ::std::string::String }, 0),
-            65..=128 => (ident("u128").to_token_stream(), 0),
-            33..=64 => (ident("u64").to_token_stream(), 0),
-            17..=32 => (ident("u32").to_token_stream(), 0),
-            9..=16 => (ident("u16").to_token_stream(), 0),
-            1..=8 => (ident("u8").to_token_stream(), 0),
-            _ => (quote! { ::ic_solidity_bindgen::internal::Unimplemented }, 0),
-        },
+        ParamType::Address => quote! { ::ic_web3_rs::types::Address },
+        ParamType::Bytes => quote! { ::std::vec::Vec<u8> },
         ParamType::Int(size) => match size {
-            129..=256 => (quote! {  ::std::string::String }, 0),
-            65..=128 => (ident("i128").to_token_stream(), 0),
-            33..=64 => (ident("i64").to_token_stream(), 0),
-            17..=32 => (ident("i32").to_token_stream(), 0),
-            9..=16 => (ident("i16").to_token_stream(), 0),
-            1..=8 => (ident("i8").to_token_stream(), 0),
-            _ => (quote! { ::ic_solidity_bindgen::internal::Unimplemented }, 0),
+            129..=256 => quote! { ::ic_web3_rs::types::I256 },
+            65..=128 => ident("i128").to_token_stream(),
+            33..=64 => ident("i64").to_token_stream(),
+            17..=32 => ident("i32").to_token_stream(),
+            9..=16 => ident("i16").to_token_stream(),
+            1..=8 => ident("i8").to_token_stream(),
+            _ => quote! { ::ic_solidity_bindgen::internal::Unimplemented },
         },
-        ParamType::Address => (quote! { ::std::string::String }, 0),
-        ParamType::Tuple(members) => match members.len() {
-            0 => (quote! { ::ic_solidity_bindgen::internal::Empty }, 1),
-            _ => {
-                let members: Vec<_> = members.iter().map(to_candid_type).collect();
-                // Unwrap is ok because in this branch there must be at least 1 item.
-                let nesting = 1 + members.iter().map(|(_, n)| *n).max().unwrap();
-                let types = members.iter().map(|(ty, _)| ty);
-                (quote! { (#(#types,)*) }, nesting)
-            }
+        ParamType::Uint(size) => match size {
+            129..=256 => quote! { ::ic_web3_rs::types::U256 },
+            65..=128 => ident("u128").to_token_stream(),
+            33..=64 => ident("u64").to_token_stream(),
+            17..=32 => ident("u32").to_token_stream(),
+            9..=16 => ident("u16").to_token_stream(),
+            1..=8 => ident("u8").to_token_stream(),
+            _ => quote! { ::ic_solidity_bindgen::internal::Unimplemented },
         },
-        ParamType::FixedBytes(len) => (quote! { [ u8; #len ] }, 0),
-        ParamType::Bytes => (quote! { ::std::vec::Vec<u8> }, 0),
-        ParamType::FixedArray(inner, _) => {
-            let (inner, nesting) = to_candid_type(inner);
-            (quote! { ::std::vec::Vec<#inner> }, nesting)
-        }
+        ParamType::Bool => quote! { bool },
+        ParamType::String => quote! { ::std::string::String },
         ParamType::Array(inner) => {
-            let (inner, nesting) = to_candid_type(inner);
-            if nesting > 0 {
-                (quote! { ::ic_solidity_bindgen::internal::Unimplemented }, 0)
-            } else {
-                (quote! { ::std::vec::Vec<#inner> }, nesting)
-            }
+            let inner = to_rust_type(inner);
+            quote! { ::std::vec::Vec<#inner> }
+        }
+        ParamType::FixedBytes(len) => quote! { [u8; #len] },
+        ParamType::FixedArray(inner, len) => {
+            let inner = to_rust_type(inner);
+            quote! { [#inner; #len] }
+        }
+        ParamType::Tuple(members) => {
+            let members = members.iter().map(to_rust_type);
+            quote! { (#(#members,)*) }
         }
-        ParamType::String => (quote! { ::std::string::String }, 0),
     }
 }
 
+/// Candid type of an output as mapped by `convert_candid_type_from_ethabi_param_type`,
+/// `Unimplemented` for the types it doesn't support.
+fn to_candid_type(kind: &ParamType) -> proc_macro2::TokenStream {
+    ethabi::param_type::Reader::read(&kind.to_string())
+        .map_err(|e| e.to_string())
+        .and_then(|param| convert_candid_type_from_ethabi_param_type(&param))
+        .and_then(|ty| syn::parse_str::<syn::Type>(&ty).map_err(|e| e.to_string()))
+        .map(|ty| ty.to_token_stream())
+        .unwrap_or_else(|_| quote! { ::ic_solidity_bindgen::internal::Unimplemented })
+}
+
 fn to_candid_values(
     outputs: &[ParamType],
     accessor: proc_macro2::TokenStream,
@@ -473,7 +741,7 @@ fn should_convert(kind: &ParamType) -> bool {
 #[cfg(test)]
 mod test {
     use chainsight_cdk::config::components::CommonConfig;
-    use insta::assert_display_snapshot;
+    use insta::{assert_display_snapshot, assert_snapshot};
     use rust_format::{Formatter, RustFmt};
     use serde_json::json;
 
@@ -489,6 +757,9 @@ mod test {
             method_args: vec![],
             abi_file_path: "examples/minimum_indexers/src/snapshot_indexer_evm/abi/ERC20.json"
                 .to_string(),
+            calls: None,
+            multicall_address: None,
+            retention: None,
         };
         let generated = snapshot_indexer_evm(config);
         let formatted = RustFmt::default()
@@ -497,6 +768,85 @@ mod test {
         assert_display_snapshot!("snapshot__snapshot_indexer_evm", formatted);
     }
 
+    #[test]
+    fn test_snapshot_with_calls() {
+        let config = SnapshotIndexerEVMConfig {
+            common: CommonConfig {
+                canister_name: "sample_snapshot_indexer_evm".to_string(),
+            },
+            method_identifier: "totalSupply():(uint256)".to_string(),
+            method_args: vec![],
+            abi_file_path: "examples/minimum_indexers/src/snapshot_indexer_evm/abi/ERC20.json"
+                .to_string(),
+            calls: Some(vec![
+                SnapshotIndexerEVMCall {
+                    address: None,
+                    method_identifier: "balanceOf(address):(uint256)".to_string(),
+                    method_args: vec![json!("0x0000000000000000000000000000000000000001")],
+                    abi_file_path: None,
+                },
+                SnapshotIndexerEVMCall {
+                    address: Some("0x6B175474E89094C44Da98b954EedeAC495271d0F".to_string()),
+                    method_identifier: "allowance(address,address):(uint256)".to_string(),
+                    method_args: vec![
+                        json!("0x0000000000000000000000000000000000000001"),
+                        json!("0x0000000000000000000000000000000000000002"),
+                    ],
+                    abi_file_path: Some(
+                        "examples/minimum_indexers/src/snapshot_indexer_evm/abi/ERC20.json"
+                            .to_string(),
+                    ),
+                },
+            ]),
+            multicall_address: None,
+            retention: None,
+        };
+        let generated = snapshot_indexer_evm(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__snapshot_indexer_evm_with_calls", formatted);
+    }
+
+    #[test]
+    fn test_serde_to_ethabi_tokens() {
+        let inputs = vec![
+            ParamType::Bool,
+            ParamType::Uint(256),
+            ParamType::Uint(64),
+            ParamType::Int(128),
+            ParamType::Address,
+            ParamType::Array(Box::new(ParamType::Uint(8))),
+            ParamType::Tuple(vec![ParamType::String, ParamType::Bytes]),
+        ];
+        let method_args = vec![
+            json!(true),
+            json!("0x100000000000000000000000000000000"),
+            json!(1),
+            json!(-1),
+            json!("0x0000000000000000000000000000000000000001"),
+            json!([1, 2]),
+            json!(["chainsight", "0x0102"]),
+        ];
+        let tokens = serde_to_ethabi_tokens(&inputs, &method_args).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Bool(true),
+                Token::Uint(ic_web3_rs::types::U256::from(u128::MAX) + 1),
+                Token::Uint(1.into()),
+                Token::Int(ic_web3_rs::types::U256::MAX),
+                Token::Address(ic_web3_rs::types::Address::from_low_u64_be(1)),
+                Token::Array(vec![Token::Uint(1.into()), Token::Uint(2.into())]),
+                Token::Tuple(vec![
+                    Token::String("chainsight".to_string()),
+                    Token::Bytes(vec![1, 2]),
+                ]),
+            ]
+        );
+        assert!(serde_to_ethabi_tokens(&[ParamType::Bool], &[json!(1)]).is_err());
+    }
+
     #[test]
     fn test_serde_to_token_streams_bool() {
         assert_eq!(
@@ -784,98 +1134,69 @@ mod test {
 
     #[test]
     fn test_to_candid_type() {
-        assert_eq!(to_candid_type(&ParamType::Bool).0.to_string(), "bool");
-        assert_eq!(to_candid_type(&ParamType::Uint(8)).0.to_string(), "u8");
-        assert_eq!(to_candid_type(&ParamType::Uint(16)).0.to_string(), "u16");
-        assert_eq!(to_candid_type(&ParamType::Uint(32)).0.to_string(), "u32");
-        assert_eq!(to_candid_type(&ParamType::Uint(64)).0.to_string(), "u64");
-        assert_eq!(to_candid_type(&ParamType::Uint(128)).0.to_string(), "u128");
-        assert_eq!(
-            to_candid_type(&ParamType::Uint(136)).0.to_string(),
-            ":: std :: string :: String"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Uint(256)).0.to_string(),
-            ":: std :: string :: String"
-        );
-        assert_eq!(to_candid_type(&ParamType::Int(8)).0.to_string(), "i8");
-        assert_eq!(to_candid_type(&ParamType::Int(16)).0.to_string(), "i16");
-        assert_eq!(to_candid_type(&ParamType::Int(32)).0.to_string(), "i32");
-        assert_eq!(to_candid_type(&ParamType::Int(64)).0.to_string(), "i64");
-        assert_eq!(to_candid_type(&ParamType::Int(128)).0.to_string(), "i128");
-        assert_eq!(
-            to_candid_type(&ParamType::Int(136)).0.to_string(),
-            ":: std :: string :: String"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Int(256)).0.to_string(),
-            ":: std :: string :: String"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Address).0.to_string(),
-            ":: std :: string :: String"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Tuple(vec![])).0.to_string(),
-            ":: ic_solidity_bindgen :: internal :: Empty"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Tuple(vec![ParamType::Uint(8), ParamType::Bool]))
-                .0
-                .to_string(),
-            "(u8 , bool ,)"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Tuple(vec![
-                ParamType::Bool,
-                ParamType::Tuple(vec![ParamType::Bool])
-            ]))
-            .0
-            .to_string(),
-            "(bool , (bool ,) ,)"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::FixedBytes(8)).0.to_string(),
-            "[u8 ; 8usize]"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::FixedBytes(256)).0.to_string(),
-            "[u8 ; 256usize]"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Bytes).0.to_string(),
-            ":: std :: vec :: Vec < u8 >"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::FixedArray(Box::new(ParamType::Uint(64)), 2))
-                .0
-                .to_string(),
-            ":: std :: vec :: Vec < u64 >"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Array(Box::new(ParamType::Uint(256))))
-                .0
-                .to_string(),
-            ":: std :: vec :: Vec < :: std :: string :: String >"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::Array(Box::new(ParamType::FixedArray(
-                Box::new(ParamType::Uint(64)),
-                2
-            ))))
-            .0
-            .to_string(),
-            ":: std :: vec :: Vec < :: std :: vec :: Vec < u64 > >"
-        );
-        assert_eq!(
-            to_candid_type(&ParamType::FixedArray(
-                Box::new(ParamType::Array(Box::new(ParamType::Uint(64)))),
-                2
-            ))
-            .0
-            .to_string(),
-            ":: std :: vec :: Vec < :: std :: vec :: Vec < u64 > >"
-        );
+        let cases = [
+            (ParamType::Bool, "bool"),
+            (ParamType::Uint(8), "u8"),
+            (ParamType::Uint(16), "u16"),
+            (ParamType::Uint(32), "u32"),
+            (ParamType::Uint(64), "u64"),
+            (ParamType::Uint(128), "u128"),
+            (ParamType::Uint(136), "String"),
+            (ParamType::Uint(256), "String"),
+            (ParamType::Int(8), "i8"),
+            (ParamType::Int(16), "i16"),
+            (ParamType::Int(32), "i32"),
+            (ParamType::Int(64), "i64"),
+            (ParamType::Int(128), "i128"),
+            (ParamType::Int(136), "String"),
+            (ParamType::Int(256), "String"),
+            (ParamType::Address, "String"),
+            (
+                ParamType::Tuple(vec![]),
+                ":: ic_solidity_bindgen :: internal :: Unimplemented",
+            ),
+            (
+                ParamType::Tuple(vec![ParamType::Uint(8), ParamType::Bool]),
+                "(u8 , bool ,)",
+            ),
+            (
+                ParamType::Tuple(vec![
+                    ParamType::Bool,
+                    ParamType::Tuple(vec![ParamType::Bool]),
+                ]),
+                "(bool , (bool ,) ,)",
+            ),
+            (ParamType::FixedBytes(8), "[u8 ; 8]"),
+            (ParamType::FixedBytes(256), "[u8 ; 256]"),
+            (ParamType::Bytes, "Vec < u8 >"),
+            (
+                ParamType::FixedArray(Box::new(ParamType::Uint(64)), 2),
+                "Vec < u64 >",
+            ),
+            (
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+                "Vec < String >",
+            ),
+            (
+                ParamType::Array(Box::new(ParamType::FixedArray(
+                    Box::new(ParamType::Uint(64)),
+                    2,
+                ))),
+                "Vec < Vec < u64 > >",
+            ),
+            (
+                ParamType::FixedArray(Box::new(ParamType::Array(Box::new(ParamType::Uint(64)))), 2),
+                "Vec < Vec < u64 > >",
+            ),
+            // ic_solidity_bindgen can't decode arrays of tuples
+            (
+                ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Bool]))),
+                ":: ic_solidity_bindgen :: internal :: Unimplemented",
+            ),
+        ];
+        for (kind, expected) in cases {
+            assert_eq!(to_candid_type(&kind).to_string(), expected, "{}", kind);
+        }
     }
 
     #[test]
@@ -5,7 +5,7 @@ use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 use syn::parse_macro_input;
 
-use crate::canisters::utils::generate_queries_without_timestamp;
+use crate::canisters::utils::{generate_queries_without_timestamp, snapshot_retention};
 
 pub fn def_snapshot_indexer_https(input: TokenStream) -> TokenStream {
     let input_json_string: String = parse_macro_input!(input as syn::LitStr).value();
@@ -47,6 +47,7 @@ fn custom_code(config: SnapshotIndexerHTTPSConfig) -> proc_macro2::TokenStream {
         url,
         headers,
         queries,
+        retention,
     } = config;
 
     let id = &common.canister_name;
@@ -73,6 +74,9 @@ fn custom_code(config: SnapshotIndexerHTTPSConfig) -> proc_macro2::TokenStream {
         }
     };
     let queries = generate_queries_without_timestamp(format_ident!("SnapshotValue"));
+    // NOTE: timestamps of snapshots are in milliseconds
+    let (retention_args, retainable_impl) =
+        snapshot_retention(&retention, quote! { self.timestamp / 1000 });
 
     quote! {
         did_export!(#id); // NOTE: need to be declared before query, update
@@ -85,8 +89,9 @@ fn custom_code(config: SnapshotIndexerHTTPSConfig) -> proc_macro2::TokenStream {
             pub value: SnapshotValue,
             pub timestamp: u64,
         }
-        prepare_stable_structure!();
-        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true);
+        prepare_stable_structure!(export(memories = [1]));
+        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true #retention_args);
+        #retainable_impl
         timer_task_func!("set_task", "index", 3);
 
         const URL : &str = #url;
@@ -110,6 +115,13 @@ fn custom_code(config: SnapshotIndexerHTTPSConfig) -> proc_macro2::TokenStream {
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
             let indexer = Web2HttpsSnapshotIndexer::new(
                 URL.to_string(),
             );
@@ -157,6 +169,7 @@ mod test {
                 ("ids".to_string(), "dai".to_string()),
                 ("vs_currencies".to_string(), "usd".to_string()),
             ])),
+            retention: None,
         };
         let generated = snapshot_indexer_https(config);
         let formatted = RustFmt::default()
@@ -174,6 +187,7 @@ mod test {
             url: "https://api.coingecko.com/api/v3/simple/price".to_string(),
             headers: BTreeMap::from([("content-type".to_string(), "application/json".to_string())]),
             queries: SnapshotIndexerHTTPSConfigQueries::Func("get_queries".to_string()),
+            retention: None,
         };
         let generated = snapshot_indexer_https(config);
         let formatted = RustFmt::default()
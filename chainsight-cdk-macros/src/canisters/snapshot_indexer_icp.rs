@@ -1,6 +1,7 @@
 use chainsight_cdk::{
     config::components::{
-        CommonConfig, LensParameter, SnapshotIndexerICPConfig, LENS_FUNCTION_ARGS_TYPE,
+        CommonConfig, LensParameter, RetentionConfig, SnapshotIndexerICPConfig,
+        LENS_FUNCTION_ARGS_TYPE,
     },
     convert::candid::CanisterMethodIdentifier,
 };
@@ -8,7 +9,7 @@ use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 use syn::parse_macro_input;
 
-use crate::canisters::utils::generate_queries_without_timestamp;
+use crate::canisters::utils::{generate_queries_without_timestamp, snapshot_retention};
 
 pub fn def_snapshot_indexer_icp(input: TokenStream) -> TokenStream {
     let input_json_string: String = parse_macro_input!(input as syn::LitStr).value();
@@ -18,7 +19,11 @@ pub fn def_snapshot_indexer_icp(input: TokenStream) -> TokenStream {
 }
 
 fn snapshot_indexer_icp(config: SnapshotIndexerICPConfig) -> proc_macro2::TokenStream {
-    let common = common_code(&config.common, config.lens_parameter.is_some());
+    let common = common_code(
+        &config.common,
+        config.lens_parameter.is_some(),
+        &config.retention,
+    );
     let custom = custom_code(config);
     quote! {
         #common
@@ -26,8 +31,14 @@ fn snapshot_indexer_icp(config: SnapshotIndexerICPConfig) -> proc_macro2::TokenS
     }
 }
 
-fn common_code(config: &CommonConfig, with_lens: bool) -> proc_macro2::TokenStream {
+fn common_code(
+    config: &CommonConfig,
+    with_lens: bool,
+    retention: &Option<RetentionConfig>,
+) -> proc_macro2::TokenStream {
     let id = &config.canister_name;
+    let (retention_args, retainable_impl) =
+        snapshot_retention(retention, quote! { self.timestamp });
 
     let lens_targets_quote = if with_lens {
         quote! { lens_targets: Vec<String> }
@@ -54,8 +65,9 @@ fn common_code(config: &CommonConfig, with_lens: bool) -> proc_macro2::TokenStre
             #lens_targets_quote
         }, 5);
 
-        prepare_stable_structure!();
-        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true);
+        prepare_stable_structure!(export(memories = [1]));
+        stable_memory_for_btree_map!("snapshot", Snapshot, 1, true #retention_args);
+        #retainable_impl
         timer_task_func!("set_task", "index", 6);
     }
 }
@@ -66,6 +78,7 @@ fn custom_code(config: SnapshotIndexerICPConfig) -> proc_macro2::TokenStream {
         method_identifier: method_identifier_str,
         is_target_component,
         lens_parameter,
+        ..
     } = config;
 
     let canister_name_ident = format_ident!("{}", &canister_name);
@@ -158,6 +171,13 @@ fn custom_code(config: SnapshotIndexerICPConfig) -> proc_macro2::TokenStream {
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
 
             let current_ts_sec = ic_cdk::api::time() / 1000000;
             let target_canister = candid::Principal::from_text(get_target_canister()).expect("invalid principal");
@@ -229,6 +249,7 @@ mod test {
                 "get_last_snapshot : () -> (record { value : text; timestamp : nat64 })".to_string(),
             is_target_component: true,
             lens_parameter: None,
+            retention: None,
         }
     }
 
@@ -268,6 +289,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_snapshot_with_retention() {
+        let mut config = config();
+        config.retention = Some(RetentionConfig {
+            policy: chainsight_cdk::storage::RetentionPolicy {
+                max_entries: Some(1000),
+                max_age_secs: Some(86400),
+                max_block_distance: None,
+            },
+            prune_interval_secs: None,
+            max_prune_per_call: None,
+        });
+
+        let generated = snapshot_indexer_icp(config);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        insta::assert_snapshot!("snapshot__snapshot_indexer_icp__with_retention", formatted);
+    }
+
     #[test]
     fn test_snapshot_target_is_not_component() {
         let config = SnapshotIndexerICPConfig {
@@ -277,6 +318,7 @@ mod test {
             method_identifier: "icrc1_total_supply : () -> (nat)".to_string(),
             is_target_component: false,
             lens_parameter: None,
+            retention: None,
         };
 
         let generated = snapshot_indexer_icp(config);
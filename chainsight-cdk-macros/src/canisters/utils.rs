@@ -1,3 +1,4 @@
+use chainsight_cdk::config::components::RetentionConfig;
 use quote::quote;
 use std::path::PathBuf;
 use syn::parse_quote;
@@ -17,6 +18,56 @@ pub fn extract_contract_name_from_path(s: &str) -> String {
     name.to_str().expect("to_str failed").to_string()
 }
 
+/// Trailing `retention(...)` argument of the macros defining stores, empty without retention.
+pub fn retention_args(retention: &Option<RetentionConfig>) -> proc_macro2::TokenStream {
+    let retention = match retention {
+        Some(retention) => retention,
+        None => return quote! {},
+    };
+    let keys = [
+        ("max_entries", retention.policy.max_entries),
+        ("max_age_secs", retention.policy.max_age_secs),
+        ("max_block_distance", retention.policy.max_block_distance),
+        (
+            "prune_interval_secs",
+            retention.prune_interval_secs.map(|v| v as u64),
+        ),
+        ("max_prune_per_call", retention.max_prune_per_call),
+    ];
+    let args = keys.into_iter().filter_map(|(key, value)| {
+        let key = quote::format_ident!("{}", key);
+        value.map(|v| {
+            let v = proc_macro2::Literal::u64_unsuffixed(v);
+            quote! { #key = #v }
+        })
+    });
+    quote! { , retention(#(#args),*) }
+}
+
+/// `retention(...)` argument of `stable_memory_for_btree_map!` for snapshots,
+/// and the `Retainable` of `Snapshot` by `timestamp_secs` of `self`, both empty without retention.
+pub fn snapshot_retention(
+    retention: &Option<RetentionConfig>,
+    timestamp_secs: proc_macro2::TokenStream,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    if retention.is_none() {
+        return (quote! {}, quote! {});
+    }
+    (
+        retention_args(retention),
+        quote! {
+            impl chainsight_cdk::storage::Retainable for Snapshot {
+                fn stamp(&self) -> chainsight_cdk::storage::EntryStamp {
+                    chainsight_cdk::storage::EntryStamp {
+                        timestamp_secs: Some(#timestamp_secs),
+                        block_number: None,
+                    }
+                }
+            }
+        },
+    )
+}
+
 pub fn generate_queries_without_timestamp(
     return_type: proc_macro2::Ident,
 ) -> proc_macro2::TokenStream {
@@ -5,27 +5,86 @@ use syn::{
     parse_macro_input, parse_quote, LitInt, Type,
 };
 
-use crate::internal::{attrs_query_func, attrs_update_func, gen_func_quote_to_call_proxy};
+use crate::internal::{
+    attrs_query_func, attrs_update_func, gen_func_quote_to_call_proxy, gen_retention_quote,
+    RetentionArgs,
+};
 
 pub mod sources;
 
+/// `web3_event_indexer!(Transfer, 16, index(to: String = 20), retention(...))`:
+/// the event, the memory id of the config, the indexed fields with the memory ids of their indexes,
+/// and the retention; all but the event are optional.
 pub struct Web3EventIndexerInput {
     out_type: syn::Type,
     stable_memory_id: Option<LitInt>,
+    indexes: Vec<EventIndexArg>,
+    retention: Option<RetentionArgs>,
+}
+/// Indexed field of the event, `to: String = 20`.
+struct EventIndexArg {
+    field: syn::Ident,
+    ty: Type,
+    memory_id: LitInt,
+}
+impl Parse for EventIndexArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let field = input.parse()?;
+        input.parse::<syn::Token![:]>()?;
+        let ty = input.parse()?;
+        input.parse::<syn::Token![=]>()?;
+        let memory_id = input.parse()?;
+        Ok(EventIndexArg {
+            field,
+            ty,
+            memory_id,
+        })
+    }
 }
 impl Parse for Web3EventIndexerInput {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         let out_type: Type = input.parse()?;
-        let stable_memory_id = if input.peek(syn::Token![,]) {
+        let stable_memory_id = if input.peek(syn::Token![,]) && input.peek2(LitInt) {
             input.parse::<syn::Token![,]>()?;
             let parsed: LitInt = input.parse()?;
             Some(parsed)
         } else {
             None
         };
+        let mut indexes = vec![];
+        if input.peek(syn::Token![,]) && input.peek2(syn::Ident) {
+            let fork = input.fork();
+            fork.parse::<syn::Token![,]>()?;
+            if fork.parse::<syn::Ident>()? == "index" {
+                input.parse::<syn::Token![,]>()?;
+                input.parse::<syn::Ident>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                indexes = content
+                    .parse_terminated(EventIndexArg::parse, syn::Token![,])?
+                    .into_iter()
+                    .collect();
+            }
+        }
+        let retention = if input.peek(syn::Token![,]) {
+            input.parse::<syn::Token![,]>()?;
+            let span = input.span();
+            let retention: RetentionArgs = input.parse()?;
+            if retention.max_age_secs.is_some() {
+                return Err(syn::Error::new(
+                    span,
+                    "max_age_secs is not supported for events, which have no timestamp",
+                ));
+            }
+            Some(retention)
+        } else {
+            None
+        };
         Ok(Web3EventIndexerInput {
             out_type,
             stable_memory_id,
+            indexes,
+            retention,
         })
     }
 }
@@ -37,13 +96,28 @@ fn web3_event_indexer_internal(args: Web3EventIndexerInput) -> proc_macro2::Toke
     let Web3EventIndexerInput {
         out_type,
         stable_memory_id,
+        indexes,
+        retention,
     } = args;
     let common = event_indexer_common(out_type.clone(), stable_memory_id);
+    let (with_indexes, index_queries) = event_index_quotes(&out_type, &indexes);
+    let (retention_quote, on_insert_quote) = match retention {
+        Some(retention) => gen_retention_quote(
+            "event",
+            &retention,
+            quote! { indexer().prune(&policy, limit) },
+        ),
+        None => (quote! {}, quote! {}),
+    };
 
     quote! {
         #common
         fn indexer() -> chainsight_cdk::web3::Web3Indexer<#out_type> {
             chainsight_cdk::web3::Web3Indexer::new(get_logs, None)
+                .with_block_finder(get_block_header, None)
+                .with_head_finder(get_chain_head, None)
+                .with_contract(get_target_addr())
+                #with_indexes
         }
         #[ic_cdk::update]
         #[candid::candid_method(update)]
@@ -51,11 +125,69 @@ fn web3_event_indexer_internal(args: Web3EventIndexerInput) -> proc_macro2::Toke
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
-            indexer().index(get_config()).await.unwrap();
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
+            // returning keeps what was indexed so far, e.g. the learned chunk size, which a trap would roll back
+            if let Err(e) = indexer().index(get_config()).await {
+                ic_cdk::println!("Failed to index: {}", e);
+                return;
+            }
+            #on_insert_quote
         }
-
+        #retention_quote
+        #index_queries
     }
 }
+/// Indexes given to the indexer, and query and proxy endpoints to look up events by the indexed fields.
+fn event_index_quotes(
+    out_type: &Type,
+    indexes: &[EventIndexArg],
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    if indexes.is_empty() {
+        return (quote! {}, quote! {});
+    }
+    let entries = indexes.iter().map(|index| {
+        let field = index.field.to_string();
+        let memory_id = &index.memory_id;
+        quote! { (#field, chainsight_cdk::storage::StoreIndex::new(#memory_id)) }
+    });
+    let with_indexes = quote! { .with_indexes(vec![#(#entries),*]) };
+
+    let attrs_query = attrs_query_func();
+    let attrs_update = attrs_update_func();
+    let queries = indexes.iter().map(|index| {
+        let EventIndexArg { field, ty, .. } = index;
+        let field_str = field.to_string();
+        let getter = format_ident!("events_by_{}", field);
+        let _getter_str = format!("_events_by_{}", field);
+        let _getter = format_ident!("{}", _getter_str);
+        let proxy_getter_quote = gen_func_quote_to_call_proxy(
+            &format!("proxy_events_by_{}", field),
+            parse_quote! { HashMap<u64, Vec<#out_type>> },
+            Some(ty.clone()),
+            &_getter_str,
+        );
+        quote! {
+            #attrs_query
+            pub fn #getter(value: #ty) -> HashMap<u64, Vec<#out_type>> {
+                #_getter(value)
+            }
+            fn #_getter(value: #ty) -> HashMap<u64, Vec<#out_type>> {
+                indexer()
+                    .find(#field_str, &chainsight_cdk::storage::Tokenizable::to_token(&value))
+                    .unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
+            }
+            #attrs_update
+            pub #proxy_getter_quote
+        }
+    });
+    (with_indexes, quote! { #(#queries)* })
+}
 fn event_indexer_common(
     out_type: syn::Type,
     stable_memory_id: Option<LitInt>,
@@ -91,6 +223,12 @@ fn event_indexer_common(
         None,
         "_get_last_indexed",
     );
+    let proxy_get_lag_quote = gen_func_quote_to_call_proxy(
+        "proxy_get_lag",
+        parse_quote! { Result<u64, String> },
+        None,
+        "_get_lag",
+    );
 
     let output = quote! {
         #storage_quote
@@ -136,6 +274,18 @@ fn event_indexer_common(
             indexer().get_last_indexed().unwrap()
         }
 
+        #attrs_query
+        pub fn get_lag() -> Result<u64, String> {
+            _get_lag()
+        }
+
+        #attrs_update
+        pub #proxy_get_lag_quote
+
+        fn _get_lag() -> Result<u64, String> {
+            indexer().get_lag().map_err(|e| e.to_string())
+        }
+
         #attrs_update
         async fn proxy_call(input: std::vec::Vec<u8>) -> std::vec::Vec<u8> {
             _proxy_events_from_to(input).await
@@ -203,6 +353,13 @@ fn algorithm_indexer_internal(args: AlgorithmIndexerInput) -> proc_macro2::Token
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
             let mut config = get_config();
             let stored = chainsight_cdk::storage::get_last_key();
             ic_cdk::println!("stored: {:?}", stored);
@@ -222,6 +379,27 @@ fn algorithm_indexer_internal(args: AlgorithmIndexerInput) -> proc_macro2::Token
         fn event_source() -> candid::Principal {
             get_target()
         }
+
+        #[ic_cdk::query]
+        #[candid::candid_method(query)]
+        #[chainsight_cdk_macros::only_controller]
+        fn get_checkpoints() -> Vec<(String, chainsight_cdk::storage::Checkpoint)> {
+            chainsight_cdk::storage::checkpoints()
+        }
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        #[chainsight_cdk_macros::only_controller]
+        fn rewind_checkpoint(name: String, key: String) -> Result<chainsight_cdk::storage::Checkpoint, String> {
+            chainsight_cdk::storage::rewind_checkpoint(&name, key).map_err(|e| e.to_string())
+        }
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        #[chainsight_cdk_macros::only_controller]
+        fn fast_forward_checkpoint(name: String, key: String) -> Result<chainsight_cdk::storage::Checkpoint, String> {
+            chainsight_cdk::storage::fast_forward_checkpoint(&name, key).map_err(|e| e.to_string())
+        }
     }
 }
 
@@ -286,6 +464,13 @@ fn algorithm_indexer_with_args_internal(
             if ic_cdk::caller() != proxy() {
                 panic!("Not permitted")
             }
+            let _task = match chainsight_cdk::storage::begin_task() {
+                Some(task) => task,
+                None => {
+                    ic_cdk::println!("Skipped indexing in maintenance");
+                    return;
+                }
+            };
             indexer().await.index(chainsight_cdk::indexer::IndexingConfig::default()).await.unwrap()
         }
         fn get_target() -> candid::Principal {
@@ -414,6 +599,36 @@ mod test {
         assert_snapshot!("snapshot__web3_event_indexer", formatted);
     }
 
+    #[test]
+    fn test_snapshot_web3_event_indexer_with_retention() {
+        let input =
+            quote! {Transfer, 16, retention(max_entries = 10000, max_block_distance = 7200)};
+        let args: syn::Result<Web3EventIndexerInput> = syn::parse2(input);
+        let generated = web3_event_indexer_internal(args.unwrap());
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__web3_event_indexer_with_retention", formatted);
+    }
+
+    #[test]
+    fn test_snapshot_web3_event_indexer_with_indexes() {
+        let input = quote! {Transfer, 16, index(to: String = 20, value: U256 = 21), retention(max_entries = 10000)};
+        let args: syn::Result<Web3EventIndexerInput> = syn::parse2(input);
+        let generated = web3_event_indexer_internal(args.unwrap());
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__web3_event_indexer_with_indexes", formatted);
+    }
+
+    #[test]
+    fn test_web3_event_indexer_with_max_age() {
+        let input = quote! {Transfer, 16, retention(max_entries = 10000, max_age_secs = 86400)};
+        let args: syn::Result<Web3EventIndexerInput> = syn::parse2(input);
+        assert!(args.is_err());
+    }
+
     #[test]
     fn test_snapshot_algorithm_indexer() {
         let input = quote! {HashMap<u64, Vec<String>>, "get_list"};
@@ -47,22 +47,71 @@ fn algorithm_indexer_source_internal() -> proc_macro2::TokenStream {
     }
 }
 
+/// A function called by a web3 snapshot indexer, either `"func_name"` on the target address
+/// or `("address", "func_name")` on another contract.
+pub struct Web3SourceCall {
+    address: Option<syn::LitStr>,
+    func_name: syn::LitStr,
+}
+impl Parse for Web3SourceCall {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(syn::token::Paren) {
+            let content;
+            syn::parenthesized!(content in input);
+            let address: syn::LitStr = content.parse()?;
+            content.parse::<syn::Token![,]>()?;
+            let func_name: syn::LitStr = content.parse()?;
+            return Ok(Web3SourceCall {
+                address: Some(address),
+                func_name,
+            });
+        }
+        Ok(Web3SourceCall {
+            address: None,
+            func_name: input.parse()?,
+        })
+    }
+}
+pub struct SnapshotIndexerWeb3SourceInput {
+    calls: Vec<Web3SourceCall>,
+}
+impl Parse for SnapshotIndexerWeb3SourceInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let calls =
+            syn::punctuated::Punctuated::<Web3SourceCall, syn::Token![,]>::parse_terminated(input)?;
+        Ok(SnapshotIndexerWeb3SourceInput {
+            calls: calls.into_iter().collect(),
+        })
+    }
+}
 pub fn snapshot_indexer_web3_source(input: TokenStream) -> TokenStream {
-    let func_name: syn::LitStr = parse_macro_input!(input as syn::LitStr);
-    snapshot_indexer_web3_source_internal(func_name).into()
+    let args = parse_macro_input!(input as SnapshotIndexerWeb3SourceInput);
+    snapshot_indexer_web3_source_internal(args).into()
 }
-fn snapshot_indexer_web3_source_internal(func_name: syn::LitStr) -> proc_macro2::TokenStream {
+fn snapshot_indexer_web3_source_internal(
+    args: SnapshotIndexerWeb3SourceInput,
+) -> proc_macro2::TokenStream {
+    let sources = args.calls.iter().map(|call| {
+        let address = match &call.address {
+            Some(address) => quote! { #address.to_string() },
+            None => quote! { get_target_addr() },
+        };
+        let func_name = &call.func_name;
+        quote! {
+            chainsight_cdk::core::Sources::<chainsight_cdk::core::Web3SnapshotIndexerSourceAttrs>::new_web3_snapshot_indexer(
+                #address,
+                get_indexing_interval(),
+                get_web3_ctx_param().chain_id,
+                #func_name.to_string(),
+            )
+        }
+    });
     quote! {
         #[ic_cdk::query]
         #[candid::candid_method(query)]
         fn get_sources() -> Vec<chainsight_cdk::core::Sources<chainsight_cdk::core::Web3SnapshotIndexerSourceAttrs>> {
             vec![
-                chainsight_cdk::core::Sources::<chainsight_cdk::core::Web3SnapshotIndexerSourceAttrs>::new_web3_snapshot_indexer(
-                    get_target_addr(),
-                    get_indexing_interval(),
-                    get_web3_ctx_param().chain_id,
-                    #func_name.to_string(),
-                )
+                #(#sources),*
             ]
         }
     }
@@ -245,7 +294,7 @@ mod test {
     #[test]
     fn test_snapshot_snapshot_indexer_web3_source() {
         let input = quote! {"total_supply"};
-        let args: syn::Result<syn::LitStr> = syn::parse2(input);
+        let args: syn::Result<SnapshotIndexerWeb3SourceInput> = syn::parse2(input);
         let generated = snapshot_indexer_web3_source_internal(args.unwrap());
         let formatted = RustFmt::default()
             .format_str(generated.to_string())
@@ -253,6 +302,21 @@ mod test {
         assert_snapshot!("snapshot__snapshot_indexer_web3_source", formatted);
     }
 
+    #[test]
+    fn test_snapshot_snapshot_indexer_web3_source_with_calls() {
+        let input =
+            quote! {"total_supply", ("0x6B175474E89094C44Da98b954EedeAC495271d0F", "allowance")};
+        let args: syn::Result<SnapshotIndexerWeb3SourceInput> = syn::parse2(input);
+        let generated = snapshot_indexer_web3_source_internal(args.unwrap());
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!(
+            "snapshot__snapshot_indexer_web3_source_with_calls",
+            formatted
+        );
+    }
+
     #[test]
     fn test_snapshot_snapshot_indexer_https_source() {
         let generated = snapshot_indexer_https_source_internal();
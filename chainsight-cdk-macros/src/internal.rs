@@ -1,6 +1,11 @@
 use proc_macro2::Span;
-use quote::quote;
-use syn::{Ident, Type};
+use quote::{format_ident, quote};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Ident, LitInt, Type,
+};
 
 pub fn attrs_query_func() -> proc_macro2::TokenStream {
     quote! {
@@ -44,6 +49,131 @@ pub fn gen_func_quote_to_call_proxy(
     }
 }
 
+/// `retention(max_entries = 100, max_age_secs = 86400, max_block_distance = 7200, prune_interval_secs = 3600, max_prune_per_call = 1000)`
+/// given to macros defining stores; every key is optional.
+#[derive(Default)]
+pub struct RetentionArgs {
+    pub max_entries: Option<u64>,
+    pub max_age_secs: Option<u64>,
+    pub max_block_distance: Option<u64>,
+    pub prune_interval_secs: Option<u32>,
+    pub max_prune_per_call: Option<u64>,
+}
+impl Parse for RetentionArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ident: Ident = input.parse()?;
+        if ident != "retention" {
+            return Err(syn::Error::new(ident.span(), "expected `retention(...)`"));
+        }
+        let content;
+        parenthesized!(content in input);
+        let mut args = RetentionArgs::default();
+        let pairs = Punctuated::<RetentionArg, syn::Token![,]>::parse_terminated(&content)?;
+        for RetentionArg { key, value } in pairs {
+            match key.to_string().as_str() {
+                "max_entries" => args.max_entries = Some(value.base10_parse()?),
+                "max_age_secs" => args.max_age_secs = Some(value.base10_parse()?),
+                "max_block_distance" => args.max_block_distance = Some(value.base10_parse()?),
+                "prune_interval_secs" => args.prune_interval_secs = Some(value.base10_parse()?),
+                "max_prune_per_call" => args.max_prune_per_call = Some(value.base10_parse()?),
+                _ => return Err(syn::Error::new(key.span(), "unknown retention key")),
+            }
+        }
+        Ok(args)
+    }
+}
+struct RetentionArg {
+    key: Ident,
+    value: LitInt,
+}
+impl Parse for RetentionArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<syn::Token![=]>()?;
+        let value = input.parse()?;
+        Ok(RetentionArg { key, value })
+    }
+}
+
+fn quote_opt<T: quote::ToTokens>(value: Option<T>) -> proc_macro2::TokenStream {
+    match value {
+        Some(v) => quote! { Some(#v) },
+        None => quote! { None },
+    }
+}
+
+/// Functions to prune `{name}s` by the retention, and the statement to run on every insert.
+/// `prune` is an expression of the number of pruned entries, given `policy` and at most `limit` entries to remove.
+/// With `prune_interval_secs`, the insert schedules the timer instead, as timers do not survive upgrades.
+pub fn gen_retention_quote(
+    name: &str,
+    args: &RetentionArgs,
+    prune: proc_macro2::TokenStream,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let prune_func = format_ident!("prune_{}s", name);
+    let _prune_func = format_ident!("_prune_{}s", name);
+    let max_entries = quote_opt(args.max_entries);
+    let max_age_secs = quote_opt(args.max_age_secs);
+    let max_block_distance = quote_opt(args.max_block_distance);
+    let limit = match args.max_prune_per_call {
+        Some(limit) => quote! { #limit },
+        None => quote! { chainsight_cdk::storage::DEFAULT_MAX_PRUNE_PER_CALL },
+    };
+    let label = format!("{}s", name);
+
+    let funcs = quote! {
+        pub fn #_prune_func() -> u64 {
+            let policy = chainsight_cdk::storage::RetentionPolicy {
+                max_entries: #max_entries,
+                max_age_secs: #max_age_secs,
+                max_block_distance: #max_block_distance,
+            };
+            let limit: u64 = #limit;
+            let pruned: u64 = #prune;
+            if pruned > 0 {
+                ic_cdk::println!("pruned {} {}", pruned, #label);
+            }
+            pruned
+        }
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        #[chainsight_cdk_macros::only_controller]
+        fn #prune_func() -> u64 {
+            #_prune_func()
+        }
+    };
+    match args.prune_interval_secs {
+        Some(interval) => {
+            let interval = interval as u64;
+            let scheduled = format_ident!("PRUNE_{}S_SCHEDULED", name.to_uppercase());
+            let schedule_func = format_ident!("_schedule_prune_{}s", name);
+            (
+                quote! {
+                    #funcs
+
+                    thread_local! {
+                        static #scheduled: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+                    }
+
+                    fn #schedule_func() {
+                        if #scheduled.with(|s| s.replace(true)) {
+                            return;
+                        }
+                        ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(#interval), || {
+                            if !chainsight_cdk::storage::is_in_maintenance() {
+                                #_prune_func();
+                            }
+                        });
+                    }
+                },
+                quote! { #schedule_func(); },
+            )
+        }
+        None => (funcs, quote! { #_prune_func(); }),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
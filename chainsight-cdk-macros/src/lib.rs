@@ -147,6 +147,16 @@ pub fn define_get_ethereum_address(input: TokenStream) -> TokenStream {
     web3::define_get_ethereum_address(input)
 }
 
+#[proc_macro]
+pub fn define_nonce_manager(input: TokenStream) -> TokenStream {
+    web3::define_nonce_manager(input)
+}
+
+#[proc_macro]
+pub fn define_relay_tracker(input: TokenStream) -> TokenStream {
+    web3::define_relay_tracker(input)
+}
+
 #[proc_macro]
 pub fn define_withdraw_balance(input: TokenStream) -> TokenStream {
     web3::define_withdraw_balance(input)
@@ -162,12 +172,12 @@ pub fn contract_event_derive(input: TokenStream) -> TokenStream {
     web3::contract_event_derive(input)
 }
 
-#[proc_macro_derive(Persist)]
+#[proc_macro_derive(Persist, attributes(persist))]
 pub fn persist_derive(input: TokenStream) -> TokenStream {
     states::persist_derive(input)
 }
 
-#[proc_macro_derive(KeyValueStore, attributes(memory_id))]
+#[proc_macro_derive(KeyValueStore, attributes(memory_id, key_type, index))]
 pub fn key_value_store_derive(input: TokenStream) -> TokenStream {
     storages::key_value_store_derive(input)
 }
@@ -176,7 +186,7 @@ pub fn generate_queries_for_key_value_store_struct(input: TokenStream) -> TokenS
     storages::generate_queries_for_key_value_store_struct(input)
 }
 
-#[proc_macro_derive(KeyValuesStore, attributes(memory_id))]
+#[proc_macro_derive(KeyValuesStore, attributes(memory_id, key_type, index))]
 pub fn key_values_store_derive(input: TokenStream) -> TokenStream {
     storages::key_values_store_derive(input)
 }
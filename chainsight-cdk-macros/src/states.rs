@@ -1,6 +1,6 @@
 use chainsight_cdk::storage::Data;
 use proc_macro::TokenStream;
-use quote::{quote, ToTokens};
+use quote::quote;
 use syn::{
     parse::{Parse, ParseStream},
     parse_macro_input, parse_quote, Expr, LitBool, LitStr, Type,
@@ -15,102 +15,135 @@ pub trait Persist {
 
 pub fn persist_derive(input: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    persist_derive_internal(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+pub(crate) struct PersistFieldOpts {
+    pub(crate) rename: Option<String>,
+    pub(crate) skip: bool,
+}
+pub(crate) fn persist_field_opts(field: &syn::Field) -> syn::Result<PersistFieldOpts> {
+    let mut opts = PersistFieldOpts::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("persist")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                opts.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                let name: LitStr = meta.value()?.parse()?;
+                opts.rename = Some(name.value());
+                Ok(())
+            } else {
+                Err(meta
+                    .error("unsupported persist attribute, expected `rename = \"...\"` or `skip`"))
+            }
+        })?;
+    }
+    Ok(opts)
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p
+            .path
+            .segments
+            .last()
+            .map(|s| s.ident == "Option")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn persist_derive_internal(input: syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
     let name = input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
     let fields = match input.data {
         syn::Data::Struct(syn::DataStruct {
             fields: syn::Fields::Named(syn::FieldsNamed { ref named, .. }),
             ..
         }) => named,
-        _ => panic!("Only support struct"),
+        _ => {
+            return Err(syn::Error::new(
+                name.span(),
+                "Persist can only be derived for structs with named fields",
+            ))
+        }
     };
-    // get field name and type
-    let mut field_names = Vec::new();
-    let mut field_types = Vec::new();
+    let mut tokenize_functions = Vec::new();
     let mut untokenize_functions = Vec::new();
-    // let mut tokenize_functions = Vec::new();
     for field in fields {
         let field_name = field.ident.as_ref().unwrap();
         let field_type = &field.ty;
-        field_names.push(field_name);
-        field_types.push(field_type);
-        let untokenize_function = match field_type.to_token_stream().to_string().as_str() {
-            "String" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_string(),
-            },
-            "u128" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_u128().unwrap(),
-            },
-            "u64" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_u64().unwrap(),
-            },
-            "u32" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_u32().unwrap(),
-            },
-            "u16" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_u16().unwrap(),
-            },
-            "u8" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_u8().unwrap(),
-            },
-            "i128" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_i128().unwrap(),
-            },
-            "i64" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_i64().unwrap(),
-            },
-            "i32" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_i32().unwrap(),
-            },
-            "i16" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_i16().unwrap(),
-            },
-            "i8" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_i8().unwrap(),
-            },
-            "bool" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_bool().unwrap(),
-            },
-            "Vec<u8>" => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_vec().unwrap(),
-            },
-            "chainsight_cdk::core::U256" => quote! {
-               #field_name: data.get(stringify!(#field_name)).unwrap().to_u256().unwrap(),
-            },
-            "U256" => quote! {
-               #field_name: data.get(stringify!(#field_name)).unwrap().to_u256().unwrap(),
-            },
-            _ => quote! {
-                #field_name: data.get(stringify!(#field_name)).unwrap().to_string(),
-            },
+        let opts = persist_field_opts(field)?;
+        if opts.skip {
+            untokenize_functions.push(quote! { #field_name: Default::default() });
+            continue;
+        }
+        let key = opts.rename.unwrap_or_else(|| field_name.to_string());
+        tokenize_functions.push(quote! {
+            data.insert(#key.to_string(), chainsight_cdk::storage::Tokenizable::to_token(&self.#field_name));
+        });
+        // Option fields missing from data, e.g. added after the data was stored, are None
+        let untokenize_function = if is_option(field_type) {
+            quote! {
+                #field_name: match data.get(#key) {
+                    Some(token) => <#field_type as chainsight_cdk::storage::Tokenizable>::from_token(token)
+                        .map_err(|e| e.in_field(#key))?,
+                    None => None,
+                }
+            }
+        } else {
+            quote! {
+                #field_name: match data.get(#key) {
+                    Some(token) => <#field_type as chainsight_cdk::storage::Tokenizable>::from_token(token)
+                        .map_err(|e| e.in_field(#key))?,
+                    None => return Err(chainsight_cdk::storage::StorageError::MissingField(#key.to_string())),
+                }
+            }
         };
         untokenize_functions.push(untokenize_function);
     }
-    let expanded = quote! {
-        impl #name {
+    let untokenize_err = format!("Failed to untokenize {}: ", name);
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
             pub fn _tokenize(&self) -> chainsight_cdk::storage::Data {
-                let mut data: HashMap<std::string::String, chainsight_cdk::storage::Token> = HashMap::new();
-                #(
-                    data.insert(stringify!(#field_names).to_string(), chainsight_cdk::storage::Token::from(self.#field_names.clone()));
-                )*
-                Data::new(data)
+                let mut data: std::collections::HashMap<std::string::String, chainsight_cdk::storage::Token> = std::collections::HashMap::new();
+                #(#tokenize_functions)*
+                chainsight_cdk::storage::Data::new(data)
             }
-            pub fn _untokenize(data: Data) -> Self {
-                Self {
-                    #(#untokenize_functions)*
-                }
+            pub fn _untokenize(data: chainsight_cdk::storage::Data) -> Self {
+                Self::_try_untokenize(&data).unwrap_or_else(|e| panic!("{}{}", #untokenize_err, e))
+            }
+            pub fn _try_untokenize(data: &chainsight_cdk::storage::Data) -> Result<Self, chainsight_cdk::storage::StorageError> {
+                Ok(Self {
+                    #(#untokenize_functions),*
+                })
             }
         }
-        impl chainsight_cdk::storage::Persist for #name {
-            fn untokenize(data: Data) -> Self {
+        impl #impl_generics chainsight_cdk::storage::Persist for #name #ty_generics #where_clause {
+            fn untokenize(data: chainsight_cdk::storage::Data) -> Self {
                 Self::_untokenize(data)
             }
-            fn tokenize(&self) -> Data {
+            fn tokenize(&self) -> chainsight_cdk::storage::Data {
                 self._tokenize()
             }
+            fn try_untokenize(data: chainsight_cdk::storage::Data) -> Result<Self, chainsight_cdk::storage::StorageError> {
+                Self::_try_untokenize(&data)
+            }
         }
-    };
-
-    expanded.into()
+        impl #impl_generics chainsight_cdk::storage::Tokenizable for #name #ty_generics #where_clause {
+            fn to_token(&self) -> chainsight_cdk::storage::Token {
+                chainsight_cdk::storage::Tokenizable::to_token(&self._tokenize())
+            }
+            fn from_token(token: &chainsight_cdk::storage::Token) -> Result<Self, chainsight_cdk::storage::StorageError> {
+                let data = <chainsight_cdk::storage::Data as chainsight_cdk::storage::Tokenizable>::from_token(token)?;
+                Self::_try_untokenize(&data)
+            }
+        }
+    })
 }
 
 struct SingleStateInput {
@@ -416,6 +449,38 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_snapshot_persist_derive() {
+        let input: syn::DeriveInput = parse_quote! {
+            pub struct Account {
+                pub owner: String,
+                #[persist(rename = "bal")]
+                pub balance: U256,
+                pub history: Vec<Transfer>,
+                pub memo: Option<String>,
+                #[persist(skip)]
+                pub cache: u64,
+            }
+        };
+        let generated = persist_derive_internal(input).unwrap();
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__persist_derive", formatted);
+    }
+
+    #[test]
+    fn test_persist_derive_unsupported_attribute() {
+        let input: syn::DeriveInput = parse_quote! {
+            pub struct Account {
+                #[persist(flatten)]
+                pub owner: String,
+            }
+        };
+        let err = persist_derive_internal(input).unwrap_err();
+        assert!(err.to_string().starts_with("unsupported persist attribute"));
+    }
+
     #[test]
     fn test_snapshot_manage_single_state() {
         let input = quote! {"timer_id", TimerId, true};
@@ -1,20 +1,131 @@
 use darling::FromDeriveInput;
 use proc_macro::TokenStream;
 use proc_macro2::Span;
-use quote::quote;
+use quote::{format_ident, quote};
 use syn::{
-    parse::Parse, parse_macro_input, parse_quote, DeriveInput, Expr, LitBool, LitInt, LitStr, Type,
+    parse::{Parse, ParseStream},
+    parse_macro_input, parse_quote, DeriveInput, Expr, LitBool, LitInt, LitStr, Type,
 };
 
 use crate::{
     canisters::utils::camel_to_snake,
-    internal::{attrs_query_func, attrs_update_func, gen_func_quote_to_call_proxy},
+    internal::{
+        attrs_query_func, attrs_update_func, gen_func_quote_to_call_proxy, gen_retention_quote,
+        RetentionArgs,
+    },
+    states::persist_field_opts,
 };
 
-pub fn prepare_stable_structure(_input: TokenStream) -> TokenStream {
-    prepare_stable_structure_internal().into()
+/// Memories exported and imported by the endpoints of `prepare_stable_structure`:
+/// `export(memories = [1], stores, indexes = [20])`.
+/// `memories` are of `MEMORY_MANAGER`, `stores` adds the stores and checkpoints of chainsight-cdk,
+/// and `indexes` the memories of its store indexes.
+#[derive(Default)]
+struct ExportArgs {
+    memories: Vec<LitInt>,
+    stores: bool,
+    indexes: Vec<LitInt>,
 }
-fn prepare_stable_structure_internal() -> proc_macro2::TokenStream {
+impl Parse for ExportArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ident: syn::Ident = input.parse()?;
+        if ident != "export" {
+            return Err(syn::Error::new(ident.span(), "expected `export(...)`"));
+        }
+        let content;
+        syn::parenthesized!(content in input);
+        let mut args = ExportArgs::default();
+        while !content.is_empty() {
+            let key: syn::Ident = content.parse()?;
+            match key.to_string().as_str() {
+                "stores" => args.stores = true,
+                "memories" | "indexes" => {
+                    content.parse::<syn::Token![=]>()?;
+                    let ids;
+                    syn::bracketed!(ids in content);
+                    let ids =
+                        syn::punctuated::Punctuated::<LitInt, syn::Token![,]>::parse_terminated(
+                            &ids,
+                        )?
+                        .into_iter()
+                        .collect();
+                    if key == "memories" {
+                        args.memories = ids;
+                    } else {
+                        args.indexes = ids;
+                    }
+                }
+                _ => return Err(syn::Error::new(key.span(), "unknown export key")),
+            }
+            if !content.is_empty() {
+                content.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+pub fn prepare_stable_structure(input: TokenStream) -> TokenStream {
+    let export = if input.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(input as ExportArgs))
+    };
+    prepare_stable_structure_internal(export).into()
+}
+fn prepare_stable_structure_internal(export: Option<ExportArgs>) -> proc_macro2::TokenStream {
+    let export_quote = match export {
+        Some(ExportArgs {
+            memories,
+            stores,
+            indexes,
+        }) => {
+            let store_memory = if stores {
+                quote! { chainsight_cdk::storage::store_memory(memory_id, &[#(#indexes),*]) }
+            } else {
+                quote! { None }
+            };
+            quote! {
+                /// Memory of `memory_id` to export or import, from the memory manager holding it.
+                fn exported_memory(memory_id: u8) -> Option<MemoryType> {
+                    if [#(#memories),*].contains(&memory_id) {
+                        return Some(MEMORY_MANAGER.with(|m| m.borrow().get(
+                            ic_stable_structures::memory_manager::MemoryId::new(memory_id)
+                        )));
+                    }
+                    #store_memory
+                }
+
+                #[ic_cdk::query]
+                #[candid::candid_method(query)]
+                #[chainsight_cdk_macros::only_controller]
+                fn export_chunk(cursor: Option<chainsight_cdk::storage::ExportCursor>) -> Result<Vec<u8>, String> {
+                    chainsight_cdk::storage::export_chunk(exported_memory, cursor).map_err(|e| e.to_string())
+                }
+
+                #[ic_cdk::update]
+                #[candid::candid_method(update)]
+                #[chainsight_cdk_macros::only_controller]
+                fn import_chunk(bytes: Vec<u8>) -> Result<Option<chainsight_cdk::storage::ExportCursor>, String> {
+                    chainsight_cdk::storage::import_chunk(exported_memory, &bytes).map_err(|e| e.to_string())
+                }
+
+                #[ic_cdk::update]
+                #[candid::candid_method(update)]
+                #[chainsight_cdk_macros::only_controller]
+                fn set_maintenance(on: bool) {
+                    chainsight_cdk::storage::set_maintenance(on)
+                }
+
+                #[ic_cdk::query]
+                #[candid::candid_method(query)]
+                fn is_in_maintenance() -> bool {
+                    chainsight_cdk::storage::is_in_maintenance()
+                }
+            }
+        }
+        None => quote! {},
+    };
     quote! {
         use ic_stable_structures::Memory;
 
@@ -33,6 +144,8 @@ fn prepare_stable_structure_internal() -> proc_macro2::TokenStream {
         fn get_upgrades_memory() -> MemoryType {
             MEMORY_MANAGER.with(|m| m.borrow().get(MEMORY_ID_FOR_UPGRADE))
         }
+
+        #export_quote
     }
 }
 
@@ -45,6 +158,7 @@ fn prepare_stable_structure_internal() -> proc_macro2::TokenStream {
 struct StableMemoryStorableOpts {
     max_size: Option<u32>,
     is_fixed_size: Option<bool>,
+    version: Option<u32>,
 }
 pub fn derive_storable_in_stable_memory(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -54,6 +168,10 @@ pub fn derive_storable_in_stable_memory(input: TokenStream) -> TokenStream {
         let is_fixed_size = opts
             .is_fixed_size
             .expect("is_fixed_size is required if max_size is set (and Bound is Bounded)");
+        let max_size = match opts.version {
+            Some(_) => quote! { #max_size + chainsight_cdk::storage::VERSION_HEADER_LEN },
+            None => quote! { #max_size },
+        };
         quote! {
             ic_stable_structures::storable::Bound::Bounded {
                 max_size: #max_size,
@@ -70,14 +188,39 @@ pub fn derive_storable_in_stable_memory(input: TokenStream) -> TokenStream {
     };
 
     let struct_name = &input.ident;
+    let (to_bytes, from_bytes) = match opts.version {
+        // NOTE: older versions are converted by StableMemoryMigration, implemented by hand
+        Some(version) => (
+            quote! {
+                std::borrow::Cow::Owned(chainsight_cdk::storage::encode_versioned(#version, &Encode!(self).unwrap()))
+            },
+            quote! {
+                let (version, payload) = chainsight_cdk::storage::decode_versioned(bytes.as_ref());
+                if version == #version {
+                    return Decode!(payload, Self).unwrap();
+                }
+                assert!(version < #version, "Encoded by a newer version {} of {}", version, stringify!(#struct_name));
+                chainsight_cdk::storage::note_migration();
+                <Self as chainsight_cdk::storage::StableMemoryMigration>::migrate(version, payload)
+            },
+        ),
+        None => (
+            quote! {
+                std::borrow::Cow::Owned(Encode!(self).unwrap())
+            },
+            quote! {
+                Decode!(bytes.as_ref(), Self).unwrap()
+            },
+        ),
+    };
     let storable_impl = quote! {
         impl ic_stable_structures::Storable for #struct_name {
             fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-                std::borrow::Cow::Owned(Encode!(self).unwrap())
+                #to_bytes
             }
 
             fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-                Decode!(bytes.as_ref(), Self).unwrap()
+                #from_bytes
             }
 
             const BOUND: ic_stable_structures::storable::Bound = #bounded;
@@ -194,47 +337,251 @@ fn mem_id(input: DeriveInput) -> u8 {
         })
         .map(|lit| lit.base10_parse::<u8>().unwrap())
         .expect("memory_id is required. e.g. #[memory_id(1)]");
-    assert!(memory_id < 6, "memory_id must be less than 6");
     memory_id
 }
+
+/// Key type given by `#[key_type(...)]`, `u64` by default.
+fn key_type(input: &DeriveInput) -> syn::Result<Type> {
+    match input
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("key_type"))
+    {
+        Some(attr) => attr.parse_args::<Type>(),
+        None => Ok(parse_quote! { u64 }),
+    }
+}
+
+/// Field with `#[index(memory_id = N)]`, looked up by its name in `Data`.
+struct IndexedField {
+    ident: syn::Ident,
+    ty: Type,
+    data_key: String,
+    memory_id: u8,
+}
+
+fn indexed_fields(input: &DeriveInput) -> syn::Result<Vec<IndexedField>> {
+    let fields = match &input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(named),
+            ..
+        }) => &named.named,
+        _ => return Ok(vec![]),
+    };
+    let mut indexed = vec![];
+    for field in fields {
+        let attr = match field.attrs.iter().find(|a| a.path().is_ident("index")) {
+            Some(attr) => attr,
+            None => continue,
+        };
+        let mut memory_id = None;
+        if !matches!(attr.meta, syn::Meta::Path(_)) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("memory_id") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    memory_id = Some(lit.base10_parse::<u8>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported index attribute, expected `memory_id = ...`"))
+                }
+            })?;
+        }
+        let memory_id = memory_id.ok_or_else(|| {
+            syn::Error::new_spanned(attr, "memory_id is required. e.g. #[index(memory_id = 20)]")
+        })?;
+        let ident = field.ident.clone().unwrap();
+        let opts = persist_field_opts(field)?;
+        if opts.skip {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "fields with #[persist(skip)] cannot be indexed",
+            ));
+        }
+        indexed.push(IndexedField {
+            data_key: opts.rename.unwrap_or_else(|| ident.to_string()),
+            ident,
+            ty: field.ty.clone(),
+            memory_id,
+        });
+    }
+    Ok(indexed)
+}
+
+/// Methods of a store derive to maintain and look up the indexes of the fields.
+fn index_quotes(
+    indexes: &[IndexedField],
+    key: &Type,
+    response: proc_macro2::TokenStream,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let index_entries = indexes.iter().map(|f| {
+        let IndexedField {
+            data_key,
+            memory_id,
+            ..
+        } = f;
+        quote! { (#data_key, chainsight_cdk::storage::StoreIndex::new(#memory_id)) }
+    });
+    let find_functions = indexes.iter().map(|f| {
+        let IndexedField {
+            ident,
+            ty,
+            data_key,
+            memory_id,
+        } = f;
+        let find = format_ident!("find_by_{}", ident);
+        quote! {
+            pub fn #find(value: #ty) -> Result<#response, chainsight_cdk::storage::StorageError> {
+                Self::get_store().find(
+                    #data_key,
+                    &chainsight_cdk::storage::Tokenizable::to_token(&value),
+                    &chainsight_cdk::storage::StoreIndex::new(#memory_id),
+                )
+            }
+        }
+    });
+    let indexes_function = quote! {
+        fn indexes() -> Vec<(&'static str, chainsight_cdk::storage::StoreIndex<#key>)> {
+            vec![#(#index_entries),*]
+        }
+    };
+    (indexes_function, quote! { #(#find_functions)* })
+}
+
+/// Input of `generate_queries_for_key_value(s)_store_struct`: the struct, its key type, `u64` by default,
+/// and the indexed fields with their types, e.g. `Transfer, u64, index(to: String)`.
+struct StoreQueriesInput {
+    name: Type,
+    key: Type,
+    indexes: Vec<syn::Field>,
+}
+impl Parse for StoreQueriesInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Type = input.parse()?;
+        let mut key = parse_quote! { u64 };
+        let mut indexes = vec![];
+        while input.peek(syn::Token![,]) {
+            input.parse::<syn::Token![,]>()?;
+            if input.peek(syn::Ident) && input.peek2(syn::token::Paren) {
+                let ident: syn::Ident = input.fork().parse()?;
+                if ident == "index" {
+                    input.parse::<syn::Ident>()?;
+                    let content;
+                    syn::parenthesized!(content in input);
+                    indexes = content
+                        .parse_terminated(syn::Field::parse_named, syn::Token![,])?
+                        .into_iter()
+                        .collect();
+                    continue;
+                }
+            }
+            key = input.parse()?;
+        }
+        Ok(StoreQueriesInput { name, key, indexes })
+    }
+}
+
+/// Query and proxy endpoints to look up the store by the indexed fields.
+fn index_queries(
+    name: &Type,
+    lowercase_name: &str,
+    indexes: &[syn::Field],
+    response: Type,
+) -> Vec<proc_macro2::TokenStream> {
+    let query_attrs = attrs_query_func();
+    let update_attrs = attrs_update_func();
+    indexes
+        .iter()
+        .map(|field| {
+            let field_name = field.ident.as_ref().unwrap();
+            let ty = &field.ty;
+            let find = format_ident!("find_by_{}", field_name);
+            let getter = format_ident!("get_{}_by_{}", lowercase_name, field_name);
+            let _getter_str = format!("_get_{}_by_{}", lowercase_name, field_name);
+            let _getter = syn::Ident::new(&_getter_str, Span::call_site());
+            let proxy_getter_quote = gen_func_quote_to_call_proxy(
+                &format!("proxy_get_{}_by_{}", lowercase_name, field_name),
+                response.clone(),
+                Some(ty.clone()),
+                &_getter_str,
+            );
+            quote! {
+                #query_attrs
+                fn #getter(value: #ty) -> #response {
+                    #_getter(value)
+                }
+                fn #_getter(value: #ty) -> #response {
+                    #name::#find(value).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
+                }
+                #update_attrs
+                #proxy_getter_quote
+            }
+        })
+        .collect()
+}
 pub fn key_values_store_derive(input: TokenStream) -> TokenStream {
     key_values_store_derive_internal(syn::parse_macro_input!(input as syn::DeriveInput)).into()
 }
 fn key_values_store_derive_internal(input: syn::DeriveInput) -> proc_macro2::TokenStream {
     let name = input.clone().ident;
+    let key = match key_type(&input) {
+        Ok(key) => key,
+        Err(e) => return e.into_compile_error(),
+    };
+    let indexes = match indexed_fields(&input) {
+        Ok(indexes) => indexes,
+        Err(e) => return e.into_compile_error(),
+    };
     let memory_id = mem_id(input);
+    let (put, index_functions) = if indexes.is_empty() {
+        (quote! { Self::get_store().set(id, e) }, quote! {})
+    } else {
+        let (indexes_function, find_functions) =
+            index_quotes(&indexes, &key, quote! { HashMap<#key, Vec<Self>> });
+        (
+            quote! { Self::get_store().set_indexed(id, e, &Self::indexes()) },
+            quote! {
+                #indexes_function
+                #find_functions
+            },
+        )
+    };
 
     quote! {
         impl #name {
-            pub fn get(id: u64) -> Vec<Self> {
+            pub fn get(id: #key) -> Result<Vec<Self>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().get(id)
             }
 
-            pub fn put(id: u64, e: Vec<Self>) {
-                Self::get_store().set(id, e)
+            pub fn put(id: #key, e: Vec<Self>) {
+                #put
             }
-            pub fn between(from: u64, to: u64) -> HashMap<u64, Vec<Self>> {
+            pub fn between(from: #key, to: #key) -> Result<HashMap<#key, Vec<Self>>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().between(from, to)
             }
-            pub fn last(n: u64) -> HashMap<u64, Vec<Self>> {
+            pub fn range(range: impl std::ops::RangeBounds<#key>) -> Result<Vec<(#key, Vec<Self>)>, chainsight_cdk::storage::StorageError> {
+                Self::get_store().range(range)
+            }
+            pub fn last(n: u64) -> Result<HashMap<#key, Vec<Self>>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().last_elems(n)
             }
-            fn get_store() -> chainsight_cdk::storage::KeyValuesStore {
-                chainsight_cdk::storage::KeyValuesStore::new(#memory_id)
+            fn get_store() -> chainsight_cdk::storage::KeyValuesStore<#key> {
+                chainsight_cdk::storage::KeyValuesStore::new_keyed(#memory_id)
             }
+            #index_functions
         }
     }
 }
 
 pub fn generate_queries_for_key_values_store_struct(input: TokenStream) -> TokenStream {
     generate_queries_for_key_values_store_struct_internal(syn::parse_macro_input!(
-        input as syn::Type
+        input as StoreQueriesInput
     ))
     .into()
 }
 fn generate_queries_for_key_values_store_struct_internal(
-    name: syn::Type,
+    input: StoreQueriesInput,
 ) -> proc_macro2::TokenStream {
+    let StoreQueriesInput { name, key, indexes } = input;
     let lowercase_name = camel_to_snake(&quote! { #name }.to_string());
 
     let query_attrs = attrs_query_func();
@@ -245,7 +592,7 @@ fn generate_queries_for_key_values_store_struct_internal(
     let proxy_getter_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_get_{}", lowercase_name),
         parse_quote! { Vec<#name> },
-        Some(parse_quote! { u64 }),
+        Some(parse_quote! { #key }),
         &_getter_str,
     );
     let between = syn::Ident::new(&format!("between_{}", lowercase_name), Span::call_site());
@@ -253,8 +600,8 @@ fn generate_queries_for_key_values_store_struct_internal(
     let _between = syn::Ident::new(&_between_str, Span::call_site());
     let proxy_between_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_between_{}", lowercase_name),
-        parse_quote! {  HashMap<u64, Vec<#name>> },
-        Some(parse_quote! { (u64, u64) }),
+        parse_quote! {  HashMap<#key, Vec<#name>> },
+        Some(parse_quote! { (#key, #key) }),
         &_between_str,
     );
     let last = syn::Ident::new(&format!("last_{}", lowercase_name), Span::call_site());
@@ -262,42 +609,49 @@ fn generate_queries_for_key_values_store_struct_internal(
     let _last = syn::Ident::new(&_last_str, Span::call_site());
     let proxy_last_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_last_{}", lowercase_name),
-        parse_quote! {  HashMap<u64, Vec<#name>> },
+        parse_quote! {  HashMap<#key, Vec<#name>> },
         Some(parse_quote! { u64 }),
         &_last_str,
     );
 
+    let index_queries = index_queries(
+        &name,
+        &lowercase_name,
+        &indexes,
+        parse_quote! { HashMap<#key, Vec<#name>> },
+    );
     quote! {
         #query_attrs
-        fn #getter(id: u64) -> Vec<#name> {
+        fn #getter(id: #key) -> Vec<#name> {
             #_getter(id)
         }
-        fn #_getter(id: u64) -> Vec<#name> {
-            #name::get(id)
+        fn #_getter(id: #key) -> Vec<#name> {
+            #name::get(id).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
         #update_attrs
         #proxy_getter_quote
 
         #query_attrs
-        fn #between(a: (u64, u64)) -> HashMap<u64, Vec<#name>> {
+        fn #between(a: (#key, #key)) -> HashMap<#key, Vec<#name>> {
             #_between(a)
         }
-        fn #_between(a: (u64, u64)) -> HashMap<u64, Vec<#name>> {
-            #name::between(a.0, a.1)
+        fn #_between(a: (#key, #key)) -> HashMap<#key, Vec<#name>> {
+            #name::between(a.0, a.1).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
 
         #update_attrs
         #proxy_between_quote
         #query_attrs
-        fn #last(n: u64) -> HashMap<u64, Vec<#name>> {
+        fn #last(n: u64) -> HashMap<#key, Vec<#name>> {
             #_last(n)
         }
-        fn #_last(n: u64) -> HashMap<u64, Vec<#name>> {
-            #name::last(n)
+        fn #_last(n: u64) -> HashMap<#key, Vec<#name>> {
+            #name::last(n).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
         #update_attrs
         #proxy_last_quote
 
+        #(#index_queries)*
     }
 }
 
@@ -306,37 +660,66 @@ pub fn key_value_store_derive(input: TokenStream) -> TokenStream {
 }
 pub fn key_value_store_derive_internal(input: syn::DeriveInput) -> proc_macro2::TokenStream {
     let name = input.clone().ident;
+    let key = match key_type(&input) {
+        Ok(key) => key,
+        Err(e) => return e.into_compile_error(),
+    };
+    let indexes = match indexed_fields(&input) {
+        Ok(indexes) => indexes,
+        Err(e) => return e.into_compile_error(),
+    };
     let memory_id = mem_id(input);
+    let (put, index_functions) = if indexes.is_empty() {
+        (
+            quote! { Self::get_store().set(id, self.clone()) },
+            quote! {},
+        )
+    } else {
+        let (indexes_function, find_functions) =
+            index_quotes(&indexes, &key, quote! { Vec<(#key, Self)> });
+        (
+            quote! { Self::get_store().set_indexed(id, self.clone(), &Self::indexes()) },
+            quote! {
+                #indexes_function
+                #find_functions
+            },
+        )
+    };
 
     quote! {
         impl #name {
-            pub fn get(id: u64) -> Option<Self> {
+            pub fn get(id: #key) -> Result<Option<Self>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().get(id)
             }
-            pub fn put(&self, id: u64) {
-                Self::get_store().set(id, self.clone())
+            pub fn put(&self, id: #key) {
+                #put
             }
-            pub fn between(from:u64, to: u64) -> Vec<(u64, Self)> {
+            pub fn between(from: #key, to: #key) -> Result<Vec<(#key, Self)>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().between(from, to)
             }
-            pub fn last(n: u64) -> Vec<(u64, Self)> {
+            pub fn range(range: impl std::ops::RangeBounds<#key>) -> Result<Vec<(#key, Self)>, chainsight_cdk::storage::StorageError> {
+                Self::get_store().range(range)
+            }
+            pub fn last(n: u64) -> Result<Vec<(#key, Self)>, chainsight_cdk::storage::StorageError> {
                 Self::get_store().last(n)
             }
-            fn get_store() -> chainsight_cdk::storage::KeyValueStore {
-                chainsight_cdk::storage::KeyValueStore::new(#memory_id)
+            fn get_store() -> chainsight_cdk::storage::KeyValueStore<#key> {
+                chainsight_cdk::storage::KeyValueStore::new_keyed(#memory_id)
             }
+            #index_functions
         }
     }
 }
 pub fn generate_queries_for_key_value_store_struct(input: TokenStream) -> TokenStream {
     generate_queries_for_key_value_store_struct_internal(syn::parse_macro_input!(
-        input as syn::Type
+        input as StoreQueriesInput
     ))
     .into()
 }
 fn generate_queries_for_key_value_store_struct_internal(
-    name: syn::Type,
+    input: StoreQueriesInput,
 ) -> proc_macro2::TokenStream {
+    let StoreQueriesInput { name, key, indexes } = input;
     let lowercase_name = camel_to_snake(&quote! { #name }.to_string());
 
     let query_attrs = attrs_query_func();
@@ -347,7 +730,7 @@ fn generate_queries_for_key_value_store_struct_internal(
     let proxy_getter_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_get_{}", lowercase_name),
         parse_quote! { Option<#name> },
-        Some(parse_quote! { u64 }),
+        Some(parse_quote! { #key }),
         &_getter_str,
     );
     let between = syn::Ident::new(&format!("between_{}", lowercase_name), Span::call_site());
@@ -355,8 +738,8 @@ fn generate_queries_for_key_value_store_struct_internal(
     let _between = syn::Ident::new(&_between_str, Span::call_site());
     let proxy_between_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_between_{}", lowercase_name),
-        parse_quote! {  Vec<(u64, #name)> },
-        Some(parse_quote! { (u64, u64) }),
+        parse_quote! {  Vec<(#key, #name)> },
+        Some(parse_quote! { (#key, #key) }),
         &_between_str,
     );
     let last = syn::Ident::new(&format!("last_{}", lowercase_name), Span::call_site());
@@ -364,40 +747,47 @@ fn generate_queries_for_key_value_store_struct_internal(
     let _last = syn::Ident::new(&_last_str, Span::call_site());
     let proxy_last_quote = gen_func_quote_to_call_proxy(
         &format!("proxy_last_{}", lowercase_name),
-        parse_quote! {  Vec<(u64, #name)> },
+        parse_quote! {  Vec<(#key, #name)> },
         Some(parse_quote! { u64 }),
         &_last_str,
     );
+    let index_queries = index_queries(
+        &name,
+        &lowercase_name,
+        &indexes,
+        parse_quote! { Vec<(#key, #name)> },
+    );
     quote! {
         #query_attrs
-        fn #getter(id: u64) -> Option<#name> {
+        fn #getter(id: #key) -> Option<#name> {
             #_getter(id)
         }
-        fn #_getter(id: u64) -> Option<#name> {
-            #name::get(id)
+        fn #_getter(id: #key) -> Option<#name> {
+            #name::get(id).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
         #update_attrs
         #proxy_getter_quote
 
         #query_attrs
-        fn #between(a:(u64, u64)) -> Vec<(u64, #name)> {
+        fn #between(a: (#key, #key)) -> Vec<(#key, #name)> {
             #_between(a)
         }
-        fn #_between(a:(u64, u64)) -> Vec<(u64, #name)> {
-            #name::between(a.0, a.1)
+        fn #_between(a: (#key, #key)) -> Vec<(#key, #name)> {
+            #name::between(a.0, a.1).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
         #update_attrs
         #proxy_between_quote
 
         #query_attrs
-        fn #last(n: u64) -> Vec<(u64, #name)> {
+        fn #last(n: u64) -> Vec<(#key, #name)> {
             #_last(n)
         }
-        fn #_last(n: u64) -> Vec<(u64, #name)> {
-            #name::last(n)
+        fn #_last(n: u64) -> Vec<(#key, #name)> {
+            #name::last(n).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
         }
         #update_attrs
         #proxy_last_quote
+        #(#index_queries)*
     }
 }
 
@@ -552,18 +942,7 @@ fn stable_memory_for_vec_internal(args: StableMemoryForVecInput) -> proc_macro2:
         }
 
         pub fn #_get_top_elems_func(n: u64) -> Vec<#ty> {
-            #state_upper_name.with(|mem| {
-                let borrowed_mem = mem.borrow();
-                let len = borrowed_mem.len();
-                let mut res = Vec::new();
-                for i in 0..n {
-                    if i >= len {
-                        break;
-                    }
-                    res.push(borrowed_mem.get(len - i - 1).unwrap());
-                }
-                res
-            })
+            #state_upper_name.with(|mem| mem.borrow().iter().rev().take(n as usize).collect())
         }
 
         #update_derives
@@ -598,6 +977,7 @@ struct StableMemoryForBTreeMapInput {
     ty: Type,
     memory_id: u8,
     is_expose_getter: LitBool,
+    retention: Option<RetentionArgs>,
 }
 impl syn::parse::Parse for StableMemoryForBTreeMapInput {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
@@ -609,11 +989,18 @@ impl syn::parse::Parse for StableMemoryForBTreeMapInput {
         let memory_id = lit_memory_id.base10_parse::<u8>().unwrap();
         input.parse::<syn::Token![,]>()?;
         let is_expose_getter: LitBool = input.parse()?;
+        let retention = if input.peek(syn::Token![,]) {
+            input.parse::<syn::Token![,]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
         Ok(StableMemoryForBTreeMapInput {
             name,
             ty,
             memory_id,
             is_expose_getter,
+            retention,
         })
     }
 }
@@ -629,10 +1016,26 @@ fn stable_memory_for_btree_map_internal(
         ty,
         memory_id,
         is_expose_getter,
+        retention,
     } = args;
 
     let state_name = name.value();
     let state_upper_name = syn::Ident::new(&format!("{}S", state_name.to_uppercase()), name.span());
+    let (retention_quote, on_insert_quote) = match retention {
+        Some(retention) => gen_retention_quote(
+            &state_name,
+            &retention,
+            quote! {
+                #state_upper_name.with(|mem| {
+                    let now_secs = ic_cdk::api::time() / 1_000_000_000;
+                    policy.prune(&mut *mem.borrow_mut(), now_secs, limit, |_, v| {
+                        chainsight_cdk::storage::Retainable::stamp(v)
+                    })
+                })
+            },
+        ),
+        None => (quote! {}, quote! {}),
+    };
     let get_vec_func = syn::Ident::new(&format!("get_{}s", state_name), name.span());
     let _get_vec_func_str = format!("_get_{}s", state_name);
     let _get_vec_func = syn::Ident::new(&_get_vec_func_str, name.span());
@@ -684,6 +1087,7 @@ fn stable_memory_for_btree_map_internal(
     let add_elem_func = syn::Ident::new(&format!("add_{}", state_name), name.span());
     let add_elem_func_internal =
         syn::Ident::new(&format!("add_{}_internal", state_name), name.span());
+    let migrate_func = syn::Ident::new(&format!("migrate_{}s", state_name), name.span());
 
     let getter_derives = if is_expose_getter.value {
         attrs_query_func()
@@ -710,12 +1114,7 @@ fn stable_memory_for_btree_map_internal(
         }
 
         pub fn #_get_vec_func() -> Vec<#ty> {
-            let data_len = #_get_len_func();
-            if data_len > 0 {
-                #range_func(0, data_len)
-            } else {
-                vec![]
-            }
+            #state_upper_name.with(|mem| mem.borrow().iter().map(|(_, v)| v).collect())
         }
 
         #update_derives
@@ -754,12 +1153,11 @@ fn stable_memory_for_btree_map_internal(
         }
 
         pub fn #_get_top_elems_func(n: u64) -> Vec<#ty> {
-            let data_len = #_get_len_func();
-            if data_len == 0 {
-                return vec![];
-            }
-            let from = if n > data_len { 0 } else { data_len - n };
-            #range_func(from, data_len)
+            let mut elems: Vec<#ty> = #state_upper_name.with(|mem| {
+                mem.borrow().iter().rev().take(n as usize).map(|(_, v)| v).collect()
+            });
+            elems.reverse();
+            elems
         }
 
         #update_derives
@@ -793,10 +1191,22 @@ fn stable_memory_for_btree_map_internal(
         }
 
         pub fn #add_elem_func_internal(value: #ty) -> Result<(), String> {
-            let new_key = #_get_len_func();
-            #state_upper_name.with(|mem| mem.borrow_mut().insert(new_key, value));
+            #state_upper_name.with(|mem| {
+                let mut mem = mem.borrow_mut();
+                let new_key = mem.last_key_value().map_or(0, |(k, _)| k + 1);
+                mem.insert(new_key, value)
+            });
+            #on_insert_quote
             Ok(())
         }
+
+        /// Rewrites the elements encoded by older versions of their type in the current one, e.g. in `post_upgrade`.
+        /// Reads up to `limit` elements from `from`; continue from `next` until it is `None`.
+        pub fn #migrate_func(from: Option<u64>, limit: u64) -> chainsight_cdk::storage::MigrationProgress<u64> {
+            #state_upper_name.with(|mem| chainsight_cdk::storage::migrate_entries(&mut *mem.borrow_mut(), from, limit))
+        }
+
+        #retention_quote
     }
 }
 
@@ -809,13 +1219,30 @@ mod test {
 
     #[test]
     fn test_snapshot_prepare_stable_structure() {
-        let generated = prepare_stable_structure_internal();
+        let generated = prepare_stable_structure_internal(None);
         let formatted = RustFmt::default()
             .format_str(generated.to_string())
             .expect("rustfmt failed");
         assert_snapshot!("snapshot__prepare_stable_structure", formatted);
     }
 
+    #[test]
+    fn test_snapshot_prepare_stable_structure_with_export() {
+        let input = quote! { export(memories = [1], stores, indexes = [20, 21]) };
+        let args: ExportArgs = syn::parse2(input).unwrap();
+        let generated = prepare_stable_structure_internal(Some(args));
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__prepare_stable_structure_with_export", formatted);
+    }
+
+    #[test]
+    fn test_prepare_stable_structure_with_unknown_export() {
+        let input = quote! { export(canister) };
+        assert!(syn::parse2::<ExportArgs>(input).is_err());
+    }
+
     #[test]
     fn test_snapshot_stable_memory_for_scalar() {
         let input = quote! {"timestamp", u64, 0, true};
@@ -849,6 +1276,38 @@ mod test {
         assert_snapshot!("snapshot__stable_memory_for_btree_map", formatted);
     }
 
+    #[test]
+    fn test_stable_memory_for_btree_map_with_retention() {
+        let input = quote! {"snapshot", Snapshot, 1, true, retention(max_entries = 1000, max_age_secs = 86400)};
+        let args: syn::Result<StableMemoryForBTreeMapInput> = syn::parse2(input);
+        let generated = stable_memory_for_btree_map_internal(args.unwrap());
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!(
+            "snapshot__stable_memory_for_btree_map_with_retention",
+            formatted
+        );
+
+        let input = quote! {"snapshot", Snapshot, 1, true, retention(max_entries = 1000, prune_interval_secs = 3600)};
+        let args: syn::Result<StableMemoryForBTreeMapInput> = syn::parse2(input);
+        let generated = stable_memory_for_btree_map_internal(args.unwrap());
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!(
+            "snapshot__stable_memory_for_btree_map_with_retention_by_timer",
+            formatted
+        );
+    }
+
+    #[test]
+    fn test_stable_memory_for_btree_map_with_unknown_retention() {
+        let input = quote! {"snapshot", Snapshot, 1, true, retention(max_blocks = 10)};
+        let args: syn::Result<StableMemoryForBTreeMapInput> = syn::parse2(input);
+        assert!(args.is_err());
+    }
+
     #[test]
     fn test_snapshot_key_values_store_derive() {
         let input = quote! {
@@ -888,7 +1347,7 @@ mod test {
     #[test]
     fn test_snapshot_generate_queries_for_key_values_store_struct() {
         let input = quote! { Account };
-        let input: syn::Type = syn::parse2(input).unwrap();
+        let input: StoreQueriesInput = syn::parse2(input).unwrap();
         let generated = generate_queries_for_key_values_store_struct_internal(input);
         let formatted = RustFmt::default()
             .format_str(generated.to_string())
@@ -902,7 +1361,7 @@ mod test {
     #[test]
     fn test_snapshot_generate_queries_for_key_value_store_struct() {
         let input = quote! { Account };
-        let input: syn::Type = syn::parse2(input).unwrap();
+        let input: StoreQueriesInput = syn::parse2(input).unwrap();
         let generated = generate_queries_for_key_value_store_struct_internal(input);
         let formatted = RustFmt::default()
             .format_str(generated.to_string())
@@ -912,4 +1371,87 @@ mod test {
             formatted
         );
     }
+
+    #[test]
+    fn test_snapshot_key_value_store_derive_with_key_type() {
+        let input = quote! {
+            #[memory_id(7)]
+            #[key_type(([u8; 20], u64))]
+            struct Balance {
+                pub balance: u64,
+            }
+        };
+        let input: syn::DeriveInput = syn::parse2(input).unwrap();
+        let generated = key_value_store_derive_internal(input);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__key_value_store_derive_with_key_type", formatted);
+    }
+
+    #[test]
+    fn test_snapshot_generate_queries_for_key_values_store_struct_with_key_type() {
+        let input = quote! { Transfer, (candid::Principal, u64) };
+        let input: StoreQueriesInput = syn::parse2(input).unwrap();
+        let generated = generate_queries_for_key_values_store_struct_internal(input);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!(
+            "snapshot__generate_queries_for_key_values_store_struct_with_key_type",
+            formatted
+        );
+    }
+
+    #[test]
+    fn test_snapshot_key_values_store_derive_with_index() {
+        let input = quote! {
+            #[memory_id(2)]
+            struct Transfer {
+                #[index(memory_id = 20)]
+                pub from: String,
+                #[index(memory_id = 21)]
+                #[persist(rename = "recipient")]
+                pub to: String,
+                pub value: u64,
+            }
+        };
+        let input: syn::DeriveInput = syn::parse2(input).unwrap();
+        let generated = key_values_store_derive_internal(input);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__key_values_store_derive_with_index", formatted);
+    }
+
+    #[test]
+    fn test_index_without_memory_id() {
+        let input: syn::DeriveInput = syn::parse2(quote! {
+            #[memory_id(2)]
+            struct Transfer {
+                #[index]
+                pub to: String,
+            }
+        })
+        .unwrap();
+        let err = indexed_fields(&input).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "memory_id is required. e.g. #[index(memory_id = 20)]"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_generate_queries_for_key_value_store_struct_with_index() {
+        let input = quote! { Balance, candid::Principal, index(token: String) };
+        let input: StoreQueriesInput = syn::parse2(input).unwrap();
+        let generated = generate_queries_for_key_value_store_struct_internal(input);
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!(
+            "snapshot__generate_queries_for_key_value_store_struct_with_index",
+            formatted
+        );
+    }
 }
@@ -37,60 +37,223 @@ impl ContractCall {
 }
 
 pub fn contract_event_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    // get struct body
     let input = syn::parse_macro_input!(input as syn::DeriveInput);
-    // get struct name
+    contract_event_derive_internal(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn contract_event_derive_internal(input: syn::DeriveInput) -> Result<proc_macro2::TokenStream> {
     let name = input.ident;
-    // get struct fields
     let fields = match input.data {
         syn::Data::Struct(syn::DataStruct {
-            fields: syn::Fields::Named(syn::FieldsNamed { ref named, .. }),
+            fields: syn::Fields::Named(syn::FieldsNamed { named, .. }),
             ..
         }) => named,
-        _ => panic!("Only support struct"),
+        _ => {
+            return Err(syn::Error::new(
+                name.span(),
+                "ContractEvent can only be derived for structs with named fields",
+            ))
+        }
     };
-    // get field name and type
-    let mut field_names = Vec::new();
-    let mut field_types = Vec::new();
-    let mut token_to_field_functions = Vec::new();
+
+    let mut field_inits = Vec::new();
     for field in fields {
         let field_name = field.ident.as_ref().unwrap();
-        let field_type = &field.ty;
-        field_names.push(field_name);
-        field_types.push(field_type.clone());
-        // if field is String, use to_string() to convert
-        let func = match field_type.to_token_stream().to_string().as_str() {
-            "String" => quote! { to_string() },
-            "Vec<u8>" => quote! { into_arary().unwrap() },
-            "u128" => quote! { into_uint().unwrap().as_u128() },
-            "u64" => quote! { into_uint().unwrap().as_u64() },
-            "u32" => quote! { into_uint().unwrap().as_u32() },
-            "u16" => quote! { into_uint().unwrap().as_u16() },
-            "u8" => quote! { into_uint().unwrap().as_u8() },
-            "bool" => quote! { into_bool().unwrap() },
-            "U256" => quote! { into_uint().unwrap().into() },
-            _ => quote! {},
+        let param_name = field_name.to_string();
+        let find = quote! {
+            item.event.params.iter().find(|p| p.name == #param_name).map(|p| p.value.clone())
         };
-
-        token_to_field_functions.push(func);
+        // Option fields are None when the log has no param of the name
+        let init = match option_inner(&field.ty) {
+            Some(inner) => {
+                let value = token_to_value(inner, quote! { token }, &param_name)?;
+                quote! { #find.map(|token| #value) }
+            }
+            None => {
+                let value = token_to_value(&field.ty, quote! { token }, &param_name)?;
+                let msg = format!("param `{}` not found in the log of {}", param_name, name);
+                quote! {{
+                    let token = #find.expect(#msg);
+                    #value
+                }}
+            }
+        };
+        field_inits.push(quote! { #field_name: #init });
     }
 
-    let gen = quote! {
-    impl From<ic_solidity_bindgen::types::EventLog> for #name {
+    Ok(quote! {
+        impl From<ic_solidity_bindgen::types::EventLog> for #name {
             fn from(item: ic_solidity_bindgen::types::EventLog) -> Self {
-                let mut event = #name::default();
-                let mut params = item.event.params.iter();
-                #(
-                    let token = params.clone().find(|p| p.name == stringify!(#field_names)).unwrap().clone().value;
-                    // match type of field_name
-                    event.#field_names = token.#token_to_field_functions;
-
-                 )*
-                event
+                Self {
+                    #(#field_inits),*
+                }
             }
         }
+    })
+}
+
+const CONTRACT_EVENT_SUPPORTED_TYPES: &str = "String, bool, u8-u128, i8-i128, U256, I256, Address, Vec<u8>, [u8; N], Vec<T>, [T; N], tuples and Option<T>";
+
+fn option_inner(ty: &syn::Type) -> Option<&syn::Type> {
+    generic_inner(ty, "Option")
+}
+
+/// The type argument of `ty` if `ty` is `wrapper<T>`.
+fn generic_inner<'a>(ty: &'a syn::Type, wrapper: &str) -> Option<&'a syn::Type> {
+    let type_path = match ty {
+        syn::Type::Path(type_path) => type_path,
+        _ => return None,
     };
-    gen.into()
+    let segment = type_path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(syn::GenericArgument::Type(inner)) if args.args.len() == 1 => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_u8(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(p) if p.path.is_ident("u8"))
+}
+
+/// Expression converting `token`, an `ethabi::Token`, into a value of `ty`.
+fn token_to_value(
+    ty: &syn::Type,
+    token: proc_macro2::TokenStream,
+    param_name: &str,
+) -> Result<proc_macro2::TokenStream> {
+    let unsupported = || {
+        syn::Error::new_spanned(
+            ty,
+            format!(
+                "unsupported type `{}` for param `{}` of ContractEvent, expected one of {}",
+                ty.to_token_stream(),
+                param_name,
+                CONTRACT_EVENT_SUPPORTED_TYPES
+            ),
+        )
+    };
+    let mismatch = |kind: &str| {
+        format!(
+            "param `{}` is not {}, the type of the field does not match the event",
+            param_name, kind
+        )
+    };
+    let value = match ty {
+        syn::Type::Path(type_path) => {
+            if let Some(inner) = generic_inner(ty, "Vec") {
+                if is_u8(inner) {
+                    let msg = mismatch("bytes");
+                    return Ok(quote! {
+                        match #token {
+                            ic_web3_rs::ethabi::Token::Bytes(b) | ic_web3_rs::ethabi::Token::FixedBytes(b) => b,
+                            _ => panic!(#msg),
+                        }
+                    });
+                }
+                let elem = token_to_value(inner, quote! { token }, param_name)?;
+                let msg = mismatch("an array");
+                return Ok(quote! {
+                    match #token {
+                        ic_web3_rs::ethabi::Token::Array(tokens) | ic_web3_rs::ethabi::Token::FixedArray(tokens) => {
+                            tokens.into_iter().map(|token| #elem).collect::<Vec<_>>()
+                        }
+                        _ => panic!(#msg),
+                    }
+                });
+            }
+            if let Some(inner) = option_inner(ty) {
+                let inner = token_to_value(inner, token, param_name)?;
+                return Ok(quote! { Some(#inner) });
+            }
+            let ident = match type_path.path.segments.last() {
+                Some(segment) if segment.arguments.is_empty() => segment.ident.to_string(),
+                _ => return Err(unsupported()),
+            };
+            match ident.as_str() {
+                "String" => quote! { #token.to_string() },
+                "bool" => {
+                    let msg = mismatch("a bool");
+                    quote! { #token.into_bool().expect(#msg) }
+                }
+                "u8" | "u16" | "u32" | "u64" | "u128" => {
+                    let msg = mismatch("a uint");
+                    let overflow = format!("param `{}` overflows {}", param_name, ident);
+                    quote! { #ty::try_from(#token.into_uint().expect(#msg)).expect(#overflow) }
+                }
+                "i8" | "i16" | "i32" | "i64" | "i128" => {
+                    let msg = mismatch("an int");
+                    let overflow = format!("param `{}` overflows {}", param_name, ident);
+                    // decoded from the full two's complement, as its low bits alone may flip the sign
+                    quote! {
+                        <#ty as std::str::FromStr>::from_str(
+                            chainsight_cdk::core::I256::from_ethabi_int(#token.into_int().expect(#msg)).value()
+                        ).expect(#overflow)
+                    }
+                }
+                "U256" => {
+                    let msg = mismatch("a uint");
+                    quote! { #token.into_uint().expect(#msg).into() }
+                }
+                "I256" => {
+                    let msg = mismatch("an int");
+                    quote! { chainsight_cdk::core::I256::from_ethabi_int(#token.into_int().expect(#msg)) }
+                }
+                "Address" | "H160" => {
+                    let msg = mismatch("an address");
+                    quote! { #token.into_address().expect(#msg) }
+                }
+                _ => return Err(unsupported()),
+            }
+        }
+        syn::Type::Array(array) => {
+            let len = &array.len;
+            if is_u8(&array.elem) {
+                let msg = mismatch(&format!("bytes{}", len.to_token_stream()));
+                quote! {
+                    <[u8; #len]>::try_from(#token.into_fixed_bytes().expect(#msg).as_slice()).expect(#msg)
+                }
+            } else {
+                let elem = token_to_value(&array.elem, quote! { token }, param_name)?;
+                let msg = mismatch(&format!("an array of {}", len.to_token_stream()));
+                quote! {
+                    match #token {
+                        ic_web3_rs::ethabi::Token::Array(tokens) | ic_web3_rs::ethabi::Token::FixedArray(tokens) => {
+                            tokens.into_iter().map(|token| #elem).collect::<Vec<_>>().try_into().unwrap_or_else(|_| panic!(#msg))
+                        }
+                        _ => panic!(#msg),
+                    }
+                }
+            }
+        }
+        syn::Type::Tuple(tuple) if !tuple.elems.is_empty() => {
+            let elems = tuple
+                .elems
+                .iter()
+                .map(|elem| token_to_value(elem, quote! { tokens.next().unwrap() }, param_name))
+                .collect::<Result<Vec<_>>>()?;
+            let len = tuple.elems.len();
+            let msg = mismatch(&format!("a tuple of {}", len));
+            quote! {
+                match #token {
+                    ic_web3_rs::ethabi::Token::Tuple(tokens) if tokens.len() == #len => {
+                        let mut tokens = tokens.into_iter();
+                        (#(#elems,)*)
+                    }
+                    _ => panic!(#msg),
+                }
+            }
+        }
+        _ => return Err(unsupported()),
+    };
+    Ok(value)
 }
 
 pub fn define_transform_for_web3(_input: TokenStream) -> TokenStream {
@@ -128,6 +291,27 @@ fn define_transform_for_web3_internal() -> proc_macro2::TokenStream {
             processor.transform(response)
         }
 
+        #attrs
+        fn transform_block_header(response: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+            use chainsight_cdk::web3::TransformProcessor;
+            let processor = chainsight_cdk::web3::processors::BlockHeaderProcessor;
+            processor.transform(response)
+        }
+
+        #attrs
+        fn transform_block_number(response: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+            use chainsight_cdk::web3::TransformProcessor;
+            let processor = chainsight_cdk::web3::processors::BlockNumberProcessor;
+            processor.transform(response)
+        }
+
+        #attrs
+        fn transform_transaction_receipt(response: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+            use chainsight_cdk::web3::TransformProcessor;
+            let processor = chainsight_cdk::web3::processors::TransactionReceiptProcessor;
+            processor.transform(response)
+        }
+
     }
 }
 
@@ -264,6 +448,98 @@ fn define_get_ethereum_address_internal() -> proc_macro2::TokenStream {
     }
 }
 
+pub fn define_nonce_manager(input: TokenStream) -> TokenStream {
+    let memory_id = syn::parse_macro_input!(input as LitInt);
+    define_nonce_manager_internal(memory_id).into()
+}
+
+fn define_nonce_manager_internal(memory_id: LitInt) -> proc_macro2::TokenStream {
+    quote! {
+        thread_local! {
+            static NONCES: std::cell::RefCell<chainsight_cdk::web3::NonceStore> = std::cell::RefCell::new(
+                ic_stable_structures::StableBTreeMap::init(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(ic_stable_structures::memory_manager::MemoryId::new(#memory_id)))
+                )
+            );
+        }
+        fn nonce_manager() -> chainsight_cdk::web3::NonceManager {
+            chainsight_cdk::web3::NonceManager::new(&NONCES)
+        }
+
+        #[ic_cdk::update]
+        #[candid::candid_method(update)]
+        #[chainsight_cdk_macros::only_controller]
+        fn reset_nonce(chain_id: u64, sender: String) {
+            let sender = Address::from_str(&sender).expect("Failed to parse sender to Address");
+            nonce_manager().reset(chain_id, sender);
+        }
+    }
+}
+
+/// Memory ids of the relays and of the index of the relays in flight.
+struct RelayTrackerInput {
+    memory_id: LitInt,
+    index_memory_id: LitInt,
+}
+impl Parse for RelayTrackerInput {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let memory_id = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let index_memory_id = input.parse()?;
+        Ok(Self {
+            memory_id,
+            index_memory_id,
+        })
+    }
+}
+
+pub fn define_relay_tracker(input: TokenStream) -> TokenStream {
+    let RelayTrackerInput {
+        memory_id,
+        index_memory_id,
+    } = syn::parse_macro_input!(input as RelayTrackerInput);
+    define_relay_tracker_internal(memory_id, index_memory_id).into()
+}
+
+fn define_relay_tracker_internal(
+    memory_id: LitInt,
+    index_memory_id: LitInt,
+) -> proc_macro2::TokenStream {
+    let query_attrs = attrs_query_func();
+    quote! {
+        thread_local! {
+            static RELAYS: std::cell::RefCell<chainsight_cdk::web3::RelayStore> = std::cell::RefCell::new(
+                ic_stable_structures::StableBTreeMap::init(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(ic_stable_structures::memory_manager::MemoryId::new(#memory_id)))
+                )
+            );
+            static RELAYS_IN_FLIGHT: std::cell::RefCell<chainsight_cdk::web3::RelayIndex> = std::cell::RefCell::new(
+                ic_stable_structures::StableBTreeMap::init(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(ic_stable_structures::memory_manager::MemoryId::new(#index_memory_id)))
+                )
+            );
+        }
+        fn relay_tracker() -> chainsight_cdk::web3::RelayTracker {
+            chainsight_cdk::web3::RelayTracker::new(&RELAYS, &RELAYS_IN_FLIGHT)
+        }
+
+        #query_attrs
+        fn get_relay(id: u64) -> Option<chainsight_cdk::web3::RelayRecord> {
+            relay_tracker().get(id)
+        }
+
+        #query_attrs
+        fn get_last_relays(n: u64) -> Vec<(u64, chainsight_cdk::web3::RelayRecord)> {
+            relay_tracker().last(n)
+        }
+
+        #query_attrs
+        fn get_relays_in_flight() -> Vec<(u64, chainsight_cdk::web3::RelayRecord)> {
+            relay_tracker().in_flight()
+        }
+    }
+}
+
 pub fn define_withdraw_balance(_input: TokenStream) -> TokenStream {
     define_withdraw_balance_internal().into()
 }
@@ -393,6 +669,47 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_snapshot_contract_event_derive() {
+        let input: syn::DeriveInput = syn::parse_quote! {
+            pub struct Transfer {
+                pub from: ic_web3_rs::types::Address,
+                pub value: U256,
+                pub delta: i64,
+                pub hash: [u8; 32],
+                pub amounts: Vec<u128>,
+                pub data: Vec<u8>,
+                pub pair: (String, bool),
+                pub memo: Option<String>,
+            }
+        };
+        let generated = contract_event_derive_internal(input).unwrap();
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__contract_event_derive", formatted);
+    }
+
+    #[test]
+    fn test_contract_event_derive_unsupported_type() {
+        let input: syn::DeriveInput = syn::parse_quote! {
+            pub struct Transfer {
+                pub value: f64,
+            }
+        };
+        let err = contract_event_derive_internal(input).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unsupported type `f64` for param `value` of ContractEvent"));
+
+        let input: syn::DeriveInput = syn::parse_quote! {
+            pub enum Transfer {
+                A,
+            }
+        };
+        assert!(contract_event_derive_internal(input).is_err());
+    }
+
     #[test]
     fn test_snapshot_define_transform_for_web3() {
         let generated = define_transform_for_web3_internal();
@@ -402,6 +719,25 @@ mod test {
         assert_snapshot!("snapshot__define_transform_for_web3", formatted);
     }
 
+    #[test]
+    fn test_snapshot_define_nonce_manager() {
+        let generated = define_nonce_manager_internal(syn::parse_quote! { 8 });
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__define_nonce_manager", formatted);
+    }
+
+    #[test]
+    fn test_snapshot_define_relay_tracker() {
+        let generated =
+            define_relay_tracker_internal(syn::parse_quote! { 9 }, syn::parse_quote! { 10 });
+        let formatted = RustFmt::default()
+            .format_str(generated.to_string())
+            .expect("rustfmt failed");
+        assert_snapshot!("snapshot__define_relay_tracker", formatted);
+    }
+
     #[test]
     fn test_snapshot_define_web3_ctx() {
         let input = quote! {};
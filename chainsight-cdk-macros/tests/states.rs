@@ -171,3 +171,104 @@ mod manage_map_state {
         assert_eq!(get_message(2), datum2.clone());
     }
 }
+
+mod persist {
+    use chainsight_cdk::{
+        core::U256,
+        storage::{Persist, StorageError, Token},
+    };
+    use chainsight_cdk_macros::Persist;
+
+    #[derive(Debug, Clone, PartialEq, Persist)]
+    pub struct Transfer {
+        pub to: String,
+        pub amount: U256,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Persist)]
+    pub struct Account {
+        #[persist(rename = "owner_name")]
+        pub owner: String,
+        pub last: Transfer,
+        pub history: Vec<Transfer>,
+        pub tags: Vec<String>,
+        pub memo: Option<String>,
+        pub parent: Option<Transfer>,
+        #[persist(skip)]
+        pub cache: u64,
+    }
+
+    fn account() -> Account {
+        let transfer = |to: &str, amount: u64| Transfer {
+            to: to.to_string(),
+            amount: ic_web3_rs::types::U256::from(amount).into(),
+        };
+        Account {
+            owner: "alice".to_string(),
+            last: transfer("bob", 1),
+            history: vec![transfer("carol", 2), transfer("dave", 3)],
+            tags: vec!["a".to_string()],
+            memo: None,
+            parent: Some(transfer("erin", 4)),
+            cache: 100,
+        }
+    }
+
+    #[test]
+    fn test_nested_round_trip() {
+        let data = account().tokenize();
+        assert_eq!(data.get("owner_name"), Some(&Token::from("alice")));
+        assert_eq!(data.get("owner"), None);
+        assert_eq!(data.get("cache"), None);
+        assert_eq!(
+            Account::untokenize(data),
+            Account {
+                cache: 0,
+                ..account()
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_option_is_none() {
+        let mut account = account();
+        account.parent = None;
+        let data = account.tokenize();
+        let without_parent = chainsight_cdk::storage::Data::new(
+            data.entries()
+                .filter(|(k, _)| k.as_str() != "parent")
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert_eq!(
+            Account::untokenize(without_parent),
+            Account {
+                cache: 0,
+                ..account
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to untokenize Account")]
+    fn test_missing_field() {
+        Account::untokenize(chainsight_cdk::storage::Data::new(Default::default()));
+    }
+
+    #[test]
+    fn test_try_untokenize_errors() {
+        assert_eq!(
+            Account::try_untokenize(chainsight_cdk::storage::Data::new(Default::default())),
+            Err(StorageError::MissingField("owner_name".to_string()))
+        );
+
+        let mut data = std::collections::HashMap::new();
+        data.insert("to".to_string(), Token::from("bob"));
+        data.insert("amount".to_string(), Token::from(-1i64));
+        let err = Transfer::try_untokenize(chainsight_cdk::storage::Data::new(data)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid field amount: Out of range: -1 does not fit in U256"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use chainsight_cdk_macros::{KeyValueStore, KeyValuesStore, Persist};
+
+#[derive(Debug, Clone, PartialEq, Persist, KeyValuesStore)]
+#[memory_id(1)]
+pub struct Transfer {
+    #[index(memory_id = 20)]
+    pub from: String,
+    #[index(memory_id = 21)]
+    #[persist(rename = "recipient")]
+    pub to: String,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Persist, KeyValueStore)]
+#[memory_id(1)]
+#[key_type(candid::Principal)]
+pub struct Balance {
+    #[index(memory_id = 22)]
+    pub token: String,
+    pub amount: u64,
+}
+
+fn transfer(from: &str, to: &str, value: u64) -> Transfer {
+    Transfer {
+        from: from.to_string(),
+        to: to.to_string(),
+        value,
+    }
+}
+
+#[test]
+fn test_find_by_indexed_field() {
+    Transfer::put(
+        1,
+        vec![transfer("alice", "bob", 1), transfer("carol", "bob", 2)],
+    );
+    Transfer::put(2, vec![transfer("bob", "alice", 3)]);
+
+    let to_bob = Transfer::find_by_to("bob".to_string()).unwrap();
+    assert_eq!(
+        to_bob,
+        HashMap::from([(
+            1,
+            vec![transfer("alice", "bob", 1), transfer("carol", "bob", 2)]
+        )])
+    );
+    assert_eq!(
+        Transfer::find_by_from("bob".to_string()).unwrap(),
+        HashMap::from([(2, vec![transfer("bob", "alice", 3)])])
+    );
+
+    // replacing the values of a key updates the index
+    Transfer::put(1, vec![transfer("alice", "dave", 4)]);
+    assert!(Transfer::find_by_to("bob".to_string()).unwrap().is_empty());
+    assert_eq!(
+        Transfer::find_by_to("dave".to_string()).unwrap(),
+        HashMap::from([(1, vec![transfer("alice", "dave", 4)])])
+    );
+}
+
+#[test]
+fn test_find_by_indexed_field_with_key_type() {
+    let owner = candid::Principal::anonymous();
+    let balance = Balance {
+        token: "ckBTC".to_string(),
+        amount: 10,
+    };
+    balance.put(owner);
+    assert_eq!(
+        Balance::find_by_token("ckBTC".to_string()).unwrap(),
+        vec![(owner, balance)]
+    );
+    assert!(Balance::find_by_token("ckETH".to_string())
+        .unwrap()
+        .is_empty());
+}
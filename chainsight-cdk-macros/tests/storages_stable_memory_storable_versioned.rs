@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode};
+use chainsight_cdk::storage::{migrate_entries, MigrationProgress, StableMemoryMigration};
+use chainsight_cdk_macros::StableMemoryStorable;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use serde::Deserialize;
+
+/// Price before versions were set
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize, StableMemoryStorable)]
+pub struct LegacyPrice {
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize, StableMemoryStorable)]
+#[stable_mem_storable_opts(max_size = 100, is_fixed_size = false, version = 1)]
+pub struct Price {
+    pub value: u64,
+    pub decimals: u8,
+}
+
+impl StableMemoryMigration for Price {
+    fn migrate(version: u32, bytes: &[u8]) -> Self {
+        match version {
+            0 => {
+                let legacy = Decode!(bytes, LegacyPrice).unwrap();
+                Price {
+                    value: legacy.value,
+                    decimals: 0,
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn price(value: u64) -> Price {
+    Price {
+        value,
+        decimals: 18,
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let bytes = price(1).to_bytes().into_owned();
+    assert!(bytes.starts_with(b"VSN"));
+    assert_eq!(Price::from_bytes(Cow::Owned(bytes)), price(1));
+}
+
+#[test]
+fn test_migrate_on_read() {
+    let legacy = LegacyPrice { value: 7 }.to_bytes();
+    assert_eq!(
+        Price::from_bytes(legacy),
+        Price {
+            value: 7,
+            decimals: 0
+        }
+    );
+}
+
+#[test]
+#[should_panic(expected = "Encoded by a newer version 2 of Price")]
+fn test_newer_version() {
+    let bytes = chainsight_cdk::storage::encode_versioned(2, &Encode!(&price(1)).unwrap());
+    Price::from_bytes(Cow::Owned(bytes));
+}
+
+#[test]
+fn test_migrate_entries() {
+    let memory = DefaultMemoryImpl::default();
+    let mut legacy = StableBTreeMap::<u64, LegacyPrice, _>::init(memory.clone());
+    legacy.insert(1, LegacyPrice { value: 1 });
+    legacy.insert(2, LegacyPrice { value: 2 });
+
+    let mut prices = StableBTreeMap::<u64, Price, _>::init(memory);
+    prices.insert(3, price(3));
+    let progress = migrate_entries(&mut prices, None, 2);
+    assert_eq!(
+        progress,
+        MigrationProgress {
+            scanned: 2,
+            migrated: 2,
+            next: Some(3),
+        }
+    );
+    assert_eq!(
+        prices.get(&2),
+        Some(Price {
+            value: 2,
+            decimals: 0
+        })
+    );
+    // entries in the current version are not rewritten
+    assert_eq!(
+        migrate_entries(&mut prices, progress.next, 2),
+        MigrationProgress {
+            scanned: 1,
+            migrated: 0,
+            next: None,
+        }
+    );
+    assert_eq!(migrate_entries(&mut prices, None, 10).migrated, 0);
+
+    let raw = StableBTreeMap::<u64, Vec<u8>, _>::init(prices.into_memory());
+    assert!(raw.iter().all(|(_, bytes)| bytes.starts_with(b"VSN")));
+}
@@ -0,0 +1,150 @@
+mod contract_event {
+    use chainsight_cdk::core::{I256, U256};
+    use chainsight_cdk_macros::ContractEvent;
+    use ic_solidity_bindgen::types::EventLog;
+    use ic_web3_rs::{
+        ethabi::{Log, LogParam, Token},
+        types::{Address, Log as EthLog, U256 as Web3U256},
+    };
+
+    #[derive(Debug, Clone, PartialEq, ContractEvent)]
+    pub struct Swap {
+        pub sender: Address,
+        pub sender_str: String,
+        pub amount: U256,
+        pub delta: i64,
+        pub tick: I256,
+        pub fee: u16,
+        pub salt: [u8; 4],
+        pub data: Vec<u8>,
+        pub path: Vec<Address>,
+        pub pair: (u64, bool),
+        pub memo: Option<String>,
+        pub missing: Option<u64>,
+    }
+
+    fn param(name: &str, value: Token) -> LogParam {
+        LogParam {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    fn int(v: i128) -> Token {
+        Token::Int(if v < 0 {
+            !Web3U256::from((-(v + 1)) as u128)
+        } else {
+            Web3U256::from(v as u128)
+        })
+    }
+
+    fn event_log(params: Vec<LogParam>) -> EventLog {
+        EventLog {
+            event: Log { params },
+            log: EthLog {
+                address: Address::zero(),
+                topics: vec![],
+                data: Default::default(),
+                block_hash: None,
+                block_number: None,
+                transaction_hash: None,
+                transaction_index: None,
+                log_index: None,
+                transaction_log_index: None,
+                log_type: None,
+                removed: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_from_event_log() {
+        let sender = Address::from_low_u64_be(1);
+        let log = event_log(vec![
+            param("sender", Token::Address(sender)),
+            param("sender_str", Token::Address(sender)),
+            param("amount", Token::Uint(Web3U256::from(1_000))),
+            param("delta", int(-5)),
+            param("tick", int(-42)),
+            param("fee", Token::Uint(Web3U256::from(3_000))),
+            param("salt", Token::FixedBytes(vec![1, 2, 3, 4])),
+            param("data", Token::Bytes(vec![5, 6])),
+            param("path", Token::Array(vec![Token::Address(sender)])),
+            param(
+                "pair",
+                Token::Tuple(vec![Token::Uint(Web3U256::from(7)), Token::Bool(true)]),
+            ),
+            param("memo", Token::String("hello".to_string())),
+        ]);
+        let swap = Swap::from(log);
+        assert_eq!(
+            swap,
+            Swap {
+                sender,
+                sender_str: "0000000000000000000000000000000000000001".to_string(),
+                amount: U256::from(Web3U256::from(1_000)),
+                delta: -5,
+                tick: I256::from(-42),
+                fee: 3_000,
+                salt: [1, 2, 3, 4],
+                data: vec![5, 6],
+                path: vec![sender],
+                pair: (7, true),
+                memo: Some("hello".to_string()),
+                missing: None,
+            }
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq, ContractEvent)]
+    pub struct Small {
+        pub value: u8,
+    }
+
+    #[test]
+    #[should_panic(expected = "param `value` overflows u8")]
+    fn test_overflow() {
+        let _ = Small::from(event_log(vec![param(
+            "value",
+            Token::Uint(Web3U256::from(256)),
+        )]));
+    }
+
+    #[derive(Debug, Clone, PartialEq, ContractEvent)]
+    pub struct SmallInt {
+        pub value: i64,
+    }
+
+    #[test]
+    fn test_int_bounds() {
+        let value = |v: i128| SmallInt::from(event_log(vec![param("value", int(v))])).value;
+        assert_eq!(value(i64::MIN as i128), i64::MIN);
+        assert_eq!(value(i64::MAX as i128), i64::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "param `value` overflows i64")]
+    fn test_int_overflow() {
+        let _ = SmallInt::from(event_log(vec![param("value", int(i64::MIN as i128 - 1))]));
+    }
+
+    #[test]
+    #[should_panic(expected = "param `value` overflows i128")]
+    fn test_int_overflow_beyond_128_bits() {
+        #[derive(Debug, Clone, PartialEq, ContractEvent)]
+        pub struct LargeInt {
+            pub value: i128,
+        }
+        // 2^128 - 1 would read as -1 from its low 128 bits
+        let _ = LargeInt::from(event_log(vec![param(
+            "value",
+            Token::Int(Web3U256::from(u128::MAX)),
+        )]));
+    }
+
+    #[test]
+    #[should_panic(expected = "param `value` is not a uint")]
+    fn test_mismatch() {
+        let _ = Small::from(event_log(vec![param("value", Token::Bool(true))]));
+    }
+}
@@ -0,0 +1,44 @@
+//! Time of `KeyValuesStore::last_elems` by the size of the store.
+//! Run with `cargo bench -p chainsight-cdk --bench last_elems`.
+use std::time::{Duration, Instant};
+
+use chainsight_cdk::storage::{Data, KeyValuesStore, Persist, Token};
+
+const SIZES: [u64; 3] = [1_000, 10_000, 100_000];
+const N: u64 = 10;
+const ITERATIONS: u32 = 1_000;
+
+struct Transfer {
+    value: u64,
+}
+
+impl Persist for Transfer {
+    fn tokenize(&self) -> Data {
+        Data::new([("value".to_string(), Token::from(self.value))].into())
+    }
+    fn untokenize(data: Data) -> Self {
+        Self {
+            value: data.get("value").unwrap().to_u64().unwrap(),
+        }
+    }
+}
+
+fn measure(store: &KeyValuesStore) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let last = store.last_elems::<Transfer>(N).unwrap();
+        assert_eq!(last.len() as u64, N);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    for (i, size) in SIZES.iter().enumerate() {
+        let store = KeyValuesStore::new(i as u8 + 1);
+        for key in 0..*size {
+            store.set(key, vec![Transfer { value: key }]);
+        }
+        let time = measure(&store);
+        println!("last_elems({}) of {:>7} keys: {:?}", N, size, time);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    indexer::{Error, Indexer, IndexingConfig},
+    indexer::{Error, Indexer, IndexingConfig, InstructionBudget},
     rpc::{CallProvider, Caller, Message},
     storage,
 };
@@ -72,6 +72,39 @@ impl AlgorithmLogFinder {
         let rep = result.reply::<Reply>().unwrap();
         Ok(rep)
     }
+
+    /// Last key indexed by the source, if it exposes `get_last_indexed`.
+    async fn source_last(&self) -> Option<u64> {
+        let message = Message::new::<()>((), self.target, "proxy_get_last_indexed").ok()?;
+        let result = CallProvider::new().call(message).await.ok()?;
+        result.reply::<u64>().ok()
+    }
+
+    /// Moves the cursor past a chunk without entries, `from..to`, unless the source has nothing after it.
+    /// Returns the new last indexed key, or `None` when caught up with the source.
+    /// `source_last` caches the last key of the source, so it is asked once per tick.
+    async fn skip_empty_chunk(
+        &self,
+        last_indexed: u64,
+        to: u64,
+        source_last: &mut Option<Option<u64>>,
+    ) -> Option<u64> {
+        let last_in_chunk = to.checked_sub(1).filter(|last| *last > last_indexed)?;
+        if source_last.is_none() {
+            *source_last = Some(self.source_last().await);
+        }
+        match source_last.flatten() {
+            Some(source_last) if to <= source_last => {
+                storage::commit_checkpoint(
+                    storage::DEFAULT_CHECKPOINT,
+                    last_in_chunk.to_string(),
+                    || {},
+                );
+                Some(last_in_chunk)
+            }
+            _ => None,
+        }
+    }
 }
 impl<Logs> AlgorithmIndexer<Logs> {
     pub fn new(src: Principal, persist: fn(Logs)) -> Self {
@@ -117,24 +150,43 @@ where
     T: CandidType + Send + Sync + Clone + DeserializeOwned + 'static,
 {
     async fn index(&self, cfg: IndexingConfig) -> Result<(), Error> {
-        let last_indexed = cfg.start_from;
+        let mut last_indexed = cfg.start_from;
         let chunk_size = cfg.chunk_size.unwrap_or(500);
-        let from = cfg.start_from.max(last_indexed + 1);
-        let to = from + chunk_size;
-        ic_cdk::println!("from: {}, to: {}", from, to);
-        let result: HashMap<u64, Vec<T>> = self
-            .finder
-            .find::<(u64, u64), HashMap<u64, Vec<T>>>((from, to))
-            .await?;
-        ic_cdk::println!("{:?}", result.len());
-        (self.persister.persist)(result.clone());
-        // get last result and update last indexed
-        let last_indexed = result.keys().max();
-        if let Some(last_indexed) = last_indexed {
-            storage::set_last_key(last_indexed.to_string());
+        let mut budget = InstructionBudget::new(cfg.instruction_budget);
+        let mut source_last = None;
+        loop {
+            let from = cfg.start_from.max(last_indexed + 1);
+            let to = from + chunk_size;
+            ic_cdk::println!("from: {}, to: {}", from, to);
+            let result: HashMap<u64, Vec<T>> = self
+                .finder
+                .find::<(u64, u64), HashMap<u64, Vec<T>>>((from, to))
+                .await?;
+            ic_cdk::println!("{:?}", result.len());
+            // get last result and update last indexed along with the batch
+            match result.keys().max() {
+                Some(last) => {
+                    storage::commit_checkpoint(
+                        storage::DEFAULT_CHECKPOINT,
+                        last.to_string(),
+                        || (self.persister.persist)(result.clone()),
+                    );
+                    last_indexed = *last;
+                }
+                None => match self
+                    .finder
+                    .skip_empty_chunk(last_indexed, to, &mut source_last)
+                    .await
+                {
+                    Some(last) => last_indexed = last,
+                    // caught up with the source
+                    None => return Ok(()),
+                },
+            }
+            if !budget.has_room() {
+                return Ok(());
+            }
         }
-
-        Ok(())
     }
 }
 
@@ -145,24 +197,49 @@ where
     T: CandidType + Send + Sync + Clone + DeserializeOwned + 'static,
 {
     async fn index(&self, cfg: IndexingConfig) -> Result<(), Error> {
-        let last_indexed = cfg.start_from;
+        let mut last_indexed = cfg.start_from;
         let chunk_size = cfg.chunk_size.unwrap_or(500);
-        let from = cfg.start_from.max(last_indexed + 1);
-        let to = from + chunk_size;
-        ic_cdk::println!("from: {}, to: {}", from, to);
+        let mut budget = InstructionBudget::new(cfg.instruction_budget);
+        let mut source_last = None;
+        loop {
+            let from = cfg.start_from.max(last_indexed + 1);
+            let to = from + chunk_size;
+            ic_cdk::println!("from: {}, to: {}", from, to);
 
-        let result: HashMap<String, Vec<T>> = self
-            .finder
-            .find::<(String, String), HashMap<String, Vec<T>>>((from.to_string(), to.to_string()))
-            .await?;
-        ic_cdk::println!("{:?}", result.len());
-        (self.persister.persist)(result.clone());
-        // get last result and update last indexed
-        let last_indexed = result.keys().max();
-        if let Some(last_indexed) = last_indexed {
-            storage::set_last_key(last_indexed.to_string());
+            let result: HashMap<String, Vec<T>> = self
+                .finder
+                .find::<(String, String), HashMap<String, Vec<T>>>((
+                    from.to_string(),
+                    to.to_string(),
+                ))
+                .await?;
+            ic_cdk::println!("{:?}", result.len());
+            // get last result and update last indexed along with the batch
+            match result.keys().max() {
+                Some(last) => {
+                    storage::commit_checkpoint(storage::DEFAULT_CHECKPOINT, last.clone(), || {
+                        (self.persister.persist)(result.clone())
+                    });
+                    match last.parse::<u64>() {
+                        Ok(last) => last_indexed = last,
+                        // not a block number, so the next chunk can't be derived
+                        Err(_) => return Ok(()),
+                    }
+                }
+                None => match self
+                    .finder
+                    .skip_empty_chunk(last_indexed, to, &mut source_last)
+                    .await
+                {
+                    Some(last) => last_indexed = last,
+                    // caught up with the source
+                    None => return Ok(()),
+                },
+            }
+            if !budget.has_room() {
+                return Ok(());
+            }
         }
-        Ok(())
     }
 }
 
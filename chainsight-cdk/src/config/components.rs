@@ -3,7 +3,11 @@ use std::collections::{BTreeMap, HashMap};
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::indexer::IndexingConfig;
+use crate::{
+    indexer::IndexingConfig,
+    storage::RetentionPolicy,
+    web3::{EIP1559FeeConfig, GasBudget, RelayThreshold},
+};
 
 lazy_static! {
     static ref MAPPING_CANDID_TY: HashMap<&'static str, &'static str> = [
@@ -53,6 +57,15 @@ pub struct AlgorithmIndexerOutput {
 pub struct AlgorithmIndexerOutputIdentifier {
     pub name: String,
     pub type_: AlgorithmOutputType,
+    /// Type of the keys of the store, e.g. `(candid::Principal, u64)`. `u64` if omitted.
+    pub key_type: Option<String>,
+    /// Fields with `#[index]`, to be looked up by query endpoints.
+    pub indexes: Option<Vec<AlgorithmIndexerOutputIndex>>,
+}
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct AlgorithmIndexerOutputIndex {
+    pub field: String,
+    pub type_: String,
 }
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum AlgorithmOutputType {
@@ -109,6 +122,11 @@ pub struct AlgorithmLensConfig {
 pub struct EventIndexerConfig {
     pub common: CommonConfig,
     pub def: EventIndexerEventDefinition,
+    /// Optional: Limits of the entries kept by the canister, kept without limit if not set.
+    /// Events have no timestamp, so `max_age_secs` is not supported
+    pub retention: Option<RetentionConfig>,
+    /// Optional: Fields of the event to look up events by, e.g. `to` of Transfer, with `events_by_<field>` queries
+    pub indexes: Option<Vec<String>>,
 }
 #[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct EventIndexerEventDefinition {
@@ -122,6 +140,22 @@ pub struct SnapshotIndexerEVMConfig {
     pub method_identifier: String,
     pub method_args: Vec<serde_json::Value>,
     pub abi_file_path: String,
+    /// Optional: Additional calls aggregated with the call above into a single eth_call through Multicall3
+    pub calls: Option<Vec<SnapshotIndexerEVMCall>>,
+    /// Optional: Address of Multicall3, defaults to the address deployed on most chains
+    pub multicall_address: Option<String>,
+    /// Optional: Limits of the entries kept by the canister, kept without limit if not set
+    pub retention: Option<RetentionConfig>,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotIndexerEVMCall {
+    /// Optional: Address of the contract to call, defaults to the target address of the canister
+    pub address: Option<String>,
+    pub method_identifier: String,
+    pub method_args: Vec<serde_json::Value>,
+    /// Optional: ABI of the contract to call, defaults to the ABI of the config
+    pub abi_file_path: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
@@ -130,6 +164,8 @@ pub struct SnapshotIndexerHTTPSConfig {
     pub url: String,
     pub headers: BTreeMap<String, String>,
     pub queries: SnapshotIndexerHTTPSConfigQueries,
+    /// Optional: Limits of the entries kept by the canister, kept without limit if not set
+    pub retention: Option<RetentionConfig>,
 }
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum SnapshotIndexerHTTPSConfigQueries {
@@ -148,6 +184,18 @@ pub struct SnapshotIndexerICPConfig {
     pub method_identifier: String,
     pub is_target_component: bool,
     pub lens_parameter: Option<LensParameter>,
+    /// Optional: Limits of the entries kept by the canister, kept without limit if not set
+    pub retention: Option<RetentionConfig>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RetentionConfig {
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+    /// Optional: Interval in seconds to prune by a timer, pruned on every insert if not set
+    pub prune_interval_secs: Option<u32>,
+    /// Optional: Maximum entries removed per prune, the rest removed by the next ones; 1000 if not set
+    pub max_prune_per_call: Option<u64>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
@@ -165,6 +213,28 @@ pub struct RelayerConfig {
     pub conversion_parameter: Option<RelayerConversionParameter>,
     /// Optional: Parameters for using Lens as data source
     pub lens_parameter: Option<LensParameter>,
+    /// Optional: Parameters for fees of EIP-1559 transactions
+    pub fee_parameter: Option<EIP1559FeeConfig>,
+    /// Optional: Deviation thresholds and heartbeat to skip relaying unchanged values
+    pub threshold_parameter: Option<RelayThreshold>,
+    /// Optional: Additional destinations to relay the same value to
+    pub destinations: Option<Vec<RelayerDestination>>,
+    /// Optional: Caps on gas prices and a daily budget for fees
+    pub gas_budget_parameter: Option<GasBudget>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RelayerDestination {
+    /// Chain id of the destination
+    pub chain_id: u64,
+    /// RPC endpoint for the destination chain
+    pub rpc_url: String,
+    /// Address for destination
+    pub address: String,
+    /// Function name to call for destination, defined in the same ABI
+    pub method_name: String,
+    /// Optional: Caps on gas prices and a daily budget for fees on this destination
+    pub gas_budget: Option<GasBudget>,
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
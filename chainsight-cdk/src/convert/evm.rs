@@ -10,32 +10,41 @@ pub struct ContractMethodIdentifier {
 }
 impl ContractMethodIdentifier {
     pub fn parse_from_str(s: &str) -> anyhow::Result<Self> {
-        let re =
-            Regex::new(r"(?P<identifier>\w+)\((?P<params>[^)]*)\)(?::\((?P<return>[^)]*)\))?")?;
-        let captures = re.captures(s).unwrap();
+        let re = Regex::new(r"^(?P<identifier>\w+)\((?P<rest>.*)$")?;
+        let captures = re
+            .captures(s.trim())
+            .ok_or_else(|| anyhow::anyhow!("Invalid method identifier: {}", s))?;
 
         let identifier = captures.name("identifier").unwrap().as_str().to_string();
 
-        let params_str = captures.name("params").unwrap().as_str();
-        let params_result: anyhow::Result<Vec<String>> = if params_str.is_empty() {
-            Ok(vec![])
-        } else {
-            params_str
-                .split(',')
-                .map(|s| convert_type_from_abi_type(s.trim()))
-                .collect()
+        // params and return values may contain tuples, so find the closing parenthesis by depth
+        let rest = captures.name("rest").unwrap().as_str();
+        let (params_str, rest) =
+            split_enclosed(rest).ok_or_else(|| anyhow::anyhow!("Unbalanced parentheses: {}", s))?;
+        let params = split_types(params_str)
+            .into_iter()
+            .map(convert_type_from_abi_type)
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        let rest = rest.trim();
+        let return_value = match rest.strip_prefix(':') {
+            Some(ret) => {
+                let (return_str, remaining) = ret
+                    .trim()
+                    .strip_prefix('(')
+                    .and_then(split_enclosed)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid return values: {}", s))?;
+                if !remaining.trim().is_empty() {
+                    return Err(anyhow::anyhow!("Invalid return values: {}", s));
+                }
+                split_types(return_str)
+                    .into_iter()
+                    .map(convert_type_from_abi_type)
+                    .collect::<anyhow::Result<Vec<String>>>()?
+            }
+            None if rest.is_empty() => vec![],
+            None => return Err(anyhow::anyhow!("Invalid method identifier: {}", s)),
         };
-        let params = params_result?;
-
-        let return_value_capture = captures.name("return");
-        let return_value = if let Some(val) = return_value_capture {
-            val.as_str()
-                .split(',')
-                .map(|s| convert_type_from_abi_type(s.trim()))
-                .collect::<anyhow::Result<Vec<String>>>()
-        } else {
-            Ok(vec![])
-        }?;
 
         Ok(ContractMethodIdentifier {
             identifier,
@@ -45,6 +54,43 @@ impl ContractMethodIdentifier {
     }
 }
 
+/// Splits `s` following an opening parenthesis into the enclosed part and the rest after the closing one.
+fn split_enclosed(s: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some((&s[..i], &s[i + 1..])),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits comma separated types at the top level, leaving tuples intact.
+fn split_types(s: &str) -> Vec<&str> {
+    if s.trim().is_empty() {
+        return vec![];
+    }
+    let mut types = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                types.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    types.push(s[start..].trim());
+    types
+}
+
 fn convert_type_from_abi_type(s: &str) -> anyhow::Result<String> {
     let param = Reader::read(s).map_err(|e| anyhow::anyhow!(e))?;
     convert_type_from_ethabi_param_type(&param).map_err(|e| anyhow::anyhow!(e))
@@ -52,37 +98,170 @@ fn convert_type_from_abi_type(s: &str) -> anyhow::Result<String> {
 
 /// To handle 256bits Unsigned Integer type in ic_web3_rs
 pub const U256_TYPE: &str = "ic_web3_rs::types::U256";
+/// To handle 256bits Signed Integer type, which ic_web3_rs only decodes into a BigInt
+pub const I256_TYPE: &str = "chainsight_cdk::core::I256";
 /// To handle Address type in ic_web3_rs
 pub const ADDRESS_TYPE: &str = "ic_web3_rs::types::Address";
 
+/// Rust type for a Solidity type, as decoded from ethabi tokens.
+/// Maps types in the same way as `convert_candid_type_from_ethabi_param_type` except for 256-bit integers and addresses.
 pub fn convert_type_from_ethabi_param_type(param: &ethabi::ParamType) -> Result<String, String> {
-    let err_msg = "ic_solidity_bindgen::internal::Unimplemented".to_string(); // temp
-                                                                              // ref: https://github.com/horizonx-tech/ic-solidity-bindgen/blob/6c9ffb4354cee4c32b1df17a2210c90f16972c21/ic-solidity-bindgen-macros/src/abi_gen.rs#L124
+    map_param_type(param, WideTypes::Native)
+}
+
+/// Candid type for a Solidity type, as returned by snapshot indexers,
+/// with 256-bit integers and addresses as strings.
+pub fn convert_candid_type_from_ethabi_param_type(
+    param: &ethabi::ParamType,
+) -> Result<String, String> {
+    map_param_type(param, WideTypes::Text)
+}
+
+/// Representation of the Solidity types without a Rust primitive: 256-bit integers and addresses.
+#[derive(Clone, Copy)]
+enum WideTypes {
+    /// `U256_TYPE`, `I256_TYPE` and `ADDRESS_TYPE`
+    Native,
+    /// `String`
+    Text,
+}
+
+/// Arrays of tuples are not supported, as ic_solidity_bindgen can't decode them.
+fn map_param_type(param: &ParamType, wide: WideTypes) -> Result<String, String> {
+    let err_msg = format!("Unsupported type: {}", param);
+    let wide_type = |native: &str| match wide {
+        WideTypes::Native => native.to_string(),
+        WideTypes::Text => "String".to_string(),
+    };
+    // ref: https://github.com/horizonx-tech/ic-solidity-bindgen/blob/6c9ffb4354cee4c32b1df17a2210c90f16972c21/ic-solidity-bindgen-macros/src/abi_gen.rs#L124
     match param {
-        ParamType::Address => Ok(ADDRESS_TYPE.to_string()),
+        ParamType::Address => Ok(wide_type(ADDRESS_TYPE)),
         ParamType::Bytes => Ok("Vec<u8>".to_string()),
         ParamType::Int(size) => match size {
-            129..=256 => Err(err_msg.to_string()),
+            129..=256 => Ok(wide_type(I256_TYPE)),
             65..=128 => Ok("i128".to_string()),
             33..=64 => Ok("i64".to_string()),
             17..=32 => Ok("i32".to_string()),
             9..=16 => Ok("i16".to_string()),
             1..=8 => Ok("i8".to_string()),
-            _ => Err(err_msg.to_string()),
+            _ => Err(err_msg),
         },
         ParamType::Uint(size) => match size {
-            129..=256 => Ok(U256_TYPE.to_string()),
+            129..=256 => Ok(wide_type(U256_TYPE)),
             65..=128 => Ok("u128".to_string()),
             33..=64 => Ok("u64".to_string()),
             17..=32 => Ok("u32".to_string()),
-            1..=16 => Ok("u16".to_string()),
+            9..=16 => Ok("u16".to_string()),
+            1..=8 => Ok("u8".to_string()),
             _ => Err(err_msg),
         },
         ParamType::Bool => Ok("bool".to_string()),
         ParamType::String => Ok("String".to_string()),
-        ParamType::Array(_) => Err(err_msg),         // temp
-        ParamType::FixedBytes(_) => Err(err_msg),    // temp
-        ParamType::FixedArray(_, _) => Err(err_msg), // temp
-        ParamType::Tuple(_) => Err(err_msg),         // temp
+        ParamType::Array(inner) if has_tuple(inner) => Err(err_msg),
+        ParamType::Array(inner) | ParamType::FixedArray(inner, _) => {
+            Ok(format!("Vec<{}>", map_param_type(inner, wide)?))
+        }
+        ParamType::FixedBytes(len) => Ok(format!("[u8; {}]", len)),
+        ParamType::Tuple(members) if members.is_empty() => Err(err_msg),
+        ParamType::Tuple(members) => {
+            let members = members
+                .iter()
+                .map(|m| map_param_type(m, wide).map(|ty| format!("{},", ty)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("({})", members.join(" ")))
+        }
+    }
+}
+
+fn has_tuple(param: &ParamType) -> bool {
+    match param {
+        ParamType::Tuple(_) => true,
+        ParamType::Array(inner) | ParamType::FixedArray(inner, _) => has_tuple(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_type_from_abi_type() {
+        let cases = [
+            ("uint8", "u8"),
+            ("uint16", "u16"),
+            ("uint256", U256_TYPE),
+            ("int256", I256_TYPE),
+            ("address", ADDRESS_TYPE),
+            ("bytes", "Vec<u8>"),
+            ("bytes32", "[u8; 32]"),
+            ("uint256[]", "Vec<ic_web3_rs::types::U256>"),
+            ("address[2]", "Vec<ic_web3_rs::types::Address>"),
+            ("string[][]", "Vec<Vec<String>>"),
+            ("(uint64,bool)", "(u64, bool,)"),
+            (
+                "(string,(int8,bytes4))[2]",
+                "Vec<(String, (i8, [u8; 4],),)>",
+            ),
+        ];
+        for (abi_type, expected) in cases {
+            assert_eq!(convert_type_from_abi_type(abi_type).unwrap(), expected);
+        }
+        // ic_solidity_bindgen can't decode arrays of tuples
+        assert!(convert_type_from_abi_type("(string,int8)[]").is_err());
+        assert!(convert_type_from_abi_type("(string,int8)[2][]").is_err());
+    }
+
+    #[test]
+    fn test_convert_candid_type() {
+        let cases = [
+            ("uint128", "u128"),
+            ("uint256", "String"),
+            ("int256", "String"),
+            ("address[]", "Vec<String>"),
+            ("(address,(int256,bytes4))", "(String, (String, [u8; 4],),)"),
+        ];
+        for (abi_type, expected) in cases {
+            let param = Reader::read(abi_type).unwrap();
+            assert_eq!(
+                convert_candid_type_from_ethabi_param_type(&param).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_method_identifier() {
+        assert_eq!(
+            ContractMethodIdentifier::parse_from_str("totalSupply():(uint256)").unwrap(),
+            ContractMethodIdentifier {
+                identifier: "totalSupply".to_string(),
+                params: vec![],
+                return_value: vec![U256_TYPE.to_string()],
+            }
+        );
+        assert_eq!(
+            ContractMethodIdentifier::parse_from_str("update(uint256[], bytes32)").unwrap(),
+            ContractMethodIdentifier {
+                identifier: "update".to_string(),
+                params: vec![
+                    "Vec<ic_web3_rs::types::U256>".to_string(),
+                    "[u8; 32]".to_string()
+                ],
+                return_value: vec![],
+            }
+        );
+        assert_eq!(
+            ContractMethodIdentifier::parse_from_str(
+                "getRound((uint80,address),int256):((int256,uint64),bool)"
+            )
+            .unwrap(),
+            ContractMethodIdentifier {
+                identifier: "getRound".to_string(),
+                params: vec![format!("(u128, {},)", ADDRESS_TYPE), I256_TYPE.to_string()],
+                return_value: vec![format!("({}, u64,)", I256_TYPE), "bool".to_string()],
+            }
+        );
+        assert!(ContractMethodIdentifier::parse_from_str("broken(uint256").is_err());
     }
 }
@@ -143,6 +143,52 @@ scale_num!(i8, i128);
 scale_num!(f64, f64);
 scale_num!(f32, f64);
 
+/// Numeric view of a value, used to measure how far it moved since it was last relayed.
+pub trait Measurable {
+    fn measure(&self) -> Option<f64>;
+}
+
+macro_rules! measurable_num {
+    ($num_ty: ident) => {
+        impl Measurable for $num_ty {
+            fn measure(&self) -> Option<f64> {
+                Some(*self as f64)
+            }
+        }
+    };
+}
+
+measurable_num!(u128);
+measurable_num!(u64);
+measurable_num!(u32);
+measurable_num!(u16);
+measurable_num!(u8);
+measurable_num!(i128);
+measurable_num!(i64);
+measurable_num!(i32);
+measurable_num!(i16);
+measurable_num!(i8);
+measurable_num!(f64);
+measurable_num!(f32);
+
+impl Measurable for String {
+    fn measure(&self) -> Option<f64> {
+        self.parse::<f64>().ok()
+    }
+}
+
+impl Measurable for &str {
+    fn measure(&self) -> Option<f64> {
+        self.parse::<f64>().ok()
+    }
+}
+
+impl Measurable for U256 {
+    fn measure(&self) -> Option<f64> {
+        self.to_string().parse::<f64>().ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -334,4 +380,14 @@ mod tests {
         assert_scale(43.21f32, 3, 43210.0);
         assert_scale(43.21f32, 1, 432.09998); // 432.1
     }
+
+    #[test]
+    fn test_measurable() {
+        assert_eq!(123u64.measure(), Some(123.0));
+        assert_eq!((-5i32).measure(), Some(-5.0));
+        assert_eq!(1.5f64.measure(), Some(1.5));
+        assert_eq!("12.5".to_string().measure(), Some(12.5));
+        assert_eq!("abc".measure(), None);
+        assert_eq!(U256::from(10u128.pow(20)).measure(), Some(1e20));
+    }
 }
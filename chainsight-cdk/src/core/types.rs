@@ -31,6 +31,78 @@ impl From<primitive_types::U256> for U256 {
     }
 }
 
+/// Signed 256-bit integer, held as a decimal string like `U256`.
+#[derive(
+    CandidType, Debug, Clone, PartialEq, PartialOrd, serde::Deserialize, serde::Serialize, Default,
+)]
+pub struct I256 {
+    value: String,
+}
+
+impl I256 {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Two's complement representation, as used by `ethabi::Token::Int`.
+    pub fn twos_complement(&self) -> primitive_types::U256 {
+        match self.value.strip_prefix('-') {
+            Some(abs) => {
+                !primitive_types::U256::from_dec_str(abs)
+                    .unwrap()
+                    .overflowing_sub(1.into())
+                    .0
+            }
+            None => primitive_types::U256::from_dec_str(&self.value).unwrap(),
+        }
+    }
+
+    pub fn from_twos_complement(value: primitive_types::U256) -> Self {
+        let value = if value.bit(255) {
+            format!("-{}", !value + 1)
+        } else {
+            value.to_string()
+        };
+        Self { value }
+    }
+
+    /// From `ic_web3_rs::ethabi::Token::Int` decoded from logs and outputs.
+    pub fn from_ethabi_int(value: ic_web3_rs::types::U256) -> Self {
+        let mut bytes = [0u8; 32];
+        value.to_big_endian(&mut bytes);
+        Self::from_twos_complement(primitive_types::U256::from_big_endian(&bytes))
+    }
+}
+
+impl From<ic_web3_rs::types::I256> for I256 {
+    fn from(i256: ic_web3_rs::types::I256) -> Self {
+        Self {
+            value: i256.to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for I256 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let abs = s.strip_prefix('-').unwrap_or(s);
+        primitive_types::U256::from_dec_str(abs)
+            .map_err(|e| format!("invalid I256 {}: {:?}", s, e))?;
+        Ok(Self {
+            value: s.to_string(),
+        })
+    }
+}
+
+impl From<i128> for I256 {
+    fn from(i: i128) -> Self {
+        Self {
+            value: i.to_string(),
+        }
+    }
+}
+
 #[derive(Clone, CandidType, Debug, PartialEq)]
 pub struct Sources<T>
 where
@@ -6,7 +6,7 @@ use derive_more::Display;
 use ic_stable_structures::storable::Bound;
 use serde::Deserialize;
 
-use crate::storage::{Data, Persist};
+use crate::storage::{Data, Persist, StorageError};
 
 #[derive(Debug, Display)]
 pub enum Error {
@@ -19,11 +19,23 @@ pub enum Error {
     #[display(fmt = "Other error: {}", _0)]
     OtherError(String),
 }
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Self {
+        Error::DatabaseError(e.to_string())
+    }
+}
 
 #[derive(CandidType, Clone, Debug, Default, PartialEq, Deserialize, serde::Serialize)]
 pub struct IndexingConfig {
     pub start_from: u64,
     pub chunk_size: Option<u64>,
+    /// Number of recent blocks whose hashes are tracked to detect chain reorganizations.
+    /// Blocks are only indexed once this deep below the head.
+    /// Reorg detection is disabled when `None`.
+    pub confirmations: Option<u64>,
+    /// Enables catch-up mode: chunks are indexed repeatedly in one call until the indexer
+    /// is caught up or this many instructions have been spent.
+    pub instruction_budget: Option<u64>,
 }
 impl ic_stable_structures::Storable for IndexingConfig {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
@@ -34,10 +46,42 @@ impl ic_stable_structures::Storable for IndexingConfig {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 100,        // temp
-        is_fixed_size: false, // temp
-    };
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Tracks the instructions spent by a catch-up loop against `IndexingConfig.instruction_budget`.
+pub struct InstructionBudget {
+    budget: Option<u64>,
+    last_mark: u64,
+}
+impl InstructionBudget {
+    pub fn new(budget: Option<u64>) -> Self {
+        Self {
+            budget,
+            last_mark: Self::instruction_counter(),
+        }
+    }
+    /// Returns true if another chunk, as costly as the one just indexed, still fits into the budget.
+    pub fn has_room(&mut self) -> bool {
+        self.has_room_at(Self::instruction_counter())
+    }
+    fn has_room_at(&mut self, now: u64) -> bool {
+        let chunk_cost = now - self.last_mark;
+        self.last_mark = now;
+        match self.budget {
+            Some(budget) => now + chunk_cost <= budget,
+            None => false,
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    fn instruction_counter() -> u64 {
+        0
+    }
+    /// instructions spent by the current call context, across awaits
+    #[cfg(target_arch = "wasm32")]
+    fn instruction_counter() -> u64 {
+        ic_cdk::api::performance_counter(1)
+    }
 }
 
 pub trait Event<T>: CandidType + Send + Sync + Clone + From<T> + Persist + 'static {
@@ -49,3 +93,22 @@ pub trait Event<T>: CandidType + Send + Sync + Clone + From<T> + Persist + 'stat
 pub trait Indexer<Log, Events, Args> {
     async fn index(&self, cfg: IndexingConfig) -> Result<(), Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_budget() {
+        assert!(!InstructionBudget::new(None).has_room_at(10));
+
+        let mut budget = InstructionBudget {
+            budget: Some(100),
+            last_mark: 0,
+        };
+        // a chunk of 30 instructions: another one ends at 60
+        assert!(budget.has_room_at(30));
+        // a chunk of 40 instructions: another one would end at 110
+        assert!(!budget.has_room_at(70));
+    }
+}
@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+
+use ic_stable_structures::StableBTreeMap;
+
+use super::storage::{block_hash_memory, Memory};
+
+thread_local! {
+    static BLOCK_HASHES: RefCell<Option<StableBTreeMap<u64, String, Memory>>> = const { RefCell::new(None) };
+}
+
+fn with_block_hashes<R>(f: impl FnOnce(&mut StableBTreeMap<u64, String, Memory>) -> R) -> R {
+    BLOCK_HASHES.with(|s| {
+        let mut s = s.borrow_mut();
+        f(s.get_or_insert_with(|| StableBTreeMap::init(block_hash_memory())))
+    })
+}
+
+/// Hash of an indexed block, kept while the block is within the confirmation depth.
+pub fn get_block_hash(number: u64) -> Option<String> {
+    with_block_hashes(|s| s.get(&number))
+}
+
+pub fn set_block_hash(number: u64, hash: String) {
+    with_block_hashes(|s| s.insert(number, hash));
+}
+
+/// Latest block with a tracked hash.
+pub fn last_block_hash() -> Option<(u64, String)> {
+    with_block_hashes(|s| s.last_key_value())
+}
+
+/// Tracked hashes of the blocks in `from..=to`, in ascending order.
+pub fn block_hashes_between(from: u64, to: u64) -> Vec<(u64, String)> {
+    if from > to {
+        return vec![];
+    }
+    with_block_hashes(|s| s.range(from..=to).collect())
+}
+
+/// Removes the tracked hashes of the blocks in `from..=to`.
+pub fn remove_block_hashes(from: u64, to: u64) {
+    block_hashes_between(from, to)
+        .into_iter()
+        .for_each(|(number, _)| {
+            with_block_hashes(|s| s.remove(&number));
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_hashes() {
+        assert_eq!(last_block_hash(), None);
+        (1..=5).for_each(|n| set_block_hash(n, format!("0x{:02x}", n)));
+        assert_eq!(get_block_hash(3), Some("0x03".to_string()));
+        assert_eq!(last_block_hash(), Some((5, "0x05".to_string())));
+        assert_eq!(
+            block_hashes_between(2, 3),
+            vec![(2, "0x02".to_string()), (3, "0x03".to_string())]
+        );
+        assert!(block_hashes_between(3, 2).is_empty());
+
+        remove_block_hashes(4, u64::MAX);
+        assert_eq!(last_block_hash(), Some((3, "0x03".to_string())));
+        remove_block_hashes(0, 1);
+        assert_eq!(get_block_hash(1), None);
+        assert_eq!(block_hashes_between(0, u64::MAX).len(), 2);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering};
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    storage::{checkpoint_memory, Memory},
+    StorageError,
+};
+use crate::time::TimeStamper;
+
+/// Name of the cursor of `set_last_key` and `get_last_key`.
+pub const DEFAULT_CHECKPOINT: &str = "last_key";
+
+/// Position of a named cursor of an indexer, kept in stable memory across upgrades.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Key of the last indexed entry, e.g. a block number
+    pub key: String,
+    /// Time of the last update in seconds
+    pub updated_at: u64,
+}
+
+impl Storable for Checkpoint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CHECKPOINTS: RefCell<Option<StableBTreeMap<String, Checkpoint, Memory>>> = const { RefCell::new(None) };
+}
+
+fn with_checkpoints<R>(f: impl FnOnce(&mut StableBTreeMap<String, Checkpoint, Memory>) -> R) -> R {
+    CHECKPOINTS.with(|c| {
+        let mut c = c.borrow_mut();
+        f(c.get_or_insert_with(|| StableBTreeMap::init(checkpoint_memory())))
+    })
+}
+
+/// Keys are compared as numbers if both are, e.g. block numbers, and as strings otherwise.
+fn compare_keys(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+pub fn get_checkpoint(name: &str) -> Option<Checkpoint> {
+    with_checkpoints(|c| c.get(&name.to_string()))
+}
+
+pub fn set_checkpoint(name: &str, key: String) {
+    let checkpoint = Checkpoint {
+        key,
+        updated_at: TimeStamper::now_sec(),
+    };
+    with_checkpoints(|c| c.insert(name.to_string(), checkpoint));
+}
+
+pub fn remove_checkpoint(name: &str) {
+    with_checkpoints(|c| c.remove(&name.to_string()));
+}
+
+/// All checkpoints by their names.
+pub fn checkpoints() -> Vec<(String, Checkpoint)> {
+    with_checkpoints(|c| c.iter().collect())
+}
+
+/// Persists a batch and moves the cursor `name` to `key`, the last key of the batch.
+/// Both are committed together, as long as `persist` does not await:
+/// a trap in `persist` rolls back the whole message, leaving the cursor where it was.
+pub fn commit_checkpoint<R>(name: &str, key: String, persist: impl FnOnce() -> R) -> R {
+    let result = persist();
+    set_checkpoint(name, key);
+    result
+}
+
+/// Moves the cursor `name` back to `key`, so that the entries after it are indexed again.
+pub fn rewind_checkpoint(name: &str, key: String) -> Result<Checkpoint, StorageError> {
+    move_checkpoint(name, key, false)
+}
+
+/// Moves the cursor `name` forward to `key`, skipping the entries up to it.
+/// A cursor not set yet is set to `key`.
+pub fn fast_forward_checkpoint(name: &str, key: String) -> Result<Checkpoint, StorageError> {
+    if get_checkpoint(name).is_none() {
+        set_checkpoint(name, key);
+        return Ok(get_checkpoint(name).unwrap());
+    }
+    move_checkpoint(name, key, true)
+}
+
+fn move_checkpoint(name: &str, key: String, forward: bool) -> Result<Checkpoint, StorageError> {
+    let current = get_checkpoint(name).ok_or(StorageError::UnknownCheckpoint(name.to_string()))?;
+    let backward = if forward {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    if compare_keys(&current.key, &key) == backward {
+        return Err(StorageError::InvalidCheckpointMove {
+            name: name.to_string(),
+            from: current.key,
+            to: key,
+        });
+    }
+    set_checkpoint(name, key);
+    Ok(get_checkpoint(name).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoints() {
+        assert_eq!(get_checkpoint("blocks"), None);
+        commit_checkpoint("blocks", "100".to_string(), || {});
+        set_checkpoint("tokens", "b".to_string());
+        assert_eq!(get_checkpoint("blocks").unwrap().key, "100");
+        assert_eq!(
+            checkpoints()
+                .into_iter()
+                .map(|(name, c)| (name, c.key))
+                .collect::<Vec<_>>(),
+            vec![
+                ("blocks".to_string(), "100".to_string()),
+                ("tokens".to_string(), "b".to_string())
+            ]
+        );
+        remove_checkpoint("tokens");
+        assert_eq!(checkpoints().len(), 1);
+    }
+
+    #[test]
+    fn test_move_checkpoint() {
+        assert_eq!(
+            rewind_checkpoint("blocks", "9".to_string()),
+            Err(StorageError::UnknownCheckpoint("blocks".to_string()))
+        );
+        fast_forward_checkpoint("blocks", "100".to_string()).unwrap();
+        // compared as numbers, not as strings
+        assert_eq!(
+            rewind_checkpoint("blocks", "9".to_string()).unwrap().key,
+            "9"
+        );
+        assert!(rewind_checkpoint("blocks", "10".to_string()).is_err());
+        assert!(fast_forward_checkpoint("blocks", "8".to_string()).is_err());
+        assert_eq!(
+            fast_forward_checkpoint("blocks", "200".to_string())
+                .unwrap()
+                .key,
+            "200"
+        );
+        assert_eq!(get_checkpoint("blocks").unwrap().key, "200");
+    }
+
+    #[test]
+    fn test_last_key() {
+        assert_eq!(crate::storage::get_last_key(), "");
+        crate::storage::set_last_key("42".to_string());
+        assert_eq!(get_checkpoint(DEFAULT_CHECKPOINT).unwrap().key, "42");
+    }
+}
@@ -0,0 +1,52 @@
+use derive_more::Display;
+
+/// Errors of reading values from tokens held in the storage.
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum StorageError {
+    #[display(fmt = "Unexpected token: expected {}, found {}", expected, found)]
+    UnexpectedToken {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[display(fmt = "Out of range: {} does not fit in {}", value, target)]
+    OutOfRange { value: String, target: &'static str },
+    #[display(fmt = "Missing field: {}", _0)]
+    MissingField(String),
+    #[display(fmt = "Invalid field {}: {}", field, error)]
+    InvalidField {
+        field: String,
+        error: Box<StorageError>,
+    },
+    #[display(fmt = "Unknown checkpoint: {}", _0)]
+    UnknownCheckpoint(String),
+    #[display(fmt = "Checkpoint {} can't be moved from {} to {}", name, from, to)]
+    InvalidCheckpointMove {
+        name: String,
+        from: String,
+        to: String,
+    },
+    #[display(
+        fmt = "Checksum mismatch of the chunk of memory {} at {}",
+        memory_id,
+        offset
+    )]
+    ChecksumMismatch { memory_id: u8, offset: u64 },
+    #[display(fmt = "Invalid chunk: {}", _0)]
+    InvalidChunk(String),
+    #[display(fmt = "Import refused: {}", _0)]
+    ImportRefused(String),
+    #[display(fmt = "Export refused: {}", _0)]
+    ExportRefused(String),
+}
+
+impl StorageError {
+    /// Adds the name of the field being read to the error.
+    pub fn in_field(self, field: &str) -> Self {
+        StorageError::InvalidField {
+            field: field.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
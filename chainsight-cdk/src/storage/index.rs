@@ -0,0 +1,129 @@
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, marker::PhantomData};
+
+use candid::Encode;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+
+use super::{
+    storage::{index_memory, Memory},
+    StoreKey, Token,
+};
+
+/// Entry of an index: the encoded value of the field and the bytes of the key holding it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct IndexEntry {
+    value: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl Storable for IndexEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = (self.value.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.value);
+        bytes.extend_from_slice(&self.key);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        Self {
+            value: bytes[4..4 + len].to_vec(),
+            key: bytes[4 + len..].to_vec(),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+type IndexMap = StableBTreeMap<IndexEntry, (), Memory>;
+
+thread_local! {
+    static INDEXES: RefCell<BTreeMap<u8, IndexMap>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Secondary index from values of a field to the keys of a `KeyValueStore` or `KeyValuesStore`
+/// holding them, kept in its own stable BTreeMap at `memory_id`.
+pub struct StoreIndex<K = u64> {
+    memory_id: u8,
+    _key: PhantomData<K>,
+}
+
+impl<K: StoreKey> StoreIndex<K> {
+    pub fn new(memory_id: u8) -> Self {
+        index_memory(memory_id);
+        Self {
+            memory_id,
+            _key: PhantomData,
+        }
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut IndexMap) -> R) -> R {
+        INDEXES.with(|indexes| {
+            let mut indexes = indexes.borrow_mut();
+            let index = indexes
+                .entry(self.memory_id)
+                .or_insert_with(|| StableBTreeMap::init(index_memory(self.memory_id)));
+            f(index)
+        })
+    }
+    pub fn insert(&self, value: &Token, key: &K) {
+        let entry = IndexEntry {
+            value: encode(value),
+            key: key.to_bytes().into_owned(),
+        };
+        self.with(|m| {
+            m.insert(entry, ());
+        })
+    }
+    pub fn remove(&self, value: &Token, key: &K) {
+        let entry = IndexEntry {
+            value: encode(value),
+            key: key.to_bytes().into_owned(),
+        };
+        self.with(|m| {
+            m.remove(&entry);
+        })
+    }
+    /// Keys holding the value, in key order.
+    pub fn keys(&self, value: &Token) -> Vec<K> {
+        let value = encode(value);
+        let mut keys = self.with(|m| {
+            let from = IndexEntry {
+                value: value.clone(),
+                key: vec![],
+            };
+            m.range(from..)
+                .take_while(|(entry, _)| entry.value == value)
+                .map(|(entry, _)| K::from_bytes(Cow::Owned(entry.key)))
+                .collect::<Vec<_>>()
+        });
+        keys.sort();
+        keys
+    }
+}
+
+fn encode(value: &Token) -> Vec<u8> {
+    Encode!(value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let index = StoreIndex::<u64>::new(20);
+        index.insert(&Token::from("alice"), &3);
+        index.insert(&Token::from("alice"), &1);
+        index.insert(&Token::from("bob"), &2);
+        index.insert(&Token::from("alice"), &3);
+        assert_eq!(index.keys(&Token::from("alice")), vec![1, 3]);
+        index.remove(&Token::from("alice"), &3);
+        assert_eq!(index.keys(&Token::from("alice")), vec![1]);
+        assert_eq!(index.keys(&Token::from("carol")), Vec::<u64>::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "used by a store")]
+    fn test_memory_id_of_store() {
+        StoreIndex::<u64>::new(3);
+    }
+}
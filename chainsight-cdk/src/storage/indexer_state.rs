@@ -0,0 +1,57 @@
+use std::cell::RefCell;
+
+use ic_stable_structures::StableBTreeMap;
+
+use super::storage::{indexer_state_memory, Memory};
+
+thread_local! {
+    static INDEXER_STATE: RefCell<Option<StableBTreeMap<String, u64, Memory>>> = const { RefCell::new(None) };
+}
+
+fn with_indexer_state<R>(f: impl FnOnce(&mut StableBTreeMap<String, u64, Memory>) -> R) -> R {
+    INDEXER_STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        f(s.get_or_insert_with(|| StableBTreeMap::init(indexer_state_memory())))
+    })
+}
+
+/// Value an indexer keeps in stable memory besides its cursors, e.g. a learned chunk size.
+pub fn get_indexer_state(name: &str) -> Option<u64> {
+    with_indexer_state(|s| s.get(&name.to_string()))
+}
+
+/// Sets the value of `name`, writing to stable memory only if it changed.
+pub fn set_indexer_state(name: &str, value: u64) {
+    with_indexer_state(|s| {
+        if s.get(&name.to_string()) != Some(value) {
+            s.insert(name.to_string(), value);
+        }
+    })
+}
+
+pub fn remove_indexer_state(name: &str) {
+    with_indexer_state(|s| {
+        if s.contains_key(&name.to_string()) {
+            s.remove(&name.to_string());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::checkpoints;
+
+    #[test]
+    fn test_indexer_state() {
+        assert_eq!(get_indexer_state("chunk_size"), None);
+        set_indexer_state("chunk_size", 100);
+        set_indexer_state("chunk_size", 50);
+        assert_eq!(get_indexer_state("chunk_size"), Some(50));
+        // kept apart from the checkpoints
+        assert!(checkpoints().is_empty());
+        remove_indexer_state("chunk_size");
+        remove_indexer_state("chunk_size");
+        assert_eq!(get_indexer_state("chunk_size"), None);
+    }
+}
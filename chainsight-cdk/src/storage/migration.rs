@@ -0,0 +1,432 @@
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::Memory;
+use ic_web3_rs::signing::keccak256;
+use serde::{Deserialize, Serialize};
+
+use super::StorageError;
+
+/// Bytes of memory in an exported chunk, within the limit of a reply.
+pub const EXPORT_CHUNK_SIZE: u64 = 1024 * 1024;
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Position to resume exporting from: an offset in the memory of a memory id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct ExportCursor {
+    /// Id of the export, shared by all of its chunks
+    pub export_id: u64,
+    /// Index of the next chunk in the export
+    pub sequence: u64,
+    pub memory_id: u8,
+    pub offset: u64,
+}
+
+impl ExportCursor {
+    /// Cursor of the first chunk of a new export.
+    pub fn start(export_id: u64) -> Self {
+        Self {
+            export_id,
+            sequence: 0,
+            memory_id: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Bytes of a memory of a canister, exported to be imported in another one.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct ExportedChunk {
+    pub export_id: u64,
+    pub sequence: u64,
+    pub memory_id: u8,
+    pub offset: u64,
+    /// Size of the memory in pages, grown to on import
+    pub pages: u64,
+    /// Bytes of all the exported memories, checked after the last chunk is imported
+    pub total_length: u64,
+    pub data: Vec<u8>,
+    /// Keccak-256 of the fields above
+    pub checksum: Vec<u8>,
+    /// Cursor of the next chunk, `None` for the last one
+    pub next: Option<ExportCursor>,
+}
+
+impl ExportedChunk {
+    fn checksum(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.export_id.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.push(self.memory_id);
+        bytes.extend_from_slice(&self.offset.to_be_bytes());
+        bytes.extend_from_slice(&self.pages.to_be_bytes());
+        bytes.extend_from_slice(&self.total_length.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        keccak256(&bytes).to_vec()
+    }
+}
+
+/// Progress of an import, to accept only the chunks of one export, in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportProgress {
+    export_id: u64,
+    total_length: u64,
+    next_sequence: u64,
+    imported: u64,
+}
+
+/// First memory id from `from` allowed by `memory` and holding any data.
+fn next_memory_id<M: Memory>(memory: &impl Fn(u8) -> Option<M>, from: u16) -> Option<u8> {
+    (from..u8::MAX as u16)
+        .map(|id| id as u8)
+        .find(|id| memory(*id).is_some_and(|m| m.size() > 0))
+}
+
+fn total_length<M: Memory>(memory: &impl Fn(u8) -> Option<M>) -> u64 {
+    (0..u8::MAX)
+        .filter_map(memory)
+        .map(|m| m.size() * WASM_PAGE_SIZE)
+        .sum()
+}
+
+/// Exports the chunk at `cursor` of the memories given by `memory`,
+/// which returns the memory of an exported memory id from the memory manager holding it, and `None` for the others.
+/// Memories are exported as they are, so that every stable structure in them is moved along.
+pub fn export_chunk_from<M: Memory>(
+    memory: impl Fn(u8) -> Option<M>,
+    cursor: ExportCursor,
+) -> Option<ExportedChunk> {
+    let ExportCursor {
+        export_id,
+        sequence,
+        memory_id,
+        offset,
+    } = cursor;
+    let memory_id = if offset == 0 {
+        next_memory_id(&memory, memory_id as u16)?
+    } else {
+        memory_id
+    };
+    let exported = memory(memory_id)?;
+    let pages = exported.size();
+    let end = (offset + EXPORT_CHUNK_SIZE).min(pages * WASM_PAGE_SIZE);
+    let mut data = vec![0; end.saturating_sub(offset) as usize];
+    exported.read(offset, &mut data);
+    let next = if end < pages * WASM_PAGE_SIZE {
+        Some((memory_id, end))
+    } else {
+        next_memory_id(&memory, memory_id as u16 + 1).map(|memory_id| (memory_id, 0))
+    };
+    let mut chunk = ExportedChunk {
+        export_id,
+        sequence,
+        memory_id,
+        offset,
+        pages,
+        total_length: total_length(&memory),
+        data,
+        checksum: vec![],
+        next: next.map(|(memory_id, offset)| ExportCursor {
+            export_id,
+            sequence: sequence + 1,
+            memory_id,
+            offset,
+        }),
+    };
+    chunk.checksum = chunk.checksum();
+    Some(chunk)
+}
+
+/// Writes an exported chunk to the memory given by `memory`, after verifying its checksum
+/// and that it follows the chunks imported so far in `progress`, starting a new import at its first chunk.
+/// Returns the cursor to export the next chunk from, `None` after the last one.
+pub fn import_chunk_into<M: Memory>(
+    memory: impl Fn(u8) -> Option<M>,
+    progress: &mut Option<ImportProgress>,
+    chunk: ExportedChunk,
+) -> Result<Option<ExportCursor>, StorageError> {
+    if chunk.checksum() != chunk.checksum {
+        return Err(StorageError::ChecksumMismatch {
+            memory_id: chunk.memory_id,
+            offset: chunk.offset,
+        });
+    }
+    let imported = memory(chunk.memory_id).ok_or(StorageError::InvalidChunk(format!(
+        "memory {} is not imported",
+        chunk.memory_id
+    )))?;
+    if chunk.sequence == 0 {
+        *progress = Some(ImportProgress {
+            export_id: chunk.export_id,
+            total_length: chunk.total_length,
+            next_sequence: 0,
+            imported: 0,
+        });
+    }
+    let current = match progress {
+        Some(p)
+            if p.export_id == chunk.export_id
+                && p.total_length == chunk.total_length
+                && p.next_sequence == chunk.sequence =>
+        {
+            p
+        }
+        _ => {
+            return Err(StorageError::InvalidChunk(format!(
+                "chunk {} of export {} does not follow the imported chunks",
+                chunk.sequence, chunk.export_id
+            )))
+        }
+    };
+    if imported.size() < chunk.pages && imported.grow(chunk.pages - imported.size()) < 0 {
+        return Err(StorageError::InvalidChunk(format!(
+            "failed to grow memory {} to {} pages",
+            chunk.memory_id, chunk.pages
+        )));
+    }
+    imported.write(chunk.offset, &chunk.data);
+    current.next_sequence += 1;
+    current.imported += chunk.data.len() as u64;
+    if chunk.next.is_none() {
+        let (imported, total_length) = (current.imported, current.total_length);
+        *progress = None;
+        if imported != total_length {
+            return Err(StorageError::InvalidChunk(format!(
+                "imported {} bytes of {}",
+                imported, total_length
+            )));
+        }
+    }
+    Ok(chunk.next)
+}
+
+thread_local! {
+    static MAINTENANCE: Cell<bool> = const { Cell::new(false) };
+    static RUNNING_TASKS: Cell<u32> = const { Cell::new(0) };
+    static IMPORT: RefCell<Option<ImportProgress>> = const { RefCell::new(None) };
+}
+
+/// Puts the canister into maintenance, in which tasks are not started, or out of it.
+/// Maintenance is kept on the heap, so an upgrade ends it.
+pub fn set_maintenance(on: bool) {
+    MAINTENANCE.with(|m| m.set(on));
+}
+
+pub fn is_in_maintenance() -> bool {
+    MAINTENANCE.with(|m| m.get())
+}
+
+/// Marks a task writing to stable memory, e.g. indexing, as running until dropped.
+pub struct TaskGuard(());
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        RUNNING_TASKS.with(|t| t.set(t.get() - 1));
+    }
+}
+
+/// Starts a task writing to stable memory, `None` in maintenance.
+/// Held across awaits, the guard is dropped on a trap in a callback as well.
+pub fn begin_task() -> Option<TaskGuard> {
+    if is_in_maintenance() {
+        return None;
+    }
+    RUNNING_TASKS.with(|t| t.set(t.get() + 1));
+    Some(TaskGuard(()))
+}
+
+/// Fails unless the canister is in maintenance with no running task, so that nothing writes to the memories.
+fn ensure_idle() -> Result<(), String> {
+    if !is_in_maintenance() {
+        return Err("the canister is not in maintenance".to_string());
+    }
+    let running = RUNNING_TASKS.with(|t| t.get());
+    if running > 0 {
+        return Err(format!("{} tasks are running", running));
+    }
+    Ok(())
+}
+
+/// Exports the chunk of the memories given by `memory` at `cursor`, encoded by candid,
+/// starting a new export if `None`. Empty after the last chunk.
+/// The canister must be in maintenance with no running task, so that the chunks make up a consistent copy.
+pub fn export_chunk<M: Memory>(
+    memory: impl Fn(u8) -> Option<M>,
+    cursor: Option<ExportCursor>,
+) -> Result<Vec<u8>, StorageError> {
+    ensure_idle().map_err(StorageError::ExportRefused)?;
+    let cursor = cursor.unwrap_or_else(|| ExportCursor::start(ic_cdk::api::time()));
+    Ok(match export_chunk_from(memory, cursor) {
+        Some(chunk) => Encode!(&chunk).unwrap(),
+        None => vec![],
+    })
+}
+
+/// Imports a chunk given by `export_chunk` of another canister to the memories given by `memory`.
+/// The canister must be in maintenance with no running task, so that nothing writes to the memories meanwhile.
+/// Stable structures are loaded on init, so the canister is to be upgraded after the last chunk.
+pub fn import_chunk<M: Memory>(
+    memory: impl Fn(u8) -> Option<M>,
+    bytes: &[u8],
+) -> Result<Option<ExportCursor>, StorageError> {
+    ensure_idle().map_err(StorageError::ImportRefused)?;
+    let chunk =
+        Decode!(bytes, ExportedChunk).map_err(|e| StorageError::InvalidChunk(e.to_string()))?;
+    IMPORT.with(|p| import_chunk_into(memory, &mut p.borrow_mut(), chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::{
+        memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+        StableBTreeMap, VectorMemory,
+    };
+
+    use super::*;
+
+    fn memories<'a>(
+        manager: &'a MemoryManager<VectorMemory>,
+        ids: &'a [u8],
+    ) -> impl Fn(u8) -> Option<VirtualMemory<VectorMemory>> + 'a {
+        move |id| ids.contains(&id).then(|| manager.get(MemoryId::new(id)))
+    }
+
+    fn copy(
+        from: &MemoryManager<VectorMemory>,
+        to: &MemoryManager<VectorMemory>,
+        ids: &[u8],
+    ) -> usize {
+        let mut cursor = ExportCursor::start(1);
+        let mut progress = None;
+        let mut chunks = 0;
+        while let Some(chunk) = export_chunk_from(memories(from, ids), cursor) {
+            chunks += 1;
+            match import_chunk_into(memories(to, ids), &mut progress, chunk).unwrap() {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = MemoryManager::init(VectorMemory::default());
+        let mut snapshots = StableBTreeMap::<u64, String, _>::init(source.get(MemoryId::new(1)));
+        let mut events = StableBTreeMap::<u64, Vec<u8>, _>::init(source.get(MemoryId::new(33)));
+        let mut config = StableBTreeMap::<u64, u64, _>::init(source.get(MemoryId::new(3)));
+        for i in 0..100 {
+            snapshots.insert(i, format!("snapshot {}", i));
+            events.insert(i, vec![i as u8; 30_000]);
+        }
+        config.insert(1, 1);
+
+        let target = MemoryManager::init(VectorMemory::default());
+        // 3 MiB of events take several chunks
+        assert!(copy(&source, &target, &[1, 33]) > 3);
+
+        let snapshots = StableBTreeMap::<u64, String, _>::init(target.get(MemoryId::new(1)));
+        let events = StableBTreeMap::<u64, Vec<u8>, _>::init(target.get(MemoryId::new(33)));
+        assert_eq!(snapshots.len(), 100);
+        assert_eq!(snapshots.get(&42), Some("snapshot 42".to_string()));
+        assert_eq!(events.get(&99), Some(vec![99; 30_000]));
+        // not in the allow-list
+        assert_eq!(target.get(MemoryId::new(3)).size(), 0);
+    }
+
+    #[test]
+    fn test_import_corrupted_chunk() {
+        let source = MemoryManager::init(VectorMemory::default());
+        StableBTreeMap::<u64, u64, _>::init(source.get(MemoryId::new(1))).insert(1, 1);
+        let mut chunk = export_chunk_from(memories(&source, &[1]), ExportCursor::start(1)).unwrap();
+        assert_eq!(chunk.next, None);
+        chunk.data[0] ^= 1;
+        let target = MemoryManager::init(VectorMemory::default());
+        assert_eq!(
+            import_chunk_into(memories(&target, &[1]), &mut None, chunk),
+            Err(StorageError::ChecksumMismatch {
+                memory_id: 1,
+                offset: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_import_chunks_out_of_order() {
+        let source = MemoryManager::init(VectorMemory::default());
+        let mut events = StableBTreeMap::<u64, Vec<u8>, _>::init(source.get(MemoryId::new(1)));
+        for i in 0..50 {
+            events.insert(i, vec![i as u8; 30_000]);
+        }
+        let first = export_chunk_from(memories(&source, &[1]), ExportCursor::start(1)).unwrap();
+        let second = export_chunk_from(memories(&source, &[1]), first.next.unwrap()).unwrap();
+        let of_another_export =
+            export_chunk_from(memories(&source, &[1]), ExportCursor::start(2)).unwrap();
+
+        let target = MemoryManager::init(VectorMemory::default());
+        let mut progress = None;
+        // not from the first chunk
+        assert!(import_chunk_into(memories(&target, &[1]), &mut progress, second.clone()).is_err());
+        import_chunk_into(memories(&target, &[1]), &mut progress, first).unwrap();
+        let mut of_another_export = of_another_export;
+        of_another_export.sequence = 1;
+        of_another_export.checksum = of_another_export.checksum();
+        assert!(
+            import_chunk_into(memories(&target, &[1]), &mut progress, of_another_export).is_err()
+        );
+        import_chunk_into(memories(&target, &[1]), &mut progress, second).unwrap();
+        // memory not in the allow-list of the target
+        let chunk = export_chunk_from(memories(&source, &[1]), ExportCursor::start(3)).unwrap();
+        assert!(import_chunk_into(memories(&target, &[2]), &mut None, chunk).is_err());
+    }
+
+    #[test]
+    fn test_export_empty() {
+        let source = MemoryManager::init(VectorMemory::default());
+        StableBTreeMap::<u64, u64, _>::init(source.get(MemoryId::new(3))).insert(1, 1);
+        assert_eq!(
+            export_chunk_from(memories(&source, &[1]), ExportCursor::start(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_export_out_of_maintenance() {
+        let memory = |_| None::<VectorMemory>;
+        assert!(matches!(
+            export_chunk(memory, Some(ExportCursor::start(1))),
+            Err(StorageError::ExportRefused(_))
+        ));
+        let task = begin_task();
+        set_maintenance(true);
+        assert!(matches!(
+            export_chunk(memory, Some(ExportCursor::start(1))),
+            Err(StorageError::ExportRefused(_))
+        ));
+        drop(task);
+        assert_eq!(
+            export_chunk(memory, Some(ExportCursor::start(1))),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_import_out_of_maintenance() {
+        let chunk = Encode!(&()).unwrap();
+        let memory = |_| None::<VectorMemory>;
+        assert!(matches!(
+            import_chunk(memory, &chunk),
+            Err(StorageError::ImportRefused(_))
+        ));
+        set_maintenance(true);
+        let task = begin_task();
+        assert!(task.is_none());
+        set_maintenance(false);
+        let _task = begin_task().unwrap();
+        set_maintenance(true);
+        assert!(matches!(
+            import_chunk(memory, &chunk),
+            Err(StorageError::ImportRefused(_))
+        ));
+    }
+}
@@ -1,7 +1,23 @@
+mod block_hash;
+mod checkpoint;
+mod error;
+mod index;
+mod indexer_state;
+mod migration;
+mod retention;
 mod storable;
 #[warn(clippy::module_inception)]
 mod storage;
 mod token;
+mod tokenizable;
+pub use block_hash::*;
+pub use checkpoint::*;
+pub use error::*;
+pub use index::*;
+pub use indexer_state::*;
+pub use migration::*;
+pub use retention::*;
 pub use storable::*;
 pub use storage::*;
 pub use token::*;
+pub use tokenizable::*;
@@ -0,0 +1,194 @@
+use candid::CandidType;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+/// Entries removed by a prune unless set otherwise, few enough to stay within the instruction limit of a message.
+pub const DEFAULT_MAX_PRUNE_PER_CALL: u64 = 1000;
+
+/// Limits of the entries kept by a store. Entries beyond any of the limits are pruned, from the oldest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Optional: Maximum number of entries
+    pub max_entries: Option<u64>,
+    /// Optional: Maximum age of entries in seconds, by their timestamp
+    pub max_age_secs: Option<u64>,
+    /// Optional: Maximum distance of entries from the latest block, by their block number
+    pub max_block_distance: Option<u64>,
+}
+
+/// Position of an entry in time and in the chain, compared against a `RetentionPolicy`.
+/// Limits are not applied to entries without the corresponding stamp.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryStamp {
+    pub timestamp_secs: Option<u64>,
+    pub block_number: Option<u64>,
+}
+
+/// Values kept under a `RetentionPolicy` in stores holding them as they are.
+pub trait Retainable {
+    fn stamp(&self) -> EntryStamp;
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_entries.is_none()
+            && self.max_age_secs.is_none()
+            && self.max_block_distance.is_none()
+    }
+
+    /// Whether an entry is older than the age or block distance limits.
+    pub fn is_expired(&self, stamp: &EntryStamp, now_secs: u64, latest_block: Option<u64>) -> bool {
+        let too_old = match (self.max_age_secs, stamp.timestamp_secs) {
+            (Some(max), Some(ts)) => now_secs.saturating_sub(ts) > max,
+            _ => false,
+        };
+        let too_far = match (self.max_block_distance, stamp.block_number, latest_block) {
+            (Some(max), Some(block), Some(latest)) => latest.saturating_sub(block) > max,
+            _ => false,
+        };
+        too_old || too_far
+    }
+
+    /// Removes up to `limit` entries of `map` beyond the limits and returns the number of removed entries.
+    /// Entries are expected in order from the oldest, so pruning stops at the first entry to keep.
+    /// Entries left beyond the limits are removed by the next calls.
+    pub fn prune<K, V, M>(
+        &self,
+        map: &mut StableBTreeMap<K, V, M>,
+        now_secs: u64,
+        limit: u64,
+        stamp: impl Fn(&K, &V) -> EntryStamp,
+    ) -> u64
+    where
+        K: Storable + Ord + Clone,
+        V: Storable,
+        M: Memory,
+    {
+        let expired = self.expired_keys(map, now_secs, limit, stamp);
+        for k in expired.iter() {
+            map.remove(k);
+        }
+        expired.len() as u64
+    }
+
+    /// Keys of up to `limit` entries of `map` to be removed by `prune`, from the oldest.
+    pub fn expired_keys<K, V, M>(
+        &self,
+        map: &StableBTreeMap<K, V, M>,
+        now_secs: u64,
+        limit: u64,
+        stamp: impl Fn(&K, &V) -> EntryStamp,
+    ) -> Vec<K>
+    where
+        K: Storable + Ord + Clone,
+        V: Storable,
+        M: Memory,
+    {
+        if self.is_empty() {
+            return vec![];
+        }
+        let excess = self
+            .max_entries
+            .map_or(0, |max| map.len().saturating_sub(max));
+        let latest_block = map
+            .last_key_value()
+            .and_then(|(k, v)| stamp(&k, &v).block_number);
+        let mut expired = vec![];
+        for (i, (k, v)) in map.iter().enumerate().take(limit as usize) {
+            if (i as u64) >= excess && !self.is_expired(&stamp(&k, &v), now_secs, latest_block) {
+                break;
+            }
+            expired.push(k);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    use super::*;
+
+    fn map(entries: &[(u64, u64)]) -> StableBTreeMap<u64, u64, DefaultMemoryImpl> {
+        let mut map = StableBTreeMap::new(DefaultMemoryImpl::default());
+        for (k, v) in entries {
+            map.insert(*k, *v);
+        }
+        map
+    }
+
+    fn by_timestamp(_: &u64, ts: &u64) -> EntryStamp {
+        EntryStamp {
+            timestamp_secs: Some(*ts),
+            block_number: None,
+        }
+    }
+
+    fn by_block(block: &u64, _: &u64) -> EntryStamp {
+        EntryStamp {
+            timestamp_secs: None,
+            block_number: Some(*block),
+        }
+    }
+
+    #[test]
+    fn test_prune_max_entries() {
+        let mut m = map(&[(1, 0), (2, 0), (3, 0), (4, 0)]);
+        let policy = RetentionPolicy {
+            max_entries: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(policy.prune(&mut m, 0, 10, by_timestamp), 1);
+        assert_eq!(m.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(policy.prune(&mut m, 0, 10, by_timestamp), 0);
+    }
+
+    #[test]
+    fn test_prune_limit() {
+        let mut m = map(&[(1, 100), (2, 100), (3, 100), (4, 100), (5, 300)]);
+        let policy = RetentionPolicy {
+            max_age_secs: Some(150),
+            ..Default::default()
+        };
+        // the rest is left to the next calls
+        assert_eq!(policy.prune(&mut m, 360, 3, by_timestamp), 3);
+        assert_eq!(m.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(policy.prune(&mut m, 360, 3, by_timestamp), 1);
+        assert_eq!(policy.prune(&mut m, 360, 3, by_timestamp), 0);
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_max_age() {
+        let mut m = map(&[(1, 100), (2, 200), (3, 300)]);
+        let policy = RetentionPolicy {
+            max_age_secs: Some(150),
+            ..Default::default()
+        };
+        assert_eq!(policy.prune(&mut m, 360, 10, by_timestamp), 2);
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_max_block_distance() {
+        let mut m = map(&[(10, 0), (15, 0), (20, 0), (30, 0)]);
+        let policy = RetentionPolicy {
+            max_entries: Some(10),
+            max_block_distance: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(policy.prune(&mut m, 0, 10, by_block), 2);
+        assert_eq!(m.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec![20, 30]);
+    }
+
+    #[test]
+    fn test_prune_without_limits() {
+        let mut m = map(&[(1, 0)]);
+        assert_eq!(
+            RetentionPolicy::default().prune(&mut m, u64::MAX, 10, by_timestamp),
+            0
+        );
+        assert_eq!(m.len(), 1);
+    }
+}
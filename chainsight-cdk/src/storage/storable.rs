@@ -1,6 +1,6 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, cell::Cell, ops::Bound};
 
-use ic_stable_structures::{storable::Bound, Storable};
+use ic_stable_structures::{storable::Bound as StorableBound, Memory, StableBTreeMap, Storable};
 
 #[derive(
     Debug,
@@ -29,7 +29,7 @@ impl Storable for StorableBool {
         }
     }
 
-    const BOUND: Bound = Bound::Bounded {
+    const BOUND: StorableBound = StorableBound::Bounded {
         max_size: 1,
         is_fixed_size: true,
     };
@@ -91,7 +91,7 @@ impl Storable for StorableStrings {
         Self(result)
     }
 
-    const BOUND: Bound = Bound::Bounded {
+    const BOUND: StorableBound = StorableBound::Bounded {
         max_size: 8192,
         is_fixed_size: false,
     };
@@ -108,6 +108,103 @@ impl From<Vec<String>> for StorableStrings {
     }
 }
 
+/// Magic of the bytes of versioned `StableMemoryStorable` types, followed by the version.
+/// Bytes encoded without a version start with the magic of candid, `DIDL`, instead.
+const VERSION_MAGIC: &[u8; 3] = b"VSN";
+/// Length of the header of versioned `StableMemoryStorable` types: the magic and the version.
+pub const VERSION_HEADER_LEN: u32 = 7;
+
+/// Conversion of values encoded by older versions of a type deriving `StableMemoryStorable`
+/// with `#[stable_mem_storable_opts(version = N)]`, called on read of those values.
+pub trait StableMemoryMigration: Sized {
+    /// Converts the candid `bytes` encoded by `version`, 0 for values encoded before versions were set.
+    fn migrate(version: u32, bytes: &[u8]) -> Self;
+}
+
+/// Bytes of `payload` with the header of `version`.
+pub fn encode_versioned(version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(VERSION_HEADER_LEN as usize + payload.len());
+    bytes.extend_from_slice(VERSION_MAGIC);
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Version and payload of `bytes`, of version 0 without a header.
+pub fn decode_versioned(bytes: &[u8]) -> (u32, &[u8]) {
+    if bytes.len() >= VERSION_HEADER_LEN as usize && bytes.starts_with(VERSION_MAGIC) {
+        let version = u32::from_be_bytes(bytes[3..7].try_into().unwrap());
+        (version, &bytes[VERSION_HEADER_LEN as usize..])
+    } else {
+        (0, bytes)
+    }
+}
+
+thread_local! {
+    static MIGRATED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Notes that the value being read was encoded by an older version, called by `from_bytes` of versioned types.
+pub fn note_migration() {
+    MIGRATED.with(|m| m.set(true));
+}
+
+fn take_migration() -> bool {
+    MIGRATED.with(|m| m.replace(false))
+}
+
+/// Progress of `migrate_entries`.
+#[derive(Clone, Debug, PartialEq, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct MigrationProgress<K> {
+    /// Number of entries read
+    pub scanned: u64,
+    /// Number of entries rewritten from an older version
+    pub migrated: u64,
+    /// Key to continue from, `None` once all entries were read
+    pub next: Option<K>,
+}
+
+/// Rewrites in the current version the values of `map` encoded by older ones, e.g. in `post_upgrade`,
+/// instead of migrating them on every read. Reads up to `limit` entries from `from`,
+/// so that large maps are migrated over several calls.
+pub fn migrate_entries<K, V, M>(
+    map: &mut StableBTreeMap<K, V, M>,
+    from: Option<K>,
+    limit: u64,
+) -> MigrationProgress<K>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    let start = from.map_or(Bound::Unbounded, Bound::Included);
+    let mut scanned = 0;
+    let mut next = None;
+    let mut outdated = Vec::new();
+    take_migration();
+    for (key, value) in map.range((start, Bound::Unbounded)) {
+        // read along with the value, so it tells whether the value was migrated
+        let migrated = take_migration();
+        if scanned == limit {
+            next = Some(key);
+            break;
+        }
+        scanned += 1;
+        if migrated {
+            outdated.push((key, value));
+        }
+    }
+    let migrated = outdated.len() as u64;
+    for (key, value) in outdated {
+        map.insert(key, value);
+    }
+    MigrationProgress {
+        scanned,
+        migrated,
+        next,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,4 +226,12 @@ mod tests {
         let decoded = StorableStrings::from_bytes(storable.to_bytes());
         assert_eq!(data, decoded.0);
     }
+
+    #[test]
+    fn test_versioned() {
+        let bytes = encode_versioned(2, b"DIDL");
+        assert_eq!(bytes.len(), VERSION_HEADER_LEN as usize + 4);
+        assert_eq!(decode_versioned(&bytes), (2, b"DIDL".as_slice()));
+        assert_eq!(decode_versioned(b"DIDL\x00"), (0, b"DIDL\x00".as_slice()));
+    }
 }
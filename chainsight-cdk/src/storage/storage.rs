@@ -1,5 +1,14 @@
 use core::fmt;
-use std::{borrow::Cow, cell::RefCell, collections::HashMap};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    marker::PhantomData,
+    ops::{Range, RangeBounds},
+    thread::LocalKey,
+};
 
 use candid::{CandidType, Decode, Encode};
 use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
@@ -9,11 +18,24 @@ use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl, Sto
 use serde::Deserialize;
 
 use super::token::Token;
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+use super::{
+    get_checkpoint, set_checkpoint, EntryStamp, RetentionPolicy, StorageError, StoreIndex,
+    DEFAULT_CHECKPOINT,
+};
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+type Stores = RefCell<BTreeMap<u8, Box<dyn Any>>>;
 
 pub trait Persist {
     fn untokenize(data: Data) -> Self;
     fn tokenize(&self) -> Data;
+    /// Reads the value from data, returning an error instead of panicking on invalid data.
+    /// Types implemented by hand fall back to `untokenize`.
+    fn try_untokenize(data: Data) -> Result<Self, StorageError>
+    where
+        Self: Sized,
+    {
+        Ok(Self::untokenize(data))
+    }
 }
 
 #[derive(Deserialize, CandidType, Clone, Debug)]
@@ -54,6 +76,8 @@ impl Data {
         Self(val)
     }
 }
+// NOTE: unbounded, so that a large snapshot or a block with many events fits into a single entry.
+// Stores created with the former bound of 100_000 bytes are migrated to the unbounded layout on load.
 impl Storable for Data {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
@@ -63,10 +87,7 @@ impl Storable for Data {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 100_000,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 impl Storable for Values {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
@@ -77,10 +98,7 @@ impl Storable for Values {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 100_000,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Values {
@@ -93,289 +111,480 @@ impl Data {
     pub fn get(&self, key: &str) -> Option<&Token> {
         self.0.get(key)
     }
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Token)> {
+        self.0.iter()
+    }
 }
 
+/// Memory ids given to stores with ids above 5, one `KeyValueStore` and one `KeyValuesStore` per id.
+/// Ids 1 to 5 keep memory ids 1 to 10, as before stores were created on demand.
+pub const DEFAULT_STORE_MEMORY_RANGE: Range<u8> = 32..255;
+
 thread_local! {
     static MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    // NOTE: Not currently in use, and id=0 conflicts with prepare_stable_structure macro
-    // static MAP: RefCell<StableBTreeMap<u64, Values, Memory>> = RefCell::new(
-    //     StableBTreeMap::init(
-    //         MANAGER.with(|m|m.borrow().get(MemoryId::new(0))),
-    //     )
-    // );
-    static KEY_VALUE_STORE_1: RefCell<StableBTreeMap<Id, Data, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(1))),
-        )
-    );
-    static KEY_VALUE_STORE_2: RefCell<StableBTreeMap<Id, Data, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(2))),
-        )
-    );
-    static KEY_VALUE_STORE_3: RefCell<StableBTreeMap<Id, Data, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(3))),
-        )
-    );
-    static KEY_VALUE_STORE_4: RefCell<StableBTreeMap<Id, Data, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(4))),
-        )
-    );
-    static KEY_VALUE_STORE_5: RefCell<StableBTreeMap<Id, Data, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(5))),
-        )
-    );
+    // NOTE: memory id 0 conflicts with prepare_stable_structure macro
+    static STORE_MEMORY_RANGE: RefCell<Range<u8>> = const { RefCell::new(DEFAULT_STORE_MEMORY_RANGE) };
+    static KEY_VALUE_STORES: Stores = const { RefCell::new(BTreeMap::new()) };
+    static KEY_VALUES_STORES: Stores = const { RefCell::new(BTreeMap::new()) };
+}
 
-    static KEY_VALUES_STORE_1: RefCell<StableBTreeMap<Id, Values, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(6))),
-        )
-    );
-    static KEY_VALUES_STORE_2: RefCell<StableBTreeMap<Id, Values, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(7))),
-        )
-    );
-    static KEY_VALUES_STORE_3: RefCell<StableBTreeMap<Id, Values, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(8))),
-        )
-    );
-    static KEY_VALUES_STORE_4: RefCell<StableBTreeMap<Id, Values, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(9))),
-        )
+/// Memory id of the checkpoints of indexing cursors, right below the default store memory range.
+pub const CHECKPOINT_MEMORY_ID: u8 = 31;
+/// Memory id of the indexer state besides its cursors, right below the checkpoints.
+pub const INDEXER_STATE_MEMORY_ID: u8 = 30;
+/// Memory id of the hashes of recent blocks tracked to detect reorgs, right below the indexer state.
+pub const BLOCK_HASH_MEMORY_ID: u8 = 29;
+/// Memory ids used by chainsight-cdk itself besides the stores.
+const RESERVED_MEMORY_IDS: [u8; 3] = [
+    CHECKPOINT_MEMORY_ID,
+    INDEXER_STATE_MEMORY_ID,
+    BLOCK_HASH_MEMORY_ID,
+];
+
+/// Sets the range of memory ids given to stores with ids above 5.
+/// Must be called before any of those stores is used, e.g. in `init` and `post_upgrade`,
+/// and must not overlap memory ids used by the canister itself.
+pub fn set_store_memory_range(range: Range<u8>) {
+    if let Some(id) = RESERVED_MEMORY_IDS
+        .into_iter()
+        .find(|id| range.contains(id))
+    {
+        panic!("Memory id {} is used by chainsight-cdk", id);
+    }
+    STORE_MEMORY_RANGE.with(|r| *r.borrow_mut() = range);
+}
+
+fn store_memory_id(mem_id: u8, values: bool) -> MemoryId {
+    assert!(mem_id > 0, "Invalid store id");
+    if mem_id < 6 {
+        return MemoryId::new(if values { mem_id + 5 } else { mem_id });
+    }
+    let range = STORE_MEMORY_RANGE.with(|r| r.borrow().clone());
+    let id = range.start as usize + (mem_id as usize - 6) * 2 + values as usize;
+    assert!(
+        id < range.end as usize,
+        "Store id {} is out of the store memory range {:?}",
+        mem_id,
+        range
     );
-    static KEY_VALUES_STORE_5: RefCell<StableBTreeMap<Id, Values, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MANAGER.with(|m|m.borrow().get(MemoryId::new(10))),
-        )
+    MemoryId::new(id as u8)
+}
+
+/// Memory of a `StoreIndex`, which must not be one of the memory ids of stores.
+pub(crate) fn index_memory(memory_id: u8) -> Memory {
+    let range = STORE_MEMORY_RANGE.with(|r| r.borrow().clone());
+    assert!(
+        memory_id > 10 && !range.contains(&memory_id) && !RESERVED_MEMORY_IDS.contains(&memory_id),
+        "Memory id {} is used by a store",
+        memory_id
     );
-    static LAST_KEY_STORE: RefCell<String> = const { RefCell::new(String::new()) }
+    MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
+
+pub(crate) fn checkpoint_memory() -> Memory {
+    MANAGER.with(|m| m.borrow().get(MemoryId::new(CHECKPOINT_MEMORY_ID)))
+}
+
+pub(crate) fn indexer_state_memory() -> Memory {
+    MANAGER.with(|m| m.borrow().get(MemoryId::new(INDEXER_STATE_MEMORY_ID)))
+}
+
+pub(crate) fn block_hash_memory() -> Memory {
+    MANAGER.with(|m| m.borrow().get(MemoryId::new(BLOCK_HASH_MEMORY_ID)))
+}
+
+/// Memory of `memory_id` to export or import, if it is one of the stores, the checkpoints,
+/// the indexer state, the block hashes or `indexes`.
+/// Memory ids 1 to 10 are those of the stores with ids 1 to 5, so the canister must not use them itself.
+pub fn store_memory(memory_id: u8, indexes: &[u8]) -> Option<VirtualMemory<DefaultMemoryImpl>> {
+    let range = STORE_MEMORY_RANGE.with(|r| r.borrow().clone());
+    let exported = (1..=10).contains(&memory_id)
+        || RESERVED_MEMORY_IDS.contains(&memory_id)
+        || range.contains(&memory_id)
+        || indexes.contains(&memory_id);
+    exported.then(|| MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+/// Keys of `KeyValueStore` and `KeyValuesStore`: bounded stable types ordered by `Ord`,
+/// e.g. `u64`, `Principal`, `[u8; 20]` for addresses, or tuples of them as composite keys.
+pub trait StoreKey: Storable + Ord + Clone + 'static {}
+impl<T: Storable + Ord + Clone + 'static> StoreKey for T {}
+
+/// Runs `f` with the stable BTreeMap of the store, creating it on first use.
+fn with_store<K: StoreKey, V: Storable + 'static, R>(
+    stores: &'static LocalKey<Stores>,
+    mem_id: u8,
+    values: bool,
+    f: impl FnOnce(&mut StableBTreeMap<K, V, Memory>) -> R,
+) -> R {
+    stores.with(|stores| {
+        let mut stores = stores.borrow_mut();
+        let store = stores.entry(mem_id).or_insert_with(|| {
+            Box::new(StableBTreeMap::<K, V, Memory>::init(
+                MANAGER.with(|m| m.borrow().get(store_memory_id(mem_id, values))),
+            ))
+        });
+        match store.downcast_mut::<StableBTreeMap<K, V, Memory>>() {
+            Some(store) => f(store),
+            None => panic!("Store {} is already used with another key type", mem_id),
+        }
+    })
+}
 
+fn assert_bounded_key<K: StoreKey>() {
+    assert!(
+        matches!(K::BOUND, Bound::Bounded { .. }),
+        "Store keys must be bounded"
+    );
 }
 
-pub struct KeyValuesStore {
-    store: &'static std::thread::LocalKey<RefCell<StableBTreeMap<Id, Values, Memory>>>,
+/// Store of a list of values per key, keyed by `u64` unless created by `new_keyed`.
+pub struct KeyValuesStore<K = u64> {
+    mem_id: u8,
+    _key: PhantomData<K>,
 }
 
+/// Sets the cursor of the indexer, kept in stable memory as the checkpoint `DEFAULT_CHECKPOINT`.
 pub fn set_last_key(key: String) {
-    LAST_KEY_STORE.with(|m| {
-        *m.borrow_mut() = key;
-    });
+    set_checkpoint(DEFAULT_CHECKPOINT, key);
 }
+/// Cursor of the indexer, empty if it has not indexed yet.
 pub fn get_last_key() -> String {
-    LAST_KEY_STORE.with(|m| m.borrow().clone())
+    get_checkpoint(DEFAULT_CHECKPOINT)
+        .map(|c| c.key)
+        .unwrap_or_default()
 }
 
 impl KeyValuesStore {
     pub fn new(mem_id: u8) -> Self {
-        assert!(mem_id > 0 && mem_id < 6);
-        match mem_id {
-            1 => Self {
-                store: &KEY_VALUES_STORE_1,
-            },
-            2 => Self {
-                store: &KEY_VALUES_STORE_2,
-            },
-            3 => Self {
-                store: &KEY_VALUES_STORE_3,
-            },
-            4 => Self {
-                store: &KEY_VALUES_STORE_4,
-            },
-            5 => Self {
-                store: &KEY_VALUES_STORE_5,
-            },
-            _ => panic!("Invalid store id"),
+        Self::new_keyed(mem_id)
+    }
+
+    pub fn last_n(&self, n: u64) -> Vec<(u64, Values)> {
+        let last = self.last();
+        if last.is_none() {
+            return vec![];
+        }
+        let (last_key, last_values) = last.unwrap();
+        if n == 1 {
+            return vec![(last_key, last_values)];
         }
+
+        let from_key = if last_key < n { 0 } else { last_key - n };
+        let key_range = from_key..last_key + 1; // note: to include the last item in the retrieval
+        self.with(|m| {
+            m.range(key_range)
+                .map(|(k, v)| (k, v.clone()))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    /// Removes up to `limit` of the oldest keys beyond `policy` and returns the number of removed keys.
+    /// Keys are taken as block numbers, and `timestamp_secs` gives the timestamp of the values if any.
+    /// The removed values are removed from `indexes` too, as by `remove_indexed`.
+    pub fn prune<T>(
+        &self,
+        policy: &RetentionPolicy,
+        now_secs: u64,
+        limit: u64,
+        indexes: &[(&str, StoreIndex)],
+        timestamp_secs: impl Fn(&[T]) -> Option<u64>,
+    ) -> u64
+    where
+        T: Persist,
+    {
+        let needs_values = policy.max_age_secs.is_some();
+        let expired = self.with(|m| {
+            policy.expired_keys(m, now_secs, limit, |k, v| EntryStamp {
+                timestamp_secs: if needs_values {
+                    untokenize_all::<T>(v.0.clone())
+                        .ok()
+                        .and_then(|values| timestamp_secs(&values))
+                } else {
+                    None
+                },
+                block_number: Some(*k),
+            })
+        });
+        for k in expired.iter() {
+            self.remove_indexed(*k, indexes);
+        }
+        expired.len() as u64
+    }
+}
+
+impl<K: StoreKey> KeyValuesStore<K> {
+    pub fn new_keyed(mem_id: u8) -> Self {
+        assert_bounded_key::<K>();
+        store_memory_id(mem_id, true);
+        Self {
+            mem_id,
+            _key: PhantomData,
+        }
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut StableBTreeMap<K, Values, Memory>) -> R) -> R {
+        with_store(&KEY_VALUES_STORES, self.mem_id, true, f)
     }
-    pub fn get<T>(&self, id: u64) -> Vec<T>
+    pub fn get<T>(&self, id: K) -> Result<Vec<T>, StorageError>
     where
         T: Persist,
     {
-        self.store.with(|m| {
-            m.borrow()
-                .get(&Id(id))
-                .map(|v| {
-                    let elems: Vec<T> = v.0.iter().map(|e| T::untokenize(e.clone())).collect();
-                    elems
-                })
-                .unwrap_or_default()
+        self.with(|m| {
+            m.get(&id)
+                .map(|v| untokenize_all(v.0))
+                .unwrap_or(Ok(vec![]))
         })
     }
-    pub fn set<T>(&self, id: u64, values: Vec<T>)
+    pub fn set<T>(&self, id: K, values: Vec<T>)
     where
         T: Persist,
     {
         let values: Vec<Data> = values.into_iter().map(|v| v.tokenize()).collect();
-        self.store.with(|m| {
-            m.borrow_mut().insert(Id(id), Values(values));
+        self.with(|m| {
+            m.insert(id, Values(values));
         })
     }
-    // note: to get by BTreeMap#range, targets of acquisition is `from <= item < to`
-    pub fn between<T>(&self, from: u64, to: u64) -> HashMap<u64, Vec<T>>
+    pub fn remove(&self, id: K) {
+        self.with(|m| {
+            m.remove(&id);
+        })
+    }
+    /// Sets the values as `set`, updating the indexes of the fields named as in `Data`.
+    pub fn set_indexed<T>(&self, id: K, values: Vec<T>, indexes: &[(&str, StoreIndex<K>)])
     where
         T: Persist,
     {
-        self.store.with(|m| {
-            m.borrow()
-                .range(Id(from)..Id(to))
-                .map(|(k, v)| {
-                    (
-                        k.clone(),
-                        v.0.iter().map(|e| T::untokenize(e.clone())).collect(),
-                    )
-                })
-                .fold(HashMap::new(), |mut acc, (k, v)| {
-                    acc.insert(k.0, v);
-                    acc
-                })
+        self.unindex(&id, indexes);
+        let values: Vec<Data> = values.into_iter().map(|v| v.tokenize()).collect();
+        for (field, index) in indexes {
+            for token in values.iter().filter_map(|data| data.get(field)) {
+                index.insert(token, &id);
+            }
+        }
+        self.with(|m| {
+            m.insert(id, Values(values));
         })
     }
-
-    pub fn last(&self) -> Option<(u64, Values)> {
-        let last = self.store.with(|m| m.borrow().last_key_value());
-        if let Some(last) = last {
-            Some((last.0.clone().0, last.1.clone()))
-        } else {
-            None
-        }
+    /// Removes the values as `remove`, updating the indexes of the fields named as in `Data`.
+    pub fn remove_indexed(&self, id: K, indexes: &[(&str, StoreIndex<K>)]) {
+        self.unindex(&id, indexes);
+        self.remove(id)
     }
-
-    pub fn last_n(&self, n: u64) -> Vec<(u64, Values)> {
-        let last = self.last();
-        if last.is_none() {
-            return vec![];
+    fn unindex(&self, id: &K, indexes: &[(&str, StoreIndex<K>)]) {
+        if indexes.is_empty() {
+            return;
         }
-        let (last_key, last_values) = last.unwrap();
-        if n == 1 {
-            return vec![(last_key, last_values)];
+        if let Some(old) = self.with(|m| m.get(id)) {
+            for (field, index) in indexes {
+                for token in old.0.iter().filter_map(|data| data.get(field)) {
+                    index.remove(token, id);
+                }
+            }
         }
-
-        let from_key = if last_key < n { 0 } else { last_key - n };
-        let key_range = Id(from_key)..Id(last_key + 1); // note: to include the last item in the retrieval
-        self.store.with(|m| {
-            m.borrow()
-                .range(key_range)
-                .map(|(k, v)| (k.clone().0, v.clone()))
-                .collect::<Vec<_>>()
+    }
+    /// Values whose field equals `value`, looked up by the index of the field.
+    pub fn find<T>(
+        &self,
+        field: &str,
+        value: &Token,
+        index: &StoreIndex<K>,
+    ) -> Result<HashMap<K, Vec<T>>, StorageError>
+    where
+        T: Persist,
+        K: Hash,
+    {
+        index
+            .keys(value)
+            .into_iter()
+            .map(|k| {
+                let values = self.with(|m| m.get(&k)).map(|v| v.0).unwrap_or_default();
+                let matched = values
+                    .into_iter()
+                    .filter(|data| data.get(field) == Some(value))
+                    .collect();
+                Ok((k, untokenize_all(matched)?))
+            })
+            .collect()
+    }
+    // note: to get by BTreeMap#range, targets of acquisition is `from <= item < to`
+    pub fn between<T>(&self, from: K, to: K) -> Result<HashMap<K, Vec<T>>, StorageError>
+    where
+        T: Persist,
+        K: Hash,
+    {
+        Ok(self.range(from..to)?.into_iter().collect())
+    }
+    /// Values of the keys in the range in key order, e.g. `(address, 0)..(address, u64::MAX)`
+    /// for all blocks of an address with `(address, block)` keys.
+    pub fn range<T>(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, Vec<T>)>, StorageError>
+    where
+        T: Persist,
+    {
+        self.with(|m| {
+            m.range(range)
+                .map(|(k, v)| Ok((k, untokenize_all(v.0)?)))
+                .collect()
         })
     }
 
-    pub fn last_elems<T>(&self, n: u64) -> HashMap<u64, Vec<T>>
+    pub fn last(&self) -> Option<(K, Values)> {
+        self.with(|m| m.last_key_value())
+    }
+
+    /// Last `n` values, iterating from the last key so that the cost does not grow with the store.
+    pub fn last_elems<T>(&self, n: u64) -> Result<HashMap<K, Vec<T>>, StorageError>
     where
         T: Persist,
+        K: Hash,
     {
-        let length = self.store.with(|m| m.borrow().len());
         let mut result = HashMap::new();
-        self.store.with(|m| {
+        self.with(|m| {
             let mut processed = 0;
-            for i in 0..length {
-                let idx = length - i - 1;
-                let elems = m.borrow().iter().nth(idx as usize);
-                if let Some((k, v)) = elems {
-                    let elems_len = v.0.len() as u64;
-                    let elems = v.0;
-                    if processed + elems_len > n {
-                        let elems = elems
-                            .iter()
-                            .skip((elems_len - (n - processed)) as usize)
-                            .map(|e| T::untokenize(e.clone()))
-                            .collect::<Vec<_>>();
-                        result.insert(k.clone().0, elems);
-                        break;
-                    } else {
-                        result.insert(
-                            k.clone().0,
-                            elems
-                                .iter()
-                                .map(|e| T::untokenize(e.clone()))
-                                .collect::<Vec<_>>(),
-                        );
-                        processed += elems.len() as u64;
-                    }
-                } else {
+            for (k, v) in m.iter().rev() {
+                if processed >= n {
                     break;
                 }
+                let elems_len = v.0.len() as u64;
+                let skip = (elems_len + processed).saturating_sub(n);
+                let elems = untokenize_all(v.0.into_iter().skip(skip as usize).collect())?;
+                result.insert(k, elems);
+                processed += elems_len;
             }
-        });
-        result
+            Ok::<(), StorageError>(())
+        })?;
+        Ok(result)
     }
 }
 
-pub struct KeyValueStore {
-    store: &'static std::thread::LocalKey<RefCell<StableBTreeMap<Id, Data, Memory>>>,
+fn untokenize_all<T: Persist>(elems: Vec<Data>) -> Result<Vec<T>, StorageError> {
+    elems.into_iter().map(T::try_untokenize).collect()
+}
+
+/// Store of a value per key, keyed by `u64` unless created by `new_keyed`.
+pub struct KeyValueStore<K = u64> {
+    mem_id: u8,
+    _key: PhantomData<K>,
 }
 
 impl KeyValueStore {
     pub fn new(mem_id: u8) -> Self {
-        assert!(mem_id > 0 && mem_id < 6);
-        match mem_id {
-            1 => Self {
-                store: &KEY_VALUE_STORE_1,
-            },
-            2 => Self {
-                store: &KEY_VALUE_STORE_2,
-            },
-            3 => Self {
-                store: &KEY_VALUE_STORE_3,
-            },
-            4 => Self {
-                store: &KEY_VALUE_STORE_4,
-            },
-            5 => Self {
-                store: &KEY_VALUE_STORE_5,
-            },
-            _ => panic!("Invalid store id"),
+        Self::new_keyed(mem_id)
+    }
+}
+
+impl<K: StoreKey> KeyValueStore<K> {
+    pub fn new_keyed(mem_id: u8) -> Self {
+        assert_bounded_key::<K>();
+        store_memory_id(mem_id, false);
+        Self {
+            mem_id,
+            _key: PhantomData,
         }
     }
-    pub fn get<T>(&self, id: u64) -> Option<T>
+    fn with<R>(&self, f: impl FnOnce(&mut StableBTreeMap<K, Data, Memory>) -> R) -> R {
+        with_store(&KEY_VALUE_STORES, self.mem_id, false, f)
+    }
+    pub fn get<T>(&self, id: K) -> Result<Option<T>, StorageError>
     where
         T: Persist,
     {
-        self.store
-            .with(|m| m.borrow().get(&Id(id)).map(T::untokenize))
+        self.with(|m| m.get(&id).map(T::try_untokenize).transpose())
     }
-    pub fn set<T>(&self, id: u64, data: T)
+    pub fn set<T>(&self, id: K, data: T)
     where
         T: Persist,
     {
-        self.store.with(|m| {
-            m.borrow_mut().insert(Id(id), data.tokenize());
+        self.with(|m| {
+            m.insert(id, data.tokenize());
+        })
+    }
+    pub fn remove(&self, id: K) {
+        self.with(|m| {
+            m.remove(&id);
         })
     }
-    pub fn between<T>(&self, from: u64, to: u64) -> Vec<(u64, T)>
+    /// Sets the data as `set`, updating the indexes of the fields named as in `Data`.
+    pub fn set_indexed<T>(&self, id: K, data: T, indexes: &[(&str, StoreIndex<K>)])
     where
         T: Persist,
     {
-        self.store.with(|m| {
-            m.borrow()
-                .range(Id(from)..Id(to))
-                .map(|(k, v)| (k.clone().0, T::untokenize(v.clone())))
-                .collect()
+        self.unindex(&id, indexes);
+        let data = data.tokenize();
+        for (field, index) in indexes {
+            if let Some(token) = data.get(field) {
+                index.insert(token, &id);
+            }
+        }
+        self.with(|m| {
+            m.insert(id, data);
         })
     }
-    pub fn last<T>(&self, n: u64) -> Vec<(u64, T)>
+    /// Removes the data as `remove`, updating the indexes of the fields named as in `Data`.
+    pub fn remove_indexed(&self, id: K, indexes: &[(&str, StoreIndex<K>)]) {
+        self.unindex(&id, indexes);
+        self.remove(id)
+    }
+    fn unindex(&self, id: &K, indexes: &[(&str, StoreIndex<K>)]) {
+        if let Some(old) = self.with(|m| m.get(id)) {
+            for (field, index) in indexes {
+                if let Some(token) = old.get(field) {
+                    index.remove(token, id);
+                }
+            }
+        }
+    }
+    /// Data whose field equals `value`, looked up by the index of the field.
+    pub fn find<T>(
+        &self,
+        field: &str,
+        value: &Token,
+        index: &StoreIndex<K>,
+    ) -> Result<Vec<(K, T)>, StorageError>
     where
         T: Persist,
     {
-        let length = self.store.with(|m| m.borrow().len());
-        let skip = if length <= n { 0 } else { length - n };
-        self.store
-            .with(|m| {
-                m.borrow()
-                    .iter()
-                    .skip(skip as usize)
-                    .map(|(k, v)| (k.clone().0, T::untokenize(v.clone())))
-                    .collect::<Vec<_>>()
-            })
+        index
+            .keys(value)
             .into_iter()
+            .filter_map(|k| {
+                self.with(|m| m.get(&k))
+                    .filter(|data| data.get(field) == Some(value))
+                    .map(|data| Ok((k, T::try_untokenize(data)?)))
+            })
             .collect()
     }
+    pub fn between<T>(&self, from: K, to: K) -> Result<Vec<(K, T)>, StorageError>
+    where
+        T: Persist,
+    {
+        self.range(from..to)
+    }
+    /// Values of the keys in the range in key order, e.g. `(address, 0)..(address, u64::MAX)`
+    /// for all blocks of an address with `(address, block)` keys.
+    pub fn range<T>(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, T)>, StorageError>
+    where
+        T: Persist,
+    {
+        self.with(|m| {
+            m.range(range)
+                .map(|(k, v)| Ok((k, T::try_untokenize(v)?)))
+                .collect()
+        })
+    }
+    pub fn last<T>(&self, n: u64) -> Result<Vec<(K, T)>, StorageError>
+    where
+        T: Persist,
+    {
+        let mut last = self.with(|m| {
+            m.iter()
+                .rev()
+                .take(n as usize)
+                .map(|(k, v)| Ok((k, T::try_untokenize(v)?)))
+                .collect::<Result<Vec<_>, StorageError>>()
+        })?;
+        last.reverse();
+        Ok(last)
+    }
 }
 
 #[cfg(test)]
@@ -410,4 +619,227 @@ mod tests {
         let kvs = KeyValueStore::new(1);
         _ = kvs.between::<SampleStruct>(0, 10);
     }
+
+    #[test]
+    fn test_store_memory_id() {
+        assert_eq!(store_memory_id(1, false), MemoryId::new(1));
+        assert_eq!(store_memory_id(5, true), MemoryId::new(10));
+        assert_eq!(store_memory_id(6, false), MemoryId::new(32));
+        assert_eq!(store_memory_id(6, true), MemoryId::new(33));
+        assert_eq!(store_memory_id(7, false), MemoryId::new(34));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of the store memory range")]
+    fn test_store_memory_id_out_of_range() {
+        store_memory_id(200, true);
+    }
+
+    #[test]
+    fn test_kvs_beyond_predefined_stores() {
+        let stores = (6..12).map(KeyValueStore::new).collect::<Vec<_>>();
+        for (i, store) in stores.iter().enumerate() {
+            store.set(
+                1,
+                SampleStruct {
+                    name: format!("store {}", i),
+                    age: i as u32,
+                },
+            );
+        }
+        KeyValuesStore::new(6).set(
+            1,
+            vec![SampleStruct {
+                name: "values".to_string(),
+                age: 0,
+            }],
+        );
+        for (i, store) in stores.iter().enumerate() {
+            let stored = store.get::<SampleStruct>(1).unwrap().unwrap();
+            assert_eq!(stored.name, format!("store {}", i));
+        }
+        assert_eq!(
+            KeyValuesStore::new(6).get::<SampleStruct>(1).unwrap()[0].name,
+            "values"
+        );
+    }
+
+    #[test]
+    fn test_composite_key_range() {
+        let store = KeyValuesStore::<([u8; 20], u64)>::new_keyed(12);
+        let sample = |age: u32| {
+            vec![SampleStruct {
+                name: "transfer".to_string(),
+                age,
+            }]
+        };
+        store.set(([1; 20], 10), sample(1));
+        store.set(([1; 20], 20), sample(2));
+        store.set(([2; 20], 15), sample(3));
+        let of_first = store
+            .range::<SampleStruct>(([1; 20], 0)..=([1; 20], u64::MAX))
+            .unwrap();
+        assert_eq!(
+            of_first
+                .iter()
+                .map(|(k, v)| (k.1, v[0].age))
+                .collect::<Vec<_>>(),
+            vec![(10, 1), (20, 2)]
+        );
+        assert_eq!(
+            store
+                .between::<SampleStruct>(([1; 20], 15), ([2; 20], 16))
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_kvs_prune() {
+        let store = KeyValuesStore::new(14);
+        for block in [100, 105, 110, 120] {
+            store.set(
+                block,
+                vec![SampleStruct {
+                    name: "block".to_string(),
+                    age: block as u32 * 10,
+                }],
+            );
+        }
+        let by_distance = RetentionPolicy {
+            max_block_distance: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.prune::<SampleStruct>(&by_distance, 0, 10, &[], |_| None),
+            2
+        );
+        let by_age = RetentionPolicy {
+            max_age_secs: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.prune::<SampleStruct>(&by_age, 1200, 10, &[], |values| values
+                .first()
+                .map(|v| v.age as u64)),
+            1
+        );
+        assert_eq!(
+            store.last_n(10).iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+            vec![120]
+        );
+    }
+
+    #[test]
+    fn test_kvs_prune_indexed() {
+        let store = KeyValuesStore::new(17);
+        let indexes = [("name", StoreIndex::new(21))];
+        for (block, name) in [(100, "alice"), (110, "bob"), (120, "alice")] {
+            store.set_indexed(
+                block,
+                vec![SampleStruct {
+                    name: name.to_string(),
+                    age: 0,
+                }],
+                &indexes,
+            );
+        }
+        let policy = RetentionPolicy {
+            max_entries: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.prune::<SampleStruct>(&policy, 0, 10, &indexes, |_| None),
+            1
+        );
+        // no dangling entries are left in the index
+        assert_eq!(indexes[0].1.keys(&Token::from("alice")), vec![120]);
+    }
+
+    #[test]
+    fn test_kvs_large_values() {
+        let store = KeyValuesStore::new(15);
+        let events = (0..30)
+            .map(|i| SampleStruct {
+                name: "x".repeat(10_000),
+                age: i,
+            })
+            .collect::<Vec<_>>();
+        store.set(1, events);
+        let events: Vec<SampleStruct> = store.get(1).unwrap();
+        assert_eq!(events.len(), 30);
+        assert_eq!(events[29].name.len(), 10_000);
+    }
+
+    #[test]
+    fn test_load_bounded_values() {
+        struct BoundedValues(Values);
+        impl Storable for BoundedValues {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                self.0.to_bytes()
+            }
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                Self(Values::from_bytes(bytes))
+            }
+            const BOUND: Bound = Bound::Bounded {
+                max_size: 100_000,
+                is_fixed_size: false,
+            };
+        }
+        let values = |name: String| Values(vec![SampleStruct { name, age: 1 }.tokenize()]);
+
+        let memory = ic_stable_structures::VectorMemory::default();
+        let mut bounded = StableBTreeMap::<u64, BoundedValues, _>::init(memory.clone());
+        bounded.insert(1, BoundedValues(values("small".to_string())));
+
+        let mut unbounded = StableBTreeMap::<u64, Values, _>::init(memory);
+        unbounded.insert(2, values("x".repeat(200_000)));
+        let name = |v: Values| SampleStruct::untokenize(v.to_vec().remove(0)).name;
+        assert_eq!(name(unbounded.get(&1).unwrap()), "small");
+        assert_eq!(name(unbounded.get(&2).unwrap()).len(), 200_000);
+    }
+
+    #[test]
+    fn test_kvs_last_elems() {
+        let store = KeyValuesStore::new(16);
+        for block in 1..=3u64 {
+            store.set(
+                block,
+                (0..2)
+                    .map(|i| SampleStruct {
+                        name: format!("{}-{}", block, i),
+                        age: i,
+                    })
+                    .collect(),
+            );
+        }
+        let names = |n: u64| {
+            let mut names = store
+                .last_elems::<SampleStruct>(n)
+                .unwrap()
+                .into_values()
+                .flat_map(|v| v.into_iter().map(|s| s.name))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(names(1), vec!["3-1"]);
+        assert_eq!(names(3), vec!["2-1", "3-0", "3-1"]);
+        assert_eq!(names(10).len(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "already used with another key type")]
+    fn test_store_with_another_key_type() {
+        KeyValueStore::new(13).set(
+            1,
+            SampleStruct {
+                name: "a".to_string(),
+                age: 1,
+            },
+        );
+        _ = KeyValueStore::<candid::Principal>::new_keyed(13)
+            .get::<SampleStruct>(candid::Principal::anonymous());
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::fmt::Write;
 
@@ -5,6 +6,8 @@ use candid::CandidType;
 use primitive_types::{H256, U256};
 use serde::Deserialize;
 
+use super::StorageError;
+
 #[derive(Debug, Clone, PartialEq, Deserialize, CandidType)]
 pub enum Token {
     String(String),
@@ -12,7 +15,102 @@ pub enum Token {
     Bool(bool),
     Array(Vec<Token>),
     Bytes(Vec<u8>),
+    /// Big-endian two's complement bytes of a signed integer
+    Int(Vec<u8>),
+    /// 20 bytes of an EVM address
+    Address(Vec<u8>),
+    Map(BTreeMap<String, Token>),
+    Null,
+}
+
+/// Decimal string of big-endian two's complement bytes of any width up to 32 bytes.
+fn int_to_string(bytes: &[u8]) -> String {
+    let fill = match bytes.first() {
+        Some(b) if b & 0x80 != 0 => 0xff,
+        _ => 0x00,
+    };
+    let mut extended = [fill; 32];
+    let len = bytes.len().min(32);
+    extended[32 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+    crate::core::I256::from_twos_complement(U256::from_big_endian(&extended))
+        .value()
+        .to_string()
+}
+
+fn is_negative(bytes: &[u8]) -> bool {
+    bytes.first().is_some_and(|b| b & 0x80 != 0)
+}
+
+/// Big-endian bytes of exactly `width` bytes holding the same value as `bytes` of any width,
+/// or `None` if the value does not fit.
+fn fit(bytes: &[u8], width: usize, signed: bool) -> Option<Vec<u8>> {
+    let negative = signed && is_negative(bytes);
+    let fill = if negative { 0xff } else { 0x00 };
+    let start = bytes.len().saturating_sub(width);
+    if bytes[..start].iter().any(|b| *b != fill) {
+        return None;
+    }
+    let mut fitted = vec![fill; width - (bytes.len() - start)];
+    fitted.extend_from_slice(&bytes[start..]);
+    if signed && is_negative(&fitted) != negative {
+        return None;
+    }
+    Some(fitted)
 }
+
+fn uint_value(bytes: &[u8]) -> Option<u128> {
+    fit(bytes, 16, false).map(|b| u128::from_be_bytes(b.try_into().unwrap()))
+}
+
+fn int_value(bytes: &[u8]) -> Option<i128> {
+    fit(bytes, 16, true).map(|b| i128::from_be_bytes(b.try_into().unwrap()))
+}
+
+/// Range-checked accessors of unsigned integers held in any width.
+macro_rules! uint_accessors {
+    ($($ty: ident: $try_to: ident, $to: ident);+) => {
+        $(
+            pub fn $try_to(&self) -> Result<$ty, StorageError> {
+                let value = match self {
+                    Token::Uint(u) => uint_value(u),
+                    Token::Int(i) if !is_negative(i) => uint_value(i),
+                    Token::Int(_) => None,
+                    _ => return Err(self.unexpected("uint")),
+                };
+                value
+                    .and_then(|v| $ty::try_from(v).ok())
+                    .ok_or_else(|| self.out_of_range(stringify!($ty)))
+            }
+            pub fn $to(&self) -> Option<$ty> {
+                self.$try_to().ok()
+            }
+        )+
+    };
+}
+
+/// Range-checked accessors of signed integers held in any width.
+macro_rules! int_accessors {
+    ($($ty: ident: $try_to: ident, $to: ident);+) => {
+        $(
+            pub fn $try_to(&self) -> Result<$ty, StorageError> {
+                let value = match self {
+                    Token::Int(i) => int_value(i).and_then(|v| $ty::try_from(v).ok()),
+                    // signed ints were stored as Uint of their own width before Int was introduced
+                    Token::Uint(u) if u.len() == std::mem::size_of::<$ty>() => {
+                        u.as_slice().try_into().ok().map($ty::from_be_bytes)
+                    }
+                    Token::Uint(u) => uint_value(u).and_then(|v| $ty::try_from(v).ok()),
+                    _ => return Err(self.unexpected("int")),
+                };
+                value.ok_or_else(|| self.out_of_range(stringify!($ty)))
+            }
+            pub fn $to(&self) -> Option<$ty> {
+                self.$try_to().ok()
+            }
+        )+
+    };
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -40,6 +138,19 @@ impl Display for Token {
                 write!(f, "]")
             }
             Token::Bytes(b) => write!(f, "{:?}", b),
+            Token::Int(i) => write!(f, "{}", int_to_string(i)),
+            Token::Address(a) => write!(f, "0x{}", hex::encode(a)),
+            Token::Map(m) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Token::Null => write!(f, "null"),
         }
     }
 }
@@ -91,13 +202,13 @@ impl From<usize> for Token {
     }
 }
 impl From<i8> for Token {
-    fn from(u: i8) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: i8) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
     }
 }
 impl From<i16> for Token {
-    fn from(u: i16) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: i16) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
     }
 }
 impl From<crate::core::U256> for Token {
@@ -109,24 +220,42 @@ impl From<crate::core::U256> for Token {
 }
 
 impl From<i32> for Token {
-    fn from(u: i32) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: i32) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
     }
 }
 
 impl From<i64> for Token {
-    fn from(u: i64) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: i64) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
     }
 }
 impl From<i128> for Token {
-    fn from(u: i128) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: i128) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
     }
 }
 impl From<isize> for Token {
-    fn from(u: isize) -> Self {
-        Token::Uint(u.to_be_bytes().to_vec())
+    fn from(i: isize) -> Self {
+        Token::Int(i.to_be_bytes().to_vec())
+    }
+}
+
+impl From<crate::core::I256> for Token {
+    fn from(i: crate::core::I256) -> Self {
+        let mut value = H256::default();
+        i.twos_complement().to_big_endian(&mut value[..]);
+        Token::Int(value.0.to_vec())
+    }
+}
+impl From<ic_web3_rs::types::Address> for Token {
+    fn from(a: ic_web3_rs::types::Address) -> Self {
+        Token::Address(a.as_bytes().to_vec())
+    }
+}
+impl From<BTreeMap<String, Token>> for Token {
+    fn from(m: BTreeMap<String, Token>) -> Self {
+        Token::Map(m)
     }
 }
 
@@ -186,85 +315,103 @@ impl Token {
                 s
             }
             Token::Bytes(b) => format!("{:?}", b),
+            Token::Int(_) | Token::Address(_) | Token::Map(_) | Token::Null => format!("{}", self),
         }
     }
-    pub fn to_usize(&self) -> Option<usize> {
+    fn kind(&self) -> &'static str {
         match self {
-            Token::Uint(u) => Some(usize::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
+            Token::String(_) => "string",
+            Token::Uint(_) => "uint",
+            Token::Bool(_) => "bool",
+            Token::Array(_) => "array",
+            Token::Bytes(_) => "bytes",
+            Token::Int(_) => "int",
+            Token::Address(_) => "address",
+            Token::Map(_) => "map",
+            Token::Null => "null",
         }
     }
-    pub fn to_u128(&self) -> Option<u128> {
-        match self {
-            Token::Uint(u) => Some(u128::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
+    pub(crate) fn unexpected(&self, expected: &'static str) -> StorageError {
+        StorageError::UnexpectedToken {
+            expected,
+            found: self.kind(),
         }
     }
-    pub fn to_u64(&self) -> Option<u64> {
-        match self {
-            Token::Uint(u) => Some(u64::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
+    fn out_of_range(&self, target: &'static str) -> StorageError {
+        StorageError::OutOfRange {
+            value: format!("{}", self),
+            target,
         }
     }
-    pub fn to_u32(&self) -> Option<u32> {
-        match self {
-            Token::Uint(u) => Some(u32::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
+    uint_accessors!(
+        u8: try_to_u8, to_u8;
+        u16: try_to_u16, to_u16;
+        u32: try_to_u32, to_u32;
+        u64: try_to_u64, to_u64;
+        u128: try_to_u128, to_u128;
+        usize: try_to_usize, to_usize
+    );
+    int_accessors!(
+        i8: try_to_i8, to_i8;
+        i16: try_to_i16, to_i16;
+        i32: try_to_i32, to_i32;
+        i64: try_to_i64, to_i64;
+        i128: try_to_i128, to_i128
+    );
+    pub fn try_to_u256(&self) -> Result<crate::core::U256, StorageError> {
+        let bytes = match self {
+            Token::Uint(u) => fit(u, 32, false),
+            Token::Int(i) if !is_negative(i) => fit(i, 32, false),
+            Token::Int(_) => None,
+            _ => return Err(self.unexpected("uint")),
+        };
+        bytes
+            .map(|b| U256::from_big_endian(&b).into())
+            .ok_or_else(|| self.out_of_range("U256"))
     }
-    pub fn to_u16(&self) -> Option<u16> {
-        match self {
-            Token::Uint(u) => Some(u16::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
-    }
-    pub fn to_u8(&self) -> Option<u8> {
-        match self {
-            Token::Uint(u) => Some(u8::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
-    }
-    pub fn to_i128(&self) -> Option<i128> {
-        match self {
-            Token::Uint(u) => Some(i128::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
-    }
-    pub fn to_i64(&self) -> Option<i64> {
+    pub fn to_u256(&self) -> Option<crate::core::U256> {
+        self.try_to_u256().ok()
+    }
+    pub fn try_to_i256(&self) -> Result<crate::core::I256, StorageError> {
+        let bytes = match self {
+            Token::Int(i) => fit(i, 32, true),
+            Token::Uint(u) => fit(u, 32, false).filter(|b| !is_negative(b)),
+            _ => return Err(self.unexpected("int")),
+        };
+        bytes
+            .map(|b| crate::core::I256::from_twos_complement(U256::from_big_endian(&b)))
+            .ok_or_else(|| self.out_of_range("I256"))
+    }
+    pub fn to_i256(&self) -> Option<crate::core::I256> {
+        self.try_to_i256().ok()
+    }
+    pub fn try_to_address(&self) -> Result<ic_web3_rs::types::Address, StorageError> {
         match self {
-            Token::Uint(u) => Some(i64::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
+            Token::Address(a) if a.len() == 20 => Ok(ic_web3_rs::types::Address::from_slice(a)),
+            Token::Address(_) => Err(self.out_of_range("Address")),
+            _ => Err(self.unexpected("address")),
         }
     }
-    pub fn to_i32(&self) -> Option<i32> {
-        match self {
-            Token::Uint(u) => Some(i32::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
+    pub fn to_address(&self) -> Option<ic_web3_rs::types::Address> {
+        self.try_to_address().ok()
     }
-    pub fn to_i16(&self) -> Option<i16> {
+    pub fn to_map(&self) -> Option<&BTreeMap<String, Token>> {
         match self {
-            Token::Uint(u) => Some(i16::from_be_bytes(u.clone().try_into().unwrap())),
+            Token::Map(m) => Some(m),
             _ => None,
         }
     }
-    pub fn to_i8(&self) -> Option<i8> {
-        match self {
-            Token::Uint(u) => Some(i8::from_be_bytes(u.clone().try_into().unwrap())),
-            _ => None,
-        }
+    pub fn is_null(&self) -> bool {
+        matches!(self, Token::Null)
     }
-    pub fn to_u256(&self) -> Option<crate::core::U256> {
+    pub fn try_to_bool(&self) -> Result<bool, StorageError> {
         match self {
-            Token::Uint(u) => Some(primitive_types::U256::from_big_endian(u).into()),
-            _ => None,
+            Token::Bool(b) => Ok(*b),
+            _ => Err(self.unexpected("bool")),
         }
     }
     pub fn to_bool(&self) -> Option<bool> {
-        match self {
-            Token::Bool(b) => Some(*b),
-            _ => None,
-        }
+        self.try_to_bool().ok()
     }
 }
 
@@ -359,4 +506,96 @@ pub mod tests {
         let result = token.to_u256();
         assert_eq!(u, result.unwrap());
     }
+    #[test]
+    fn test_from_negative_int() {
+        let token: Token = (-123_i64).into();
+        assert_eq!(token, Token::Int((-123_i64).to_be_bytes().to_vec()));
+        assert_eq!(token.to_string(), "-123");
+        assert_eq!(token.to_i64(), Some(-123));
+        assert_eq!(Token::from(-1_i8).to_i256().unwrap().value(), "-1");
+    }
+    #[test]
+    fn test_from_address() {
+        let address = ic_web3_rs::types::Address::from_low_u64_be(1);
+        let token: Token = address.into();
+        assert_eq!(
+            token.to_string(),
+            "0x0000000000000000000000000000000000000001"
+        );
+        assert_eq!(token.to_address(), Some(address));
+    }
+    #[test]
+    fn test_map_and_null() {
+        let token = Token::from(std::collections::BTreeMap::from([
+            ("b".to_string(), Token::Null),
+            ("a".to_string(), Token::from(1_u8)),
+        ]));
+        assert_eq!(token.to_string(), "{a: 01, b: null}");
+        assert!(token.to_map().unwrap()["b"].is_null());
+    }
+    #[test]
+    fn test_decode_legacy_token() {
+        #[derive(candid::CandidType)]
+        enum Token {
+            String(String),
+            Uint(Vec<u8>),
+            Bool(bool),
+            Array(Vec<Token>),
+            Bytes(Vec<u8>),
+        }
+        let legacy = Token::Array(vec![
+            Token::String("a".to_string()),
+            Token::Uint((-1_i32).to_be_bytes().to_vec()),
+            Token::Bool(true),
+            Token::Bytes(vec![1]),
+        ]);
+        let bytes = candid::encode_one(legacy).unwrap();
+        let token: super::Token = candid::decode_one(&bytes).unwrap();
+        assert_eq!(
+            token,
+            super::Token::Array(vec![
+                super::Token::from("a"),
+                super::Token::Uint(vec![0xff; 4]),
+                super::Token::Bool(true),
+                super::Token::Bytes(vec![1]),
+            ])
+        );
+        assert_eq!(super::Token::Uint(vec![0xff; 4]).to_i32(), Some(-1));
+    }
+    #[test]
+    fn test_width_agnostic_uint() {
+        let token = Token::from(123_u64);
+        assert_eq!(token.try_to_u128(), Ok(123));
+        assert_eq!(token.try_to_u8(), Ok(123));
+        assert_eq!(Token::Uint(vec![]).try_to_u64(), Ok(0));
+        assert!(Token::Uint(vec![]).to_u256().unwrap().value().is_zero());
+        assert_eq!(Token::Uint(vec![0; 40]).try_to_u16(), Ok(0));
+        assert_eq!(Token::from(5_i32).try_to_u64(), Ok(5));
+    }
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(
+            Token::from(300_u64).try_to_u8(),
+            Err(super::StorageError::OutOfRange {
+                value: "000000000000012C".to_string(),
+                target: "u8",
+            })
+        );
+        assert!(Token::from(-1_i64).try_to_u64().is_err());
+        assert!(Token::from(i64::MIN).try_to_i32().is_err());
+        assert!(Token::Uint(vec![1; 33]).try_to_u256().is_err());
+        assert_eq!(Token::from(-5_i64).try_to_i8(), Ok(-5));
+        assert_eq!(Token::from(-5_i8).try_to_i128(), Ok(-5));
+    }
+    #[test]
+    fn test_unexpected_token() {
+        assert_eq!(
+            Token::from("a").try_to_u64(),
+            Err(super::StorageError::UnexpectedToken {
+                expected: "uint",
+                found: "string",
+            })
+        );
+        assert_eq!(Token::Bool(true).to_u64(), None);
+    }
 }
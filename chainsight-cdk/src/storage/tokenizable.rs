@@ -0,0 +1,302 @@
+use std::{collections::HashMap, str::FromStr};
+
+use super::{Data, StorageError, Token};
+
+/// Conversion of a field of `Persist` types from and to `Token`.
+/// Nested `Persist` types are converted through `Data`, as a map.
+pub trait Tokenizable: Sized {
+    fn to_token(&self) -> Token;
+    fn from_token(token: &Token) -> Result<Self, StorageError>;
+
+    /// Token for a sequence of the type, overridden by u8 to be held as bytes.
+    #[doc(hidden)]
+    fn slice_to_token(items: &[Self]) -> Token {
+        Token::Array(items.iter().map(|i| i.to_token()).collect())
+    }
+    #[doc(hidden)]
+    fn vec_from_token(token: &Token) -> Result<Vec<Self>, StorageError> {
+        match token {
+            Token::Array(items) => items.iter().map(Self::from_token).collect(),
+            _ => Err(token.unexpected("array")),
+        }
+    }
+}
+
+impl Tokenizable for String {
+    fn to_token(&self) -> Token {
+        Token::from(self.clone())
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        Ok(token.to_string())
+    }
+}
+
+impl Tokenizable for bool {
+    fn to_token(&self) -> Token {
+        Token::from(*self)
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        token.try_to_bool()
+    }
+}
+
+macro_rules! int_tokenizable {
+    ($ty: ident, $to: ident) => {
+        impl Tokenizable for $ty {
+            fn to_token(&self) -> Token {
+                Token::from(*self)
+            }
+            fn from_token(token: &Token) -> Result<Self, StorageError> {
+                token.$to()
+            }
+        }
+    };
+}
+int_tokenizable!(u16, try_to_u16);
+int_tokenizable!(u32, try_to_u32);
+int_tokenizable!(u64, try_to_u64);
+int_tokenizable!(u128, try_to_u128);
+int_tokenizable!(usize, try_to_usize);
+int_tokenizable!(i8, try_to_i8);
+int_tokenizable!(i16, try_to_i16);
+int_tokenizable!(i32, try_to_i32);
+int_tokenizable!(i64, try_to_i64);
+int_tokenizable!(i128, try_to_i128);
+
+impl Tokenizable for u8 {
+    fn to_token(&self) -> Token {
+        Token::from(*self)
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        token.try_to_u8()
+    }
+    fn slice_to_token(items: &[Self]) -> Token {
+        Token::from(items)
+    }
+    fn vec_from_token(token: &Token) -> Result<Vec<Self>, StorageError> {
+        match token {
+            Token::Bytes(b) => Ok(b.clone()),
+            _ => Err(token.unexpected("bytes")),
+        }
+    }
+}
+
+impl Tokenizable for crate::core::U256 {
+    fn to_token(&self) -> Token {
+        Token::from(self.clone())
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        token.try_to_u256()
+    }
+}
+
+impl Tokenizable for crate::core::I256 {
+    fn to_token(&self) -> Token {
+        Token::from(self.clone())
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        match token {
+            // held as a decimal string before Int was introduced
+            Token::String(s) => Self::from_str(s).map_err(|_| token.unexpected("int")),
+            _ => token.try_to_i256(),
+        }
+    }
+}
+
+impl Tokenizable for ic_web3_rs::types::Address {
+    fn to_token(&self) -> Token {
+        Token::from(*self)
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        match token {
+            // held as a hex string before Address was introduced
+            Token::String(s) => Self::from_str(s).map_err(|_| token.unexpected("address")),
+            _ => token.try_to_address(),
+        }
+    }
+}
+
+impl<T: Tokenizable> Tokenizable for Vec<T> {
+    fn to_token(&self) -> Token {
+        T::slice_to_token(self)
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        T::vec_from_token(token)
+    }
+}
+
+impl<T: Tokenizable, const N: usize> Tokenizable for [T; N] {
+    fn to_token(&self) -> Token {
+        T::slice_to_token(self)
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        let items = T::vec_from_token(token)?;
+        let len = items.len();
+        items.try_into().map_err(|_| StorageError::OutOfRange {
+            value: format!("{} items", len),
+            target: std::any::type_name::<Self>(),
+        })
+    }
+}
+
+/// `None` is null and `Some` is an array of the value, so that nested options are not ambiguous.
+impl<T: Tokenizable> Tokenizable for Option<T> {
+    fn to_token(&self) -> Token {
+        match self {
+            Some(v) => Token::Array(vec![v.to_token()]),
+            None => Token::Null,
+        }
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        match token {
+            Token::Null => Ok(None),
+            Token::Array(items) => match items.as_slice() {
+                // None was an empty array before Null was introduced
+                [] => Ok(None),
+                [item] => T::from_token(item).map(Some),
+                _ => Err(token.unexpected("option")),
+            },
+            _ => Err(token.unexpected("option")),
+        }
+    }
+}
+
+macro_rules! tuple_tokenizable {
+    ($len: expr, $($ty: ident : $idx: tt),+) => {
+        impl<$($ty: Tokenizable),+> Tokenizable for ($($ty,)+) {
+            fn to_token(&self) -> Token {
+                Token::Array(vec![$(self.$idx.to_token()),+])
+            }
+            fn from_token(token: &Token) -> Result<Self, StorageError> {
+                match token {
+                    Token::Array(items) if items.len() == $len => {
+                        Ok(($($ty::from_token(&items[$idx])?,)+))
+                    }
+                    _ => Err(token.unexpected("tuple")),
+                }
+            }
+        }
+    };
+}
+tuple_tokenizable!(1, A: 0);
+tuple_tokenizable!(2, A: 0, B: 1);
+tuple_tokenizable!(3, A: 0, B: 1, C: 2);
+tuple_tokenizable!(4, A: 0, B: 1, C: 2, D: 3);
+tuple_tokenizable!(5, A: 0, B: 1, C: 2, D: 3, E: 4);
+tuple_tokenizable!(6, A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+
+impl Tokenizable for Data {
+    fn to_token(&self) -> Token {
+        Token::Map(
+            self.entries()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        )
+    }
+    fn from_token(token: &Token) -> Result<Self, StorageError> {
+        match token {
+            Token::Map(m) => Ok(Data::new(
+                m.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            )),
+            // an array of key and value pairs before Map was introduced
+            Token::Array(entries) => {
+                let mut data = HashMap::new();
+                for entry in entries {
+                    match entry {
+                        Token::Array(pair) => match pair.as_slice() {
+                            [Token::String(k), v] => {
+                                data.insert(k.clone(), v.clone());
+                            }
+                            _ => return Err(token.unexpected("map")),
+                        },
+                        _ => return Err(token.unexpected("map")),
+                    }
+                }
+                Ok(Data::new(data))
+            }
+            _ => Err(token.unexpected("map")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Tokenizable + PartialEq + std::fmt::Debug>(value: T) -> Token {
+        let token = value.to_token();
+        assert_eq!(T::from_token(&token), Ok(value));
+        token
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(round_trip("hello".to_string()), Token::from("hello"));
+        assert_eq!(round_trip(1u64), Token::from(1u64));
+        assert_eq!(round_trip(-1i32), Token::from(-1i32));
+        assert_eq!(round_trip(true), Token::Bool(true));
+        round_trip(crate::core::U256::from(primitive_types::U256::from(7)));
+        round_trip(crate::core::I256::from(-7i128));
+        round_trip(ic_web3_rs::types::Address::from_low_u64_be(1));
+    }
+
+    #[test]
+    fn test_collections() {
+        assert_eq!(round_trip(vec![1u8, 2]), Token::Bytes(vec![1, 2]));
+        assert_eq!(round_trip([1u8, 2]), Token::Bytes(vec![1, 2]));
+        assert_eq!(
+            round_trip(vec![1u64, 2]),
+            Token::Array(vec![Token::from(1u64), Token::from(2u64)])
+        );
+        round_trip(vec![vec!["a".to_string()], vec![]]);
+        round_trip((1u32, "a".to_string(), false));
+        assert_eq!(round_trip(None::<u64>), Token::Null);
+        round_trip(Some(vec![1u16]));
+        round_trip(Some(None::<u16>));
+        assert!(<[u8; 3]>::from_token(&Token::Bytes(vec![1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_data() {
+        let data = Data::new(HashMap::from([
+            ("b".to_string(), Token::from(1u64)),
+            ("a".to_string(), Token::from("x")),
+        ]));
+        let token = data.to_token();
+        assert_eq!(
+            token,
+            Token::Map(std::collections::BTreeMap::from([
+                ("a".to_string(), Token::from("x")),
+                ("b".to_string(), Token::from(1u64)),
+            ]))
+        );
+        let restored = Data::from_token(&token).unwrap();
+        assert_eq!(restored.get("b"), Some(&Token::from(1u64)));
+        assert!(Data::from_token(&Token::from("a")).is_err());
+    }
+
+    #[test]
+    fn test_legacy_formats() {
+        let legacy_data =
+            Token::Array(vec![Token::Array(vec![Token::from("a"), Token::from("x")])]);
+        assert_eq!(
+            Data::from_token(&legacy_data).unwrap().get("a"),
+            Some(&Token::from("x"))
+        );
+        assert_eq!(Option::<u64>::from_token(&Token::Array(vec![])), Ok(None));
+        assert_eq!(
+            i64::from_token(&Token::Uint((-3i64).to_be_bytes().to_vec())),
+            Ok(-3)
+        );
+        assert_eq!(
+            crate::core::I256::from_token(&Token::from("-7")),
+            Ok(crate::core::I256::from(-7i128))
+        );
+        assert_eq!(
+            ic_web3_rs::types::Address::from_token(&Token::from(
+                "0x0000000000000000000000000000000000000001"
+            )),
+            Ok(ic_web3_rs::types::Address::from_low_u64_be(1))
+        );
+    }
+}
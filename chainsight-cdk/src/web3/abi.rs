@@ -1,4 +1,4 @@
-use crate::core::U256 as ChainsightU256;
+use crate::core::{I256 as ChainsightI256, U256 as ChainsightU256};
 use ethabi::{Bytes, Token};
 use primitive_types::U256;
 
@@ -59,6 +59,12 @@ impl Encoder<ChainsightU256> for EthAbiEncoder {
         ethabi::encode(&[token])
     }
 }
+impl Encoder<ChainsightI256> for EthAbiEncoder {
+    fn encode(&self, val: ChainsightI256) -> Bytes {
+        let token = Token::Int(val.twos_complement());
+        ethabi::encode(&[token])
+    }
+}
 
 macro_rules! scalar_type_ethabi_encodable {
     ($scalar_ty: ident, $token_ty: ident) => {
@@ -306,4 +312,20 @@ mod tests {
             .unwrap();
         assert_eq!(decoded, U256::from_dec_str("12345678901234567890").unwrap());
     }
+    #[test]
+    fn test_encode_chainsight_i256() {
+        let encoder = EthAbiEncoder;
+        let expected = ChainsightI256::from(-12345678901234567890i128);
+        let encoded = encoder.encode(expected.clone());
+        let decoded = ethabi::decode(&[ethabi::ParamType::Int(256)], &encoded).unwrap()[0]
+            .clone()
+            .into_int()
+            .unwrap();
+        assert_eq!(decoded, U256::MAX - U256::from(12345678901234567889u128));
+        assert_eq!(ChainsightI256::from_twos_complement(decoded), expected);
+        assert_eq!(
+            ChainsightI256::from_twos_complement(U256::from(1)).value(),
+            "1"
+        );
+    }
 }
@@ -0,0 +1,184 @@
+use candid::CandidType;
+use ic_web3_rs::{contract::Options, types::U256};
+use serde::{Deserialize, Serialize};
+
+/// Length of the rolling window of the daily budget in seconds.
+pub const GAS_BUDGET_WINDOW_SECS: u64 = 24 * 60 * 60;
+/// Gas limit assumed for the worst-case cost of transactions sent without one.
+pub const DEFAULT_GAS_LIMIT: u128 = 1_000_000;
+
+/// Fee in wei a transaction pays at most: its gas limit at its maximum fee per gas.
+pub fn worst_case_fee(
+    gas: Option<u128>,
+    max_fee_per_gas: Option<u128>,
+    gas_price: Option<u128>,
+) -> u128 {
+    gas.unwrap_or(DEFAULT_GAS_LIMIT)
+        .saturating_mul(max_fee_per_gas.or(gas_price).unwrap_or_default())
+}
+
+/// Limits on the gas spent by relays to a destination.
+/// The daily budget is shared by all relays of the sender on the chain.
+/// Unlike the caps of `EIP1559FeeConfig`, which clamp the fees, relays are skipped when these are exceeded.
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GasBudget {
+    /// Optional: Hard cap for the gas price in wei, compared with maxFeePerGas for EIP-1559 transactions
+    pub max_gas_price: Option<u128>,
+    /// Optional: Hard cap for maxPriorityFeePerGas in wei
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// Optional: Fees in wei that may be paid within the last 24 hours
+    pub daily_budget: Option<u128>,
+    /// Optional: Gas limit of relayed transactions, bounding their worst-case cost against the daily budget
+    pub gas_limit: Option<u128>,
+}
+
+impl GasBudget {
+    /// Checks the options of a transaction against the caps, and its worst-case fee
+    /// added to the fees spent within the window against the daily budget.
+    /// Returns the reason to skip the transaction if any limit is exceeded.
+    pub fn check(&self, options: &Options, spent: u128) -> Result<(), String> {
+        if let Some(cap) = self.max_gas_price {
+            let price = options.max_fee_per_gas.or(options.gas_price);
+            if let Some(price) = price.filter(|p| *p > U256::from(cap)) {
+                return Err(format!("gas price {} exceeds the cap {}", price, cap));
+            }
+        }
+        if let Some(cap) = self.max_priority_fee_per_gas {
+            if let Some(fee) = options
+                .max_priority_fee_per_gas
+                .filter(|f| *f > U256::from(cap))
+            {
+                return Err(format!("priority fee {} exceeds the cap {}", fee, cap));
+            }
+        }
+        if let Some(budget) = self.daily_budget {
+            let fee = worst_case_fee(
+                options
+                    .gas
+                    .or(self.gas_limit.map(U256::from))
+                    .map(|v| v.as_u128()),
+                options.max_fee_per_gas.map(|v| v.as_u128()),
+                options.gas_price.map(|v| v.as_u128()),
+            );
+            if spent.saturating_add(fee) > budget {
+                return Err(format!(
+                    "{} wei on top of {} spent within 24 hours exceeds the budget {}",
+                    fee, spent, budget
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the gas limit of the options if the budget has one and the options don't.
+    pub fn limit_gas(&self, options: Options) -> Options {
+        Options {
+            gas: options.gas.or(self.gas_limit.map(U256::from)),
+            ..options
+        }
+    }
+
+    pub fn state(
+        &self,
+        chain_id: u64,
+        sender: String,
+        contract: String,
+        spent: u128,
+    ) -> GasBudgetState {
+        GasBudgetState {
+            chain_id,
+            sender,
+            contract,
+            budget: self.clone(),
+            spent,
+            remaining: self.daily_budget.map(|b| b.saturating_sub(spent)),
+        }
+    }
+}
+
+/// Gas spent by relays of the sender on the chain within the rolling window, against the budget of a destination.
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct GasBudgetState {
+    pub chain_id: u64,
+    pub sender: String,
+    pub contract: String,
+    pub budget: GasBudget,
+    /// Fees in wei of transactions sent within the last 24 hours, the worst-case ones for those not mined yet
+    pub spent: u128,
+    pub remaining: Option<u128>,
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_web3_rs::types::U64;
+
+    use super::*;
+
+    #[test]
+    fn test_check_caps() {
+        let budget = GasBudget {
+            max_gas_price: Some(100),
+            max_priority_fee_per_gas: Some(10),
+            daily_budget: None,
+            gas_limit: None,
+        };
+        let eip1559 = |max_fee: u64, priority: u64| {
+            Options::with(|op| {
+                op.max_fee_per_gas = Some(U256::from(max_fee));
+                op.max_priority_fee_per_gas = Some(U256::from(priority));
+                op.transaction_type = Some(U64::from(2));
+            })
+        };
+        assert!(budget.check(&eip1559(100, 10), 0).is_ok());
+        assert!(budget.check(&eip1559(101, 10), 0).is_err());
+        assert!(budget.check(&eip1559(100, 11), 0).is_err());
+
+        let legacy = |price: u64| Options::with(|op| op.gas_price = Some(U256::from(price)));
+        assert!(budget.check(&legacy(100), 0).is_ok());
+        assert!(budget.check(&legacy(101), 0).is_err());
+        assert!(GasBudget::default().check(&legacy(u64::MAX), 0).is_ok());
+    }
+
+    #[test]
+    fn test_check_daily_budget() {
+        let budget = GasBudget {
+            daily_budget: Some(1_000),
+            gas_limit: Some(10),
+            ..Default::default()
+        };
+        let legacy = |price: u64| Options::with(|op| op.gas_price = Some(U256::from(price)));
+        // the upcoming transaction counts at its gas limit
+        assert!(budget.check(&legacy(10), 900).is_ok());
+        assert!(budget.check(&legacy(11), 900).is_err());
+        assert!(budget
+            .check(
+                &Options::with(|op| {
+                    op.gas = Some(U256::from(20));
+                    op.gas_price = Some(U256::from(5));
+                }),
+                900
+            )
+            .is_ok());
+        assert!(budget.check(&Options::default(), 1_000).is_ok());
+        assert!(budget.check(&Options::default(), 1_001).is_err());
+        assert_eq!(budget.limit_gas(legacy(1)).gas, Some(U256::from(10)));
+        assert_eq!(worst_case_fee(None, None, Some(2)), 2 * DEFAULT_GAS_LIMIT);
+        assert_eq!(worst_case_fee(Some(3), Some(4), Some(2)), 12);
+
+        let state = budget.state(1, "0x02".to_string(), "0x01".to_string(), 400);
+        assert_eq!(state.remaining, Some(600));
+        assert_eq!(
+            budget
+                .state(1, "0x02".to_string(), "0x01".to_string(), 1_200)
+                .remaining,
+            Some(0)
+        );
+        assert_eq!(
+            GasBudget::default()
+                .state(1, "0x02".to_string(), "0x01".to_string(), 400)
+                .remaining,
+            None
+        );
+    }
+}
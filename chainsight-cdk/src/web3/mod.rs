@@ -5,3 +5,11 @@ pub mod abi;
 pub use abi::*;
 pub mod processors;
 pub use processors::*;
+pub mod nonce;
+pub use nonce::*;
+pub mod relay;
+pub use relay::*;
+pub mod budget;
+pub use budget::*;
+pub mod multicall;
+pub use multicall::*;
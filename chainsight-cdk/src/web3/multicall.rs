@@ -0,0 +1,189 @@
+use ic_web3_rs::{
+    contract::tokens::Detokenize,
+    ethabi::{self, param_type::Reader, ParamType, Token},
+    transports::{ic_http_client::CallOptions, ICHttp},
+    types::{Address, Bytes, CallRequest},
+    Transport,
+};
+use serde_json::json;
+
+/// Address of Multicall3, deployed at the same address on most EVM chains.
+pub const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+/// A call aggregated by `aggregate3` of Multicall3.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call3 {
+    pub target: Address,
+    pub allow_failure: bool,
+    pub call_data: Vec<u8>,
+}
+
+impl Call3 {
+    pub fn new(target: Address, call_data: Vec<u8>) -> Self {
+        Self {
+            target,
+            allow_failure: false,
+            call_data,
+        }
+    }
+}
+
+/// The result of a call aggregated by `aggregate3` of Multicall3.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call3Result {
+    pub success: bool,
+    pub return_data: Vec<u8>,
+}
+
+impl Call3Result {
+    /// Decodes the return data with the output types of the called function, e.g. `["uint256"]`.
+    pub fn decode<T: Detokenize>(&self, output_types: &[&str]) -> anyhow::Result<T> {
+        if !self.success {
+            anyhow::bail!("call failed: 0x{}", hex::encode(&self.return_data));
+        }
+        let kinds = output_types
+            .iter()
+            .map(|t| Reader::read(t))
+            .collect::<Result<Vec<_>, _>>()?;
+        let tokens = ethabi::decode(&kinds, &self.return_data)?;
+        T::from_tokens(tokens).map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+fn call3_kind() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])))
+}
+
+fn result_kind() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])))
+}
+
+/// Encodes the input of `aggregate3((address,bool,bytes)[])`.
+pub fn encode_aggregate3(calls: &[Call3]) -> Vec<u8> {
+    let calls = calls
+        .iter()
+        .map(|c| {
+            Token::Tuple(vec![
+                Token::Address(c.target),
+                Token::Bool(c.allow_failure),
+                Token::Bytes(c.call_data.clone()),
+            ])
+        })
+        .collect();
+    let mut data = ethabi::short_signature("aggregate3", &[call3_kind()]).to_vec();
+    data.extend(ethabi::encode(&[Token::Array(calls)]));
+    data
+}
+
+/// Decodes the output of `aggregate3`, `(bool success, bytes returnData)[]`.
+pub fn decode_aggregate3(data: &[u8]) -> anyhow::Result<Vec<Call3Result>> {
+    let tokens = ethabi::decode(&[result_kind()], data)?;
+    let results = match tokens.into_iter().next() {
+        Some(Token::Array(results)) => results,
+        other => anyhow::bail!("unexpected output of aggregate3: {:?}", other),
+    };
+    results
+        .into_iter()
+        .map(|result| match result {
+            Token::Tuple(fields) => match fields.as_slice() {
+                [Token::Bool(success), Token::Bytes(return_data)] => Ok(Call3Result {
+                    success: *success,
+                    return_data: return_data.clone(),
+                }),
+                _ => anyhow::bail!("unexpected result of aggregate3: {:?}", fields),
+            },
+            other => anyhow::bail!("unexpected result of aggregate3: {:?}", other),
+        })
+        .collect()
+}
+
+/// Executes the calls in a single `eth_call` to `aggregate3` of the Multicall3 at `multicall`.
+/// The results are in the same order as the calls.
+pub async fn aggregate3(
+    url: &str,
+    multicall: Address,
+    calls: &[Call3],
+    call_options: CallOptions,
+) -> anyhow::Result<Vec<Call3Result>> {
+    let request = CallRequest {
+        to: Some(multicall),
+        data: Some(Bytes(encode_aggregate3(calls))),
+        ..Default::default()
+    };
+    let transport = ICHttp::new(url, None)?;
+    let result = transport
+        .execute(
+            "eth_call",
+            vec![serde_json::to_value(request)?, json!("latest")],
+            call_options,
+        )
+        .await?;
+    let data: Bytes = serde_json::from_value(result)?;
+    let results = decode_aggregate3(&data.0)?;
+    if results.len() != calls.len() {
+        anyhow::bail!(
+            "aggregate3 returned {} results for {} calls",
+            results.len(),
+            calls.len()
+        );
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ic_web3_rs::types::U256;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_aggregate3() {
+        let target = Address::from_str("0x0000000000000000000000000000000000000001").unwrap();
+        let data = encode_aggregate3(&[Call3::new(target, vec![0x18, 0x16, 0x0d, 0xdd])]);
+        assert_eq!(hex::encode(&data[..4]), "82ad56cb");
+        let decoded = ethabi::decode(&[call3_kind()], &data[4..]).unwrap();
+        assert_eq!(
+            decoded,
+            vec![Token::Array(vec![Token::Tuple(vec![
+                Token::Address(target),
+                Token::Bool(false),
+                Token::Bytes(vec![0x18, 0x16, 0x0d, 0xdd]),
+            ])])]
+        );
+    }
+
+    #[test]
+    fn test_decode_aggregate3() {
+        let output = ethabi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Bytes(ethabi::encode(&[Token::Uint(U256::from(100))])),
+            ]),
+            Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Bytes(ethabi::encode(&[
+                    Token::Uint(U256::from(1)),
+                    Token::String("chainsight".to_string()),
+                ])),
+            ]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+        let results = decode_aggregate3(&output).unwrap();
+        assert_eq!(results.len(), 3);
+
+        let first: U256 = results[0].decode(&["uint256"]).unwrap();
+        assert_eq!(first, U256::from(100));
+        let second: (u64, String) = results[1].decode(&["uint64", "string"]).unwrap();
+        assert_eq!(second, (1, "chainsight".to_string()));
+        assert!(results[2].decode::<U256>(&["uint256"]).is_err());
+    }
+}
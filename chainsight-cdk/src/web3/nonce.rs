@@ -0,0 +1,335 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::CandidType;
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use ic_web3_rs::types::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::time::TimeStamper;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub type NonceStore = StableBTreeMap<NonceKey, NonceState, Memory>;
+
+const DEFAULT_GAP_TIMEOUT_SECS: u64 = 600;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NonceKey {
+    pub chain_id: u64,
+    pub sender: Address,
+}
+impl Storable for NonceKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.chain_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.sender.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            chain_id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            sender: Address::from_slice(&bytes[8..]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 28,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct NonceState {
+    /// Next nonce to reserve
+    pub next: u64,
+    /// Pending transaction count of the sender last seen
+    pub pending: u64,
+    /// Number of reserved nonces neither committed nor released yet
+    pub in_flight: u64,
+    /// Time of the last confirmation or resync in seconds
+    pub updated_at: u64,
+}
+impl Storable for NonceState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.next.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.pending.to_be_bytes());
+        bytes.extend_from_slice(&self.in_flight.to_be_bytes());
+        bytes.extend_from_slice(&self.updated_at.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let field = |i: usize| u64::from_be_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        Self {
+            next: field(0),
+            pending: field(1),
+            in_flight: field(2),
+            updated_at: field(3),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// Reserves nonces locally so that overlapping transactions from the same sender don't collide.
+#[derive(Clone, Copy)]
+pub struct NonceManager {
+    store: &'static std::thread::LocalKey<RefCell<NonceStore>>,
+    gap_timeout_secs: u64,
+}
+
+impl NonceManager {
+    pub fn new(store: &'static std::thread::LocalKey<RefCell<NonceStore>>) -> Self {
+        Self {
+            store,
+            gap_timeout_secs: DEFAULT_GAP_TIMEOUT_SECS,
+        }
+    }
+    /// Sets how long the pending count may make no progress before resyncing,
+    /// e.g. when a reservation was never committed nor released.
+    pub fn with_gap_timeout(mut self, secs: u64) -> Self {
+        self.gap_timeout_secs = secs;
+        self
+    }
+
+    /// Reserves the next nonce given the pending transaction count of the sender, right before signing.
+    /// Committed nonces the pending count doesn't include yet stay reserved, as the node may lag behind.
+    /// The local nonce is resynced to the pending count when the chain is ahead of it,
+    /// and back below it only when the pending count made no progress within the gap timeout,
+    /// e.g. as a sent transaction was dropped.
+    /// The nonce is released when the reservation is dropped without being committed.
+    pub fn reserve(&self, chain_id: u64, sender: Address, pending: u64) -> NonceReservation {
+        self.reserve_at(chain_id, sender, pending, TimeStamper::now_sec())
+    }
+
+    fn reserve_at(
+        &self,
+        chain_id: u64,
+        sender: Address,
+        pending: u64,
+        now: u64,
+    ) -> NonceReservation {
+        let key = NonceKey { chain_id, sender };
+        let nonce = self.store.with(|m| {
+            let mut m = m.borrow_mut();
+            let state = m.get(&key).unwrap_or(NonceState {
+                next: pending,
+                pending,
+                in_flight: 0,
+                updated_at: now,
+            });
+            // only confirmations keep the gap timeout from expiring, not reservations
+            let updated_at = if pending > state.pending {
+                now
+            } else {
+                state.updated_at
+            };
+            let timed_out = now >= updated_at + self.gap_timeout_secs;
+            let state = if state.next <= pending || timed_out {
+                NonceState {
+                    next: pending,
+                    pending,
+                    in_flight: if timed_out { 0 } else { state.in_flight },
+                    updated_at: now,
+                }
+            } else {
+                NonceState {
+                    pending,
+                    updated_at,
+                    ..state
+                }
+            };
+            let nonce = state.next;
+            m.insert(
+                key,
+                NonceState {
+                    next: nonce + 1,
+                    in_flight: state.in_flight + 1,
+                    ..state
+                },
+            );
+            nonce
+        });
+        NonceReservation {
+            manager: *self,
+            chain_id,
+            sender,
+            nonce,
+            committed: false,
+        }
+    }
+
+    pub fn get(&self, chain_id: u64, sender: Address) -> Option<NonceState> {
+        self.store
+            .with(|m| m.borrow().get(&NonceKey { chain_id, sender }))
+    }
+
+    fn update(&self, chain_id: u64, sender: Address, f: impl FnOnce(&mut NonceState)) {
+        let key = NonceKey { chain_id, sender };
+        self.store.with(|m| {
+            let mut m = m.borrow_mut();
+            if let Some(mut state) = m.get(&key) {
+                f(&mut state);
+                m.insert(key, state);
+            }
+        })
+    }
+
+    /// Marks a reserved nonce as sent, so that it is expected in the pending count.
+    pub fn commit(&self, chain_id: u64, sender: Address) {
+        self.update(chain_id, sender, |state| {
+            state.in_flight = state.in_flight.saturating_sub(1)
+        });
+    }
+
+    /// Gives back a reserved nonce which will not be used.
+    /// The local nonce goes back to it if no later nonce was reserved since,
+    /// and is resynced on the next reservation otherwise.
+    pub fn release(&self, chain_id: u64, sender: Address, nonce: u64) {
+        self.update(chain_id, sender, |state| {
+            state.in_flight = state.in_flight.saturating_sub(1);
+            if state.next == nonce + 1 {
+                state.next = nonce;
+            }
+        });
+    }
+
+    /// Forgets the local nonce so that the next reservation starts from the pending count.
+    pub fn reset(&self, chain_id: u64, sender: Address) {
+        self.store.with(|m| {
+            m.borrow_mut().remove(&NonceKey { chain_id, sender });
+        })
+    }
+}
+
+/// Nonce reserved by `NonceManager::reserve`, released on drop unless committed once the transaction is sent.
+#[must_use]
+pub struct NonceReservation {
+    manager: NonceManager,
+    chain_id: u64,
+    sender: Address,
+    nonce: u64,
+    committed: bool,
+}
+
+impl NonceReservation {
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn commit(mut self) {
+        self.manager.commit(self.chain_id, self.sender);
+        self.committed = true;
+    }
+}
+
+impl Drop for NonceReservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.manager.release(self.chain_id, self.sender, self.nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    use super::*;
+
+    thread_local! {
+        static NONCES: RefCell<NonceStore> = RefCell::new(StableBTreeMap::init(
+            MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+        ));
+    }
+
+    fn sent(reservation: NonceReservation) -> u64 {
+        let nonce = reservation.nonce();
+        reservation.commit();
+        nonce
+    }
+
+    #[test]
+    fn test_reserve() {
+        let manager = NonceManager::new(&NONCES);
+        let sender = Address::from_low_u64_be(1);
+        // overlapping relays before the first transaction is sent
+        let first = manager.reserve(1, sender, 5);
+        let second = manager.reserve(1, sender, 5);
+        assert_eq!((first.nonce(), second.nonce()), (5, 6));
+        assert_eq!(sent(first), 5);
+        assert_eq!(sent(second), 6);
+        assert_eq!(sent(manager.reserve(1, sender, 7)), 7);
+        // other chains and senders are independent
+        assert_eq!(sent(manager.reserve(2, sender, 0)), 0);
+        assert_eq!(sent(manager.reserve(1, Address::from_low_u64_be(2), 3)), 3);
+        // resync when the chain is ahead
+        assert_eq!(sent(manager.reserve(1, sender, 10)), 10);
+        assert_eq!(manager.get(1, sender).unwrap().next, 11);
+        assert_eq!(manager.get(1, sender).unwrap().in_flight, 0);
+    }
+
+    #[test]
+    fn test_reserve_keeps_unconfirmed_nonces() {
+        let manager = NonceManager::new(&NONCES);
+        let sender = Address::from_low_u64_be(1);
+        assert_eq!(sent(manager.reserve(1, sender, 5)), 5);
+        assert_eq!(sent(manager.reserve(1, sender, 6)), 6);
+        // the node doesn't report the transaction with nonce 6 yet
+        assert_eq!(sent(manager.reserve(1, sender, 6)), 7);
+        assert_eq!(manager.get(1, sender).unwrap().in_flight, 0);
+    }
+
+    #[test]
+    fn test_reserve_resyncs_on_dropped_transaction() {
+        let manager = NonceManager::new(&NONCES).with_gap_timeout(600);
+        let sender = Address::from_low_u64_be(1);
+        assert_eq!(sent(manager.reserve_at(1, sender, 5, 1000)), 5);
+        // the transaction with nonce 5 never reached the mempool
+        assert_eq!(sent(manager.reserve_at(1, sender, 5, 1100)), 6);
+        assert_eq!(sent(manager.reserve_at(1, sender, 5, 1600)), 5);
+    }
+
+    #[test]
+    fn test_reserve_resyncs_on_gap_timeout() {
+        let manager = NonceManager::new(&NONCES).with_gap_timeout(600);
+        let sender = Address::from_low_u64_be(1);
+        // a reservation neither committed nor released, e.g. lost in a trap
+        std::mem::forget(manager.reserve_at(1, sender, 5, 1000));
+        // frequent reservations don't keep the gap open
+        for now in [1100, 1200, 1300] {
+            assert_eq!(manager.reserve_at(1, sender, 5, now).nonce(), 6);
+        }
+        assert_eq!(manager.reserve_at(1, sender, 5, 1600).nonce(), 5);
+    }
+
+    #[test]
+    fn test_reset() {
+        let manager = NonceManager::new(&NONCES);
+        let sender = Address::from_low_u64_be(1);
+        let _first = manager.reserve(1, sender, 5);
+        assert_eq!(manager.reserve(1, sender, 5).nonce(), 6);
+        manager.reset(1, sender);
+        assert_eq!(manager.get(1, sender), None);
+        assert_eq!(manager.reserve(1, sender, 5).nonce(), 5);
+    }
+
+    #[test]
+    fn test_release_on_drop() {
+        let manager = NonceManager::new(&NONCES);
+        let sender = Address::from_low_u64_be(1);
+        let first = manager.reserve(1, sender, 5);
+        let second = manager.reserve(1, sender, 5);
+        drop(second);
+        let third = manager.reserve(1, sender, 5);
+        assert_eq!(third.nonce(), 6);
+        // a nonce followed by later reservations leaves a gap, resynced on the gap timeout
+        drop(first);
+        assert_eq!(sent(third), 6);
+        assert_eq!(manager.reserve(1, sender, 5).nonce(), 7);
+    }
+}
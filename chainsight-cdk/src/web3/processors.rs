@@ -10,14 +10,17 @@ use ic_web3_rs::{
         ic_http_client::{CallOptions, CallOptionsBuilder},
         ICHttp,
     },
-    types::{Address, U64},
+    types::{Address, BlockNumber, FeeHistory, U256, U64},
     Transport, Web3,
 };
 use std::{future::Future, ops::Add};
 
+use super::{NonceManager, NonceReservation};
+
 use serde_json::{json, Value};
 
 const RPC_CALL_MAX_RETRY: u32 = 5;
+const BLOCK_NUMBER_ROUNDING: u64 = 10;
 #[async_trait]
 pub trait TransactionOptionBuilder {
     async fn build(&self) -> anyhow::Result<Option<Options>>;
@@ -28,6 +31,70 @@ pub struct EVMTransactionOptionBuilder {
     pub chain_id: u64,
     pub key_name: String,
     pub sender_address: Option<Address>,
+    pub fee_config: EIP1559FeeConfig,
+    pub nonce_manager: Option<NonceManager>,
+    pub nonce: Option<u64>,
+}
+
+/// Parameters to derive EIP-1559 fees from `eth_feeHistory`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EIP1559FeeConfig {
+    /// Number of recent blocks to sample
+    pub block_count: u64,
+    /// Percentile of the priority fees paid in each sampled block
+    pub reward_percentile: f64,
+    /// Multiplier applied to the next base fee to absorb base fee increases
+    pub base_fee_multiplier: f64,
+    /// Optional: Hard cap for maxFeePerGas in wei
+    pub max_fee_per_gas: Option<u128>,
+    /// Optional: Hard cap for maxPriorityFeePerGas in wei
+    pub max_priority_fee_per_gas: Option<u128>,
+}
+impl Default for EIP1559FeeConfig {
+    fn default() -> Self {
+        Self {
+            block_count: 10,
+            reward_percentile: 50.0,
+            base_fee_multiplier: 2.0,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+        }
+    }
+}
+impl EIP1559FeeConfig {
+    /// Returns `(max_fee_per_gas, max_priority_fee_per_gas)` for the given fee history.
+    /// The max fee is derived from the capped priority fee, and fails when its cap is below
+    /// the next base fee, as the transaction could not be included.
+    pub fn fees(&self, history: &FeeHistory) -> anyhow::Result<(U256, U256)> {
+        let base_fee = history.base_fee_per_gas.last().cloned().unwrap_or_default();
+        let mut rewards: Vec<U256> = history
+            .reward
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|r| r.first().cloned())
+            .collect();
+        rewards.sort();
+        let mut priority_fee = rewards.get(rewards.len() / 2).cloned().unwrap_or_default();
+        if let Some(cap) = self.max_priority_fee_per_gas {
+            priority_fee = priority_fee.min(U256::from(cap));
+        }
+        // per-mille precision is enough for a multiplier
+        let multiplier = U256::from((self.base_fee_multiplier * 1000.0).round() as u64);
+        let mut max_fee = base_fee * multiplier / 1000 + priority_fee;
+        if let Some(cap) = self.max_fee_per_gas {
+            if U256::from(cap) < base_fee {
+                anyhow::bail!(
+                    "max fee per gas cap {} is below the base fee {}",
+                    cap,
+                    base_fee
+                );
+            }
+            max_fee = max_fee.min(U256::from(cap));
+        }
+        Ok((max_fee, priority_fee.min(max_fee)))
+    }
 }
 
 impl EVMTransactionOptionBuilder {
@@ -37,6 +104,9 @@ impl EVMTransactionOptionBuilder {
             chain_id,
             key_name,
             sender_address: None,
+            fee_config: EIP1559FeeConfig::default(),
+            nonce_manager: None,
+            nonce: None,
         }
     }
     pub fn new_with_transport_and_address(
@@ -50,8 +120,29 @@ impl EVMTransactionOptionBuilder {
             chain_id,
             key_name,
             sender_address: Some(sender_address),
+            fee_config: EIP1559FeeConfig::default(),
+            nonce_manager: None,
+            nonce: None,
         }
     }
+    pub fn with_fee_config(mut self, fee_config: EIP1559FeeConfig) -> Self {
+        self.fee_config = fee_config;
+        self
+    }
+    /// Uses the given sender instead of deriving it from the public key.
+    pub fn with_sender_address(mut self, sender_address: Address) -> Self {
+        self.sender_address = Some(sender_address);
+        self
+    }
+    pub fn with_nonce_manager(mut self, nonce_manager: NonceManager) -> Self {
+        self.nonce_manager = Some(nonce_manager);
+        self
+    }
+    /// Uses the given nonce instead of fetching or reserving one, e.g. to replace a stuck transaction.
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
 
     async fn with_retry<T, E, Fut, F: FnMut() -> Fut>(&self, mut f: F) -> Result<T, E>
     where
@@ -105,6 +196,48 @@ impl EVMTransactionOptionBuilder {
         web3.eth()
     }
 
+    /// Nonce to put in the options, left unset when it is reserved with `reserve_nonce` right before signing.
+    async fn nonce(&self, ethereum_address: Address) -> anyhow::Result<Option<U256>> {
+        if let Some(nonce) = self.nonce {
+            return Ok(Some(U256::from(nonce)));
+        }
+        if self.nonce_manager.is_some() {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.with_retry(|| {
+                self.eth()
+                    .transaction_count(ethereum_address, None, CallOptions::default())
+            })
+            .await?,
+        ))
+    }
+
+    /// Reserves a nonce from the nonce manager, if any and no nonce is given.
+    /// Call it last before sending, and commit the reservation once the transaction is sent:
+    /// it is released when dropped otherwise.
+    pub async fn reserve_nonce(&self) -> anyhow::Result<Option<NonceReservation>> {
+        let nonce_manager = match &self.nonce_manager {
+            Some(nonce_manager) if self.nonce.is_none() => nonce_manager,
+            _ => return Ok(None),
+        };
+        let ethereum_address = self.get_ethereum_address().await?;
+        let pending = self
+            .with_retry(|| {
+                self.eth().transaction_count(
+                    ethereum_address,
+                    Some(BlockNumber::Pending),
+                    CallOptions::default(),
+                )
+            })
+            .await?;
+        Ok(Some(nonce_manager.reserve(
+            self.chain_id,
+            ethereum_address,
+            pending.as_u64(),
+        )))
+    }
+
     async fn get_ethereum_address(&self) -> anyhow::Result<Address> {
         if let Some(address) = self.sender_address {
             return Ok(address);
@@ -125,22 +258,33 @@ impl EVMTransactionOptionBuilder {
 impl TransactionOptionBuilder for EVMTransactionOptionBuilder {
     async fn build(&self) -> anyhow::Result<Option<Options>> {
         let supports_eip1559 = self.supports_eip1559().await?;
+        let ethereum_address = self.get_ethereum_address().await?;
+        let nonce = self.nonce(ethereum_address).await?;
         if supports_eip1559 {
-            return Ok(None);
+            let history = self
+                .with_retry(|| {
+                    self.eth().fee_history(
+                        U256::from(self.fee_config.block_count),
+                        BlockNumber::Latest,
+                        Some(vec![self.fee_config.reward_percentile]),
+                        CallOptions::default(),
+                    )
+                })
+                .await?;
+            let (max_fee_per_gas, max_priority_fee_per_gas) = self.fee_config.fees(&history)?;
+            return Ok(Some(Options::with(|op| {
+                op.max_fee_per_gas = Some(max_fee_per_gas);
+                op.max_priority_fee_per_gas = Some(max_priority_fee_per_gas);
+                op.nonce = nonce;
+                op.transaction_type = Some(U64::from(2))
+            })));
         }
         let gas_price = self
             .with_retry(|| self.eth().gas_price(CallOptions::default()))
             .await?;
-        let ethereum_address = self.get_ethereum_address().await?;
-        let nonce = self
-            .with_retry(|| {
-                self.eth()
-                    .transaction_count(ethereum_address, None, CallOptions::default())
-            })
-            .await?;
         Ok(Some(Options::with(|op| {
             op.gas_price = Some(gas_price);
-            op.nonce = Some(nonce);
+            op.nonce = nonce;
             op.transaction_type = Some(U64::from(0))
         })))
     }
@@ -192,6 +336,106 @@ impl TransformProcessor for EIP1559SupportProcessor {
     }
 }
 
+/// Reduces a block to its number and hashes.
+/// Bodies without `result`, e.g. RPC errors, are returned unchanged for the caller to report.
+pub struct BlockHeaderProcessor;
+impl TransformProcessor for BlockHeaderProcessor {
+    fn process_body(&self, body: &[u8]) -> Vec<u8> {
+        let header = match serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|body| body.get("result").cloned())
+        {
+            Some(Value::Object(block)) => json!({
+                "number": block.get("number"),
+                "hash": block.get("hash"),
+                "parentHash": block.get("parentHash"),
+            }),
+            Some(_) => Value::Null,
+            None => return body.to_vec(),
+        };
+        let result: Value = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "result": header
+        });
+        serde_json::to_vec(&result).unwrap()
+    }
+    fn context(&self) -> TransformContext {
+        TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_block_header".to_string(),
+            }),
+            context: vec![],
+        }
+    }
+}
+
+/// Rounds the block number down so that replicas observing slightly different heads agree on the response.
+/// Bodies without a block number, e.g. RPC errors, are returned unchanged for the caller to report.
+pub struct BlockNumberProcessor;
+impl TransformProcessor for BlockNumberProcessor {
+    fn process_body(&self, body: &[u8]) -> Vec<u8> {
+        let number = match serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|body| body.get("result").cloned())
+            .and_then(|v| v.as_str().map(|v| v.to_string()))
+            .and_then(|v| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok())
+        {
+            Some(number) => number,
+            None => return body.to_vec(),
+        };
+        let result: Value = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "result": format!("0x{:x}", number - number % BLOCK_NUMBER_ROUNDING)
+        });
+        serde_json::to_vec(&result).unwrap()
+    }
+    fn context(&self) -> TransformContext {
+        TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_block_number".to_string(),
+            }),
+            context: vec![],
+        }
+    }
+}
+
+/// Reduces a transaction receipt to the fields used to follow relayed transactions.
+pub struct TransactionReceiptProcessor;
+impl TransformProcessor for TransactionReceiptProcessor {
+    fn process_body(&self, body: &[u8]) -> Vec<u8> {
+        let body: Value = serde_json::from_slice(body).unwrap();
+        let receipt = match body.get("result") {
+            Some(Value::Object(receipt)) => json!({
+                "transactionHash": receipt.get("transactionHash"),
+                "blockNumber": receipt.get("blockNumber"),
+                "status": receipt.get("status"),
+                "gasUsed": receipt.get("gasUsed"),
+                "effectiveGasPrice": receipt.get("effectiveGasPrice"),
+            }),
+            _ => Value::Null,
+        };
+        let result: Value = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "result": receipt
+        });
+        serde_json::to_vec(&result).unwrap()
+    }
+    fn context(&self) -> TransformContext {
+        TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_transaction_receipt".to_string(),
+            }),
+            context: vec![],
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,4 +549,207 @@ mod tests {
         .unwrap();
         assert_eq!(target.convert_result(v), true);
     }
+    #[test]
+    fn test_block_header_processor() {
+        let processor = BlockHeaderProcessor;
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(200u8),
+                headers: vec![],
+                body: r#"{
+                    "jsonrpc": "2.0",
+                    "id": 3,
+                    "result": {
+                      "number": "0x1b4",
+                      "gasUsed": "0x0",
+                      "hash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                      "parentHash": "0xe99e022112df268087ea7eafaf4790497fd21dbeeb6bd7a1721df161a6657a54",
+                      "timestamp": "0x55ba467c",
+                      "transactions": [],
+                      "uncles": []
+                    }
+                  }"#.into(),
+            },
+            context: vec![],
+        };
+        let res = processor.transform(raw);
+        assert_eq!(
+            res.body,
+            serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "result": {
+                    "number": "0x1b4",
+                    "hash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                    "parentHash": "0xe99e022112df268087ea7eafaf4790497fd21dbeeb6bd7a1721df161a6657a54"
+                }
+            }))
+            .unwrap()
+        );
+    }
+    #[test]
+    fn test_block_header_processor_not_found() {
+        let processor = BlockHeaderProcessor;
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(200u8),
+                headers: vec![],
+                body: r#"{"jsonrpc": "2.0", "id": 3, "result": null}"#.into(),
+            },
+            context: vec![],
+        };
+        let res = processor.transform(raw);
+        assert_eq!(
+            res.body,
+            serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "result": null
+            }))
+            .unwrap()
+        );
+    }
+    #[test]
+    fn test_transaction_receipt_processor() {
+        let processor = TransactionReceiptProcessor;
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(200u8),
+                headers: vec![],
+                body: r#"{
+                    "jsonrpc": "2.0",
+                    "id": 5,
+                    "result": {
+                      "blockHash": "0xa957d47df264a31badc3ae823e10ac1d444b098d9b73d204c40426e57f47e8c3",
+                      "blockNumber": "0xeff35f",
+                      "cumulativeGasUsed": "0xa12515",
+                      "effectiveGasPrice": "0x5a9c688d4",
+                      "gasUsed": "0xb4c8",
+                      "logs": [],
+                      "status": "0x1",
+                      "transactionHash": "0x85d995eba9763907fdf35cd2034144dd9d53ce32cbec21349d4b12823c6860c5",
+                      "transactionIndex": "0x66",
+                      "type": "0x2"
+                    }
+                  }"#.into(),
+            },
+            context: vec![],
+        };
+        let res = processor.transform(raw);
+        assert_eq!(
+            res.body,
+            serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "result": {
+                    "transactionHash": "0x85d995eba9763907fdf35cd2034144dd9d53ce32cbec21349d4b12823c6860c5",
+                    "blockNumber": "0xeff35f",
+                    "status": "0x1",
+                    "gasUsed": "0xb4c8",
+                    "effectiveGasPrice": "0x5a9c688d4"
+                }
+            }))
+            .unwrap()
+        );
+    }
+    #[test]
+    fn test_block_number_processor() {
+        let processor = BlockNumberProcessor;
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(200u8),
+                headers: vec![],
+                body: r#"{"jsonrpc": "2.0", "id": 7, "result": "0x12d687"}"#.into(),
+            },
+            context: vec![],
+        };
+        let res = processor.transform(raw);
+        assert_eq!(
+            res.body,
+            serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "result": "0x12d680"
+            }))
+            .unwrap()
+        );
+    }
+    #[test]
+    fn test_block_processors_keep_errors() {
+        let error = r#"{"jsonrpc": "2.0", "id": 7, "error": {"code": -32005, "message": "limit exceeded"}}"#;
+        for body in [error, "rate limited"] {
+            let raw = TransformArgs {
+                response: HttpResponse {
+                    status: candid::Nat::from(200u8),
+                    headers: vec![],
+                    body: body.into(),
+                },
+                context: vec![],
+            };
+            assert_eq!(
+                BlockHeaderProcessor.transform(raw.clone()).body,
+                body.as_bytes()
+            );
+            assert_eq!(BlockNumberProcessor.transform(raw).body, body.as_bytes());
+        }
+    }
+    fn fee_history(base_fees: Vec<u64>, rewards: Vec<u64>) -> FeeHistory {
+        FeeHistory {
+            oldest_block: BlockNumber::Number(1.into()),
+            base_fee_per_gas: base_fees.into_iter().map(U256::from).collect(),
+            gas_used_ratio: vec![],
+            reward: Some(rewards.into_iter().map(|r| vec![U256::from(r)]).collect()),
+        }
+    }
+    #[test]
+    fn test_eip1559_fees() {
+        let history = fee_history(vec![90, 100, 110], vec![3, 1, 2]);
+        let (max_fee, priority_fee) = EIP1559FeeConfig::default().fees(&history).unwrap();
+        assert_eq!(max_fee, U256::from(222));
+        assert_eq!(priority_fee, U256::from(2));
+
+        let config = EIP1559FeeConfig {
+            base_fee_multiplier: 1.25,
+            ..Default::default()
+        };
+        let (max_fee, _) = config.fees(&history).unwrap();
+        assert_eq!(max_fee, U256::from(139));
+    }
+    #[test]
+    fn test_eip1559_fees_with_caps() {
+        let history = fee_history(vec![100], vec![30, 50]);
+        let config = EIP1559FeeConfig {
+            max_fee_per_gas: Some(150),
+            max_priority_fee_per_gas: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.fees(&history).unwrap(),
+            (U256::from(150), U256::from(40))
+        );
+        // the max fee is derived from the capped priority fee
+        let config = EIP1559FeeConfig {
+            max_fee_per_gas: Some(1_000),
+            max_priority_fee_per_gas: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.fees(&history).unwrap(),
+            (U256::from(240), U256::from(40))
+        );
+        let config = EIP1559FeeConfig {
+            max_fee_per_gas: Some(120),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.fees(&history).unwrap(),
+            (U256::from(120), U256::from(50))
+        );
+        // a cap below the base fee can't be included
+        let config = EIP1559FeeConfig {
+            max_fee_per_gas: Some(20),
+            ..Default::default()
+        };
+        assert!(config.fees(&history).is_err());
+    }
 }
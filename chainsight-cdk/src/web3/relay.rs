@@ -0,0 +1,929 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use ic_web3_rs::{
+    contract::Options,
+    transports::{
+        ic_http_client::{CallOptions, CallOptionsBuilder},
+        ICHttp,
+    },
+    types::{Address, BlockNumber, H256, U256, U64},
+    Transport, Web3,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    time::TimeStamper,
+    web3::{worst_case_fee, TransactionReceiptProcessor, TransformProcessor},
+};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub type RelayStore = StableBTreeMap<u64, RelayRecord, Memory>;
+/// Chain ids of the relays in flight by their ids, so that they are found without scanning all relays.
+pub type RelayIndex = StableBTreeMap<u64, u64, Memory>;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_FEE_BUMP_PERCENT: u64 = 20;
+const DEFAULT_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub enum RelayStatus {
+    /// Sent and waiting to be mined
+    Pending,
+    /// Mined successfully
+    Mined,
+    /// Mined but reverted
+    Reverted,
+    /// Superseded by a transaction with the same nonce and a higher fee, but may still be mined
+    Replaced,
+    /// Never mined; its nonce was consumed by another transaction
+    Dropped,
+    /// Sending failed, so it is neither followed nor counted as relayed
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct RelayRecord {
+    pub tx_hash: String,
+    pub chain_id: u64,
+    /// Address of the sender, unset for relays recorded before it was
+    pub sender: Option<String>,
+    /// Address of the destination contract
+    pub contract: String,
+    pub nonce: u64,
+    /// Gas limit of the transaction
+    pub gas: Option<u128>,
+    pub gas_price: Option<u128>,
+    pub max_fee_per_gas: Option<u128>,
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// Time the transaction was sent in seconds
+    pub sent_at: u64,
+    pub status: RelayStatus,
+    /// Block in which the transaction was mined
+    pub block_number: Option<u64>,
+    /// Id of the relay which replaced this one
+    pub replaced_by: Option<u64>,
+    /// Error returned when sending the transaction
+    pub error: Option<String>,
+    /// Measured value relayed, if the value is numeric
+    pub value: Option<f64>,
+    /// Fee paid in wei once the transaction is mined
+    pub fee: Option<u128>,
+}
+impl RelayRecord {
+    /// Whether a receipt may still show up for the transaction.
+    pub fn in_flight(&self) -> bool {
+        matches!(self.status, RelayStatus::Pending | RelayStatus::Replaced)
+    }
+    pub fn is_destined_to(&self, chain_id: u64, contract: &str) -> bool {
+        self.chain_id == chain_id && self.contract == contract
+    }
+    /// Whether the relay was sent by the sender on the chain; relays without a sender were sent by the only one.
+    pub fn is_sent_by(&self, chain_id: u64, sender: &str) -> bool {
+        self.chain_id == chain_id && self.sender.as_deref().is_none_or(|s| s == sender)
+    }
+    /// Fee paid once mined, or the most the transaction may pay while it can still be mined.
+    pub fn worst_case_fee(&self) -> u128 {
+        match self.fee {
+            Some(fee) => fee,
+            None if self.in_flight() => {
+                worst_case_fee(self.gas, self.max_fee_per_gas, self.gas_price)
+            }
+            None => 0,
+        }
+    }
+}
+impl Storable for RelayRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Receipt of a mined transaction, reduced to the fields the relayer cares about.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayReceipt {
+    pub block_number: u64,
+    pub succeeded: bool,
+    /// Gas used multiplied by the effective gas price, if the node reports both
+    pub fee: Option<u128>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcReceipt {
+    block_number: Option<U64>,
+    status: Option<U64>,
+    gas_used: Option<U256>,
+    effective_gas_price: Option<U256>,
+}
+
+/// Fetches the receipt of a transaction with `eth_getTransactionReceipt`.
+/// Returns None while the transaction is not mined.
+pub async fn find_transaction_receipt(
+    url: &str,
+    tx_hash: &str,
+    call_options: CallOptions,
+) -> anyhow::Result<Option<RelayReceipt>> {
+    let transport = ICHttp::new(url, None)?;
+    let result = transport
+        .execute(
+            "eth_getTransactionReceipt",
+            vec![json!(tx_hash)],
+            call_options,
+        )
+        .await?;
+    if result.is_null() {
+        return Ok(None);
+    }
+    let receipt: RpcReceipt = serde_json::from_value(result)?;
+    let fee = match (receipt.gas_used, receipt.effective_gas_price) {
+        (Some(gas_used), Some(price)) => gas_used.checked_mul(price).map(|fee| fee.low_u128()),
+        _ => None,
+    };
+    Ok(receipt.block_number.map(|number| RelayReceipt {
+        block_number: number.as_u64(),
+        succeeded: receipt.status.map(|s| s.as_u64() == 1).unwrap_or(true),
+        fee,
+    }))
+}
+
+/// Records relayed transactions and follows them until they are mined.
+/// Transactions stuck longer than the timeout are replaced by sending a new one
+/// with the same nonce and a bumped fee.
+/// Relays no longer in flight are pruned once older than the retention period.
+pub struct RelayTracker {
+    store: &'static std::thread::LocalKey<RefCell<RelayStore>>,
+    in_flight: &'static std::thread::LocalKey<RefCell<RelayIndex>>,
+    timeout_secs: u64,
+    fee_bump_percent: u64,
+    retention_secs: u64,
+}
+
+impl RelayTracker {
+    pub fn new(
+        store: &'static std::thread::LocalKey<RefCell<RelayStore>>,
+        in_flight: &'static std::thread::LocalKey<RefCell<RelayIndex>>,
+    ) -> Self {
+        Self {
+            store,
+            in_flight,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            fee_bump_percent: DEFAULT_FEE_BUMP_PERCENT,
+            retention_secs: DEFAULT_RETENTION_SECS,
+        }
+    }
+    /// Sets how long a transaction may stay pending before it is replaced.
+    pub fn with_timeout(mut self, secs: u64) -> Self {
+        self.timeout_secs = secs;
+        self
+    }
+    /// Sets the fee increase of replacements in percent. Most nodes require at least 10.
+    pub fn with_fee_bump(mut self, percent: u64) -> Self {
+        self.fee_bump_percent = percent;
+        self
+    }
+    /// Sets how long relays no longer in flight are kept.
+    /// Shorter than the gas budget window, fees spent within the window are forgotten.
+    pub fn with_retention(mut self, secs: u64) -> Self {
+        self.retention_secs = secs;
+        self
+    }
+
+    /// Records a sent transaction and returns the id of the relay.
+    /// Transactions whose sending failed are recorded as failed and not followed.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        chain_id: u64,
+        sender: Address,
+        contract: &str,
+        tx_hash: H256,
+        options: &Options,
+        value: Option<f64>,
+        error: Option<String>,
+    ) -> u64 {
+        let record = RelayRecord {
+            tx_hash: format!("0x{}", hex::encode(tx_hash.as_bytes())),
+            chain_id,
+            sender: Some(address_to_string(&sender)),
+            contract: contract.to_string(),
+            nonce: options.nonce.map(|n| n.as_u64()).unwrap_or_default(),
+            gas: options.gas.map(|v| v.as_u128()),
+            gas_price: options.gas_price.map(|v| v.as_u128()),
+            max_fee_per_gas: options.max_fee_per_gas.map(|v| v.as_u128()),
+            max_priority_fee_per_gas: options.max_priority_fee_per_gas.map(|v| v.as_u128()),
+            sent_at: TimeStamper::now_sec(),
+            status: if error.is_some() {
+                RelayStatus::Failed
+            } else {
+                RelayStatus::Pending
+            },
+            block_number: None,
+            replaced_by: None,
+            error,
+            value,
+            fee: None,
+        };
+        let in_flight = record.in_flight();
+        let id = self.store.with(|m| {
+            let mut m = m.borrow_mut();
+            let id = m.last_key_value().map(|(k, _)| k + 1).unwrap_or_default();
+            m.insert(id, record);
+            id
+        });
+        if in_flight {
+            self.in_flight.with(|m| m.borrow_mut().insert(id, chain_id));
+        }
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<RelayRecord> {
+        self.store.with(|m| m.borrow().get(&id))
+    }
+
+    /// Returns the latest n relays, newest first.
+    pub fn last(&self, n: u64) -> Vec<(u64, RelayRecord)> {
+        self.store
+            .with(|m| m.borrow().iter().rev().take(n as usize).collect())
+    }
+
+    /// Returns the relays which may still be mined, oldest first.
+    pub fn in_flight(&self) -> Vec<(u64, RelayRecord)> {
+        let ids: Vec<u64> = self.in_flight.with(|m| m.borrow().keys().collect());
+        self.with_records(ids)
+    }
+
+    /// Returns the relays in flight on the chain, oldest first.
+    fn in_flight_on(&self, chain_id: u64) -> Vec<(u64, RelayRecord)> {
+        let ids: Vec<u64> = self.in_flight.with(|m| {
+            m.borrow()
+                .iter()
+                .filter(|(_, chain)| *chain == chain_id)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        self.with_records(ids)
+    }
+
+    fn with_records(&self, ids: Vec<u64>) -> Vec<(u64, RelayRecord)> {
+        self.store.with(|m| {
+            let m = m.borrow();
+            ids.into_iter()
+                .filter_map(|id| m.get(&id).map(|record| (id, record)))
+                .collect()
+        })
+    }
+
+    /// Returns the latest relay to the destination.
+    pub fn latest(&self, chain_id: u64, contract: &str) -> Option<(u64, RelayRecord)> {
+        self.store.with(|m| {
+            m.borrow()
+                .iter()
+                .rev()
+                .find(|(_, record)| record.is_destined_to(chain_id, contract))
+        })
+    }
+
+    /// Returns the latest relay to the destination as a status.
+    pub fn destination_status(
+        &self,
+        chain_id: u64,
+        contract: String,
+        method_name: &str,
+    ) -> RelayDestinationStatus {
+        let latest = self.latest(chain_id, &contract);
+        RelayDestinationStatus {
+            chain_id,
+            contract,
+            method_name: method_name.to_string(),
+            last_relay_id: latest.as_ref().map(|(id, _)| *id),
+            last_relay: latest.map(|(_, record)| record),
+        }
+    }
+
+    /// Returns the last value relayed to the destination which was neither reverted, dropped nor failed.
+    pub fn last_relayed(&self, chain_id: u64, contract: &str) -> LastRelay {
+        self.last_relayed_id(chain_id, contract)
+            .and_then(|id| self.get(id))
+            .map(|record| LastRelay {
+                value: record.value,
+                relayed_at: record.sent_at,
+            })
+            .unwrap_or_default()
+    }
+
+    fn last_relayed_id(&self, chain_id: u64, contract: &str) -> Option<u64> {
+        self.store.with(|m| {
+            m.borrow()
+                .iter()
+                .rev()
+                .find(|(_, record)| {
+                    record.is_destined_to(chain_id, contract)
+                        && !matches!(
+                            record.status,
+                            RelayStatus::Reverted | RelayStatus::Dropped | RelayStatus::Failed
+                        )
+                })
+                .map(|(id, _)| id)
+        })
+    }
+
+    /// Returns the fees of relays by the sender on the chain sent at or after the given time,
+    /// counting the ones not mined yet at their worst case.
+    pub fn spent_since(&self, chain_id: u64, sender: Address, since: u64) -> u128 {
+        let sender = address_to_string(&sender);
+        self.store.with(|m| {
+            m.borrow()
+                .iter()
+                .rev()
+                .take_while(|(_, record)| record.sent_at >= since)
+                .filter(|(_, record)| record.is_sent_by(chain_id, &sender))
+                .map(|(_, record)| record.worst_case_fee())
+                .fold(0u128, u128::saturating_add)
+        })
+    }
+
+    /// Returns the oldest pending relay to the destination which exceeded the timeout.
+    pub fn stuck(&self, chain_id: u64, contract: &str) -> Option<(u64, RelayRecord)> {
+        let now = TimeStamper::now_sec();
+        self.in_flight_on(chain_id).into_iter().find(|(_, record)| {
+            record.is_destined_to(chain_id, contract)
+                && record.status == RelayStatus::Pending
+                && record.sent_at + self.timeout_secs <= now
+        })
+    }
+
+    /// Builds options replacing the stuck transaction: its nonce, and fees bumped
+    /// over the ones it was sent with unless the current fees are already higher.
+    pub fn replacement_options(&self, stuck: &RelayRecord, current: Options) -> Options {
+        let bump = |prev: Option<u128>, current: Option<U256>| match prev {
+            Some(prev) => {
+                let bumped = U256::from(prev) * (100 + self.fee_bump_percent) / 100;
+                Some(current.map_or(bumped, |c| c.max(bumped)))
+            }
+            None => current,
+        };
+        Options {
+            nonce: Some(U256::from(stuck.nonce)),
+            gas_price: bump(stuck.gas_price, current.gas_price),
+            max_fee_per_gas: bump(stuck.max_fee_per_gas, current.max_fee_per_gas),
+            max_priority_fee_per_gas: bump(
+                stuck.max_priority_fee_per_gas,
+                current.max_priority_fee_per_gas,
+            ),
+            ..current
+        }
+    }
+
+    /// Marks the relay as replaced by another one.
+    pub fn replace(&self, id: u64, by: u64) {
+        self.update(id, |record| {
+            record.status = RelayStatus::Replaced;
+            record.replaced_by = Some(by);
+        })
+    }
+
+    /// Applies the receipt of a relay. Without a receipt, the relay is dropped
+    /// once the confirmed transaction count of the sender is past its nonce.
+    pub fn resolve(&self, id: u64, receipt: Option<RelayReceipt>, confirmed_nonce: u64) {
+        self.update(id, |record| match &receipt {
+            Some(receipt) => {
+                record.status = if receipt.succeeded {
+                    RelayStatus::Mined
+                } else {
+                    RelayStatus::Reverted
+                };
+                record.block_number = Some(receipt.block_number);
+                record.fee = receipt.fee;
+            }
+            None if record.nonce < confirmed_nonce => record.status = RelayStatus::Dropped,
+            None => {}
+        })
+    }
+
+    /// Polls the receipts of the relays in flight to the destination.
+    pub async fn poll(
+        &self,
+        url: &str,
+        chain_id: u64,
+        contract: &str,
+        sender: Address,
+    ) -> anyhow::Result<()> {
+        let relays: Vec<(u64, RelayRecord)> = self
+            .in_flight_on(chain_id)
+            .into_iter()
+            .filter(|(_, record)| record.is_destined_to(chain_id, contract))
+            .collect();
+        if relays.is_empty() {
+            return Ok(());
+        }
+        let confirmed_nonce = Web3::new(ICHttp::new(url, None)?)
+            .eth()
+            .transaction_count(sender, Some(BlockNumber::Latest), CallOptions::default())
+            .await?
+            .as_u64();
+        for (id, record) in relays {
+            let receipt =
+                find_transaction_receipt(url, &record.tx_hash, Self::receipt_call_options())
+                    .await?;
+            self.resolve(id, receipt, confirmed_nonce);
+        }
+        Ok(())
+    }
+
+    /// Removes the relays no longer in flight sent before the retention period, and returns their number.
+    /// The latest relay and the last relayed value of each destination are kept for the thresholds.
+    pub fn prune(&self) -> u64 {
+        let cutoff = TimeStamper::now_sec().saturating_sub(self.retention_secs);
+        let expired: Vec<(u64, RelayRecord)> = self.store.with(|m| {
+            m.borrow()
+                .iter()
+                .take_while(|(_, record)| record.sent_at < cutoff)
+                .filter(|(_, record)| !record.in_flight())
+                .collect()
+        });
+        let mut kept = std::collections::BTreeSet::new();
+        let mut destinations = std::collections::BTreeSet::new();
+        for (_, record) in expired.iter() {
+            if destinations.insert((record.chain_id, record.contract.clone())) {
+                kept.extend(
+                    self.latest(record.chain_id, &record.contract)
+                        .map(|(id, _)| id),
+                );
+                kept.extend(self.last_relayed_id(record.chain_id, &record.contract));
+            }
+        }
+        self.store.with(|m| {
+            let mut m = m.borrow_mut();
+            expired
+                .into_iter()
+                .filter(|(id, _)| !kept.contains(id))
+                .map(|(id, _)| m.remove(&id))
+                .count() as u64
+        })
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut RelayRecord)) {
+        let in_flight = self.store.with(|m| {
+            let mut m = m.borrow_mut();
+            let mut record = m.get(&id)?;
+            f(&mut record);
+            let in_flight = record.in_flight();
+            m.insert(id, record);
+            Some(in_flight)
+        });
+        if in_flight == Some(false) {
+            self.in_flight.with(|m| m.borrow_mut().remove(&id));
+        }
+    }
+
+    fn receipt_call_options() -> CallOptions {
+        CallOptionsBuilder::default()
+            .max_resp(None)
+            .cycles(None)
+            .transform(Some(TransactionReceiptProcessor.context()))
+            .build()
+            .unwrap()
+    }
+}
+
+fn address_to_string(address: &Address) -> String {
+    format!("0x{}", hex::encode(address.as_bytes()))
+}
+
+/// Conditions to relay a value. A value is relayed when it deviates from the last
+/// relayed one beyond either threshold, or when the heartbeat interval elapsed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayThreshold {
+    /// Optional: Deviation relative to the last relayed value, e.g. 0.01 for 1%
+    pub relative_deviation: Option<f64>,
+    /// Optional: Absolute deviation from the last relayed value
+    pub absolute_deviation: Option<f64>,
+    /// Optional: Maximum interval between relays in seconds
+    pub heartbeat_sec: Option<u64>,
+}
+impl RelayThreshold {
+    /// Whether the value should be relayed. Values which cannot be measured are always relayed.
+    pub fn is_hit(&self, last: &LastRelay, value: Option<f64>, now: u64) -> bool {
+        if last.relayed_at == 0 {
+            return true;
+        }
+        if let Some(heartbeat) = self.heartbeat_sec {
+            if now >= last.relayed_at + heartbeat {
+                return true;
+            }
+        }
+        if self.relative_deviation.is_none() && self.absolute_deviation.is_none() {
+            return false;
+        }
+        let (value, last) = match (value, last.value) {
+            (Some(value), Some(last)) => (value, last),
+            _ => return true,
+        };
+        let deviation = (value - last).abs();
+        if let Some(absolute) = self.absolute_deviation {
+            if deviation >= absolute {
+                return true;
+            }
+        }
+        if let Some(relative) = self.relative_deviation {
+            if last == 0.0 {
+                return value != 0.0;
+            }
+            if deviation / last.abs() >= relative {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The value relayed last and when it was relayed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LastRelay {
+    /// Measured value, if the relayed value is numeric
+    pub value: Option<f64>,
+    /// Time of the relay in seconds, 0 if nothing was relayed yet
+    pub relayed_at: u64,
+}
+
+/// Latest relay to a destination of the relayer.
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct RelayDestinationStatus {
+    pub chain_id: u64,
+    pub contract: String,
+    pub method_name: String,
+    pub last_relay_id: Option<u64>,
+    pub last_relay: Option<RelayRecord>,
+}
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    use super::*;
+    use crate::web3::DEFAULT_GAS_LIMIT;
+
+    thread_local! {
+        static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+            MemoryManager::init(DefaultMemoryImpl::default());
+        static RELAYS: RefCell<RelayStore> = RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.get(MemoryId::new(0))),
+        ));
+        static RELAYS_IN_FLIGHT: RefCell<RelayIndex> = RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.get(MemoryId::new(1))),
+        ));
+    }
+
+    fn tracker() -> RelayTracker {
+        RelayTracker::new(&RELAYS, &RELAYS_IN_FLIGHT)
+    }
+
+    const CONTRACT: &str = "0x0000000000000000000000000000000000000001";
+
+    fn sender() -> Address {
+        Address::from_low_u64_be(100)
+    }
+
+    fn options(nonce: u64, max_fee: u64, priority: u64) -> Options {
+        Options::with(|op| {
+            op.nonce = Some(U256::from(nonce));
+            op.max_fee_per_gas = Some(U256::from(max_fee));
+            op.max_priority_fee_per_gas = Some(U256::from(priority));
+            op.transaction_type = Some(U64::from(2));
+        })
+    }
+
+    #[test]
+    fn test_record_and_resolve() {
+        let tracker = tracker();
+        let first = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(1),
+            &options(5, 100, 10),
+            None,
+            None,
+        );
+        let second = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(2),
+            &options(6, 100, 10),
+            None,
+            None,
+        );
+        assert_eq!(second, first + 1);
+        assert_eq!(tracker.get(first).unwrap().nonce, 5);
+        assert_eq!(tracker.in_flight().len(), 2);
+
+        tracker.resolve(
+            first,
+            Some(RelayReceipt {
+                block_number: 10,
+                succeeded: true,
+                fee: Some(1_000),
+            }),
+            6,
+        );
+        tracker.resolve(
+            second,
+            Some(RelayReceipt {
+                block_number: 11,
+                succeeded: false,
+                fee: Some(500),
+            }),
+            7,
+        );
+        let first = tracker.get(first).unwrap();
+        assert_eq!(first.status, RelayStatus::Mined);
+        assert_eq!(first.block_number, Some(10));
+        assert_eq!(tracker.get(second).unwrap().status, RelayStatus::Reverted);
+        // reverted transactions pay fees as well
+        assert_eq!(tracker.spent_since(1, sender(), 0), 1_500);
+        assert_eq!(tracker.spent_since(1, sender(), u64::MAX), 0);
+        assert_eq!(tracker.spent_since(2, sender(), 0), 0);
+        assert_eq!(tracker.spent_since(1, Address::from_low_u64_be(1), 0), 0);
+        assert!(tracker.in_flight().is_empty());
+        let last = tracker.last(1);
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].0, second);
+    }
+
+    #[test]
+    fn test_stuck_and_replace() {
+        let tracker = tracker().with_timeout(0);
+        let stuck_id = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(1),
+            &options(5, 100, 10),
+            None,
+            None,
+        );
+        assert!(tracker.stuck(2, CONTRACT).is_none());
+        let (id, stuck) = tracker.stuck(1, CONTRACT).unwrap();
+        assert_eq!(id, stuck_id);
+
+        // fees are bumped over the stuck transaction
+        let replacement = tracker.replacement_options(&stuck, options(7, 110, 10));
+        assert_eq!(replacement.nonce, Some(U256::from(5)));
+        assert_eq!(replacement.max_fee_per_gas, Some(U256::from(120)));
+        assert_eq!(replacement.max_priority_fee_per_gas, Some(U256::from(12)));
+        assert_eq!(replacement.transaction_type, Some(U64::from(2)));
+        // unless the current fees are already higher
+        let replacement = tracker.replacement_options(&stuck, options(7, 200, 20));
+        assert_eq!(replacement.max_fee_per_gas, Some(U256::from(200)));
+        assert_eq!(replacement.max_priority_fee_per_gas, Some(U256::from(20)));
+
+        let replacement_id = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(2),
+            &replacement,
+            None,
+            None,
+        );
+        tracker.replace(stuck_id, replacement_id);
+        let stuck = tracker.get(stuck_id).unwrap();
+        assert_eq!(stuck.status, RelayStatus::Replaced);
+        assert_eq!(stuck.replaced_by, Some(replacement_id));
+        // the replaced relay is still polled but never replaced again
+        assert_eq!(tracker.in_flight().len(), 2);
+        assert_eq!(tracker.stuck(1, CONTRACT).unwrap().0, replacement_id);
+        // the replacement got mined
+        tracker.resolve(stuck_id, None, 6);
+        assert_eq!(tracker.get(stuck_id).unwrap().status, RelayStatus::Dropped);
+    }
+
+    #[test]
+    fn test_resolve_dropped() {
+        let tracker = tracker();
+        let id = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(1),
+            &options(5, 100, 10),
+            None,
+            None,
+        );
+        tracker.resolve(id, None, 5);
+        assert_eq!(tracker.get(id).unwrap().status, RelayStatus::Pending);
+        // pending relays count at their worst case
+        assert_eq!(tracker.spent_since(1, sender(), 0), 100 * DEFAULT_GAS_LIMIT);
+        tracker.resolve(id, None, 6);
+        assert_eq!(tracker.get(id).unwrap().status, RelayStatus::Dropped);
+        assert_eq!(tracker.spent_since(1, sender(), 0), 0);
+    }
+
+    #[test]
+    fn test_record_failed() {
+        let tracker = tracker();
+        let relayed = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(1),
+            &options(5, 100, 10),
+            Some(1.0),
+            None,
+        );
+        tracker.resolve(
+            relayed,
+            Some(RelayReceipt {
+                block_number: 10,
+                succeeded: true,
+                fee: Some(1_000),
+            }),
+            6,
+        );
+        let failed = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(2),
+            &options(6, 100, 10),
+            Some(2.0),
+            Some("connection reset".to_string()),
+        );
+        assert_eq!(tracker.get(failed).unwrap().status, RelayStatus::Failed);
+        // failed relays are neither followed, charged nor counted as relayed
+        assert!(tracker.in_flight().is_empty());
+        assert_eq!(tracker.spent_since(1, sender(), 0), 1_000);
+        assert_eq!(tracker.last_relayed(1, CONTRACT).value, Some(1.0));
+        assert_eq!(tracker.latest(1, CONTRACT).unwrap().0, failed);
+    }
+
+    #[test]
+    fn test_prune() {
+        let tracker = tracker();
+        let other = "0x0000000000000000000000000000000000000002";
+        let ids: Vec<u64> = (0..4)
+            .map(|i| {
+                tracker.record(
+                    1,
+                    sender(),
+                    if i == 3 { other } else { CONTRACT },
+                    H256::from_low_u64_be(i),
+                    &options(i, 100, 10),
+                    Some(i as f64),
+                    None,
+                )
+            })
+            .collect();
+        let mined = RelayReceipt {
+            block_number: 10,
+            succeeded: true,
+            fee: Some(1),
+        };
+        tracker.resolve(ids[0], Some(mined.clone()), 1);
+        tracker.resolve(ids[1], Some(mined.clone()), 2);
+        tracker.resolve(ids[3], Some(mined), 4);
+        assert_eq!(
+            tracker
+                .in_flight()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            vec![ids[2]]
+        );
+        RELAYS.with(|m| {
+            let mut m = m.borrow_mut();
+            for id in ids.iter() {
+                let mut record = m.get(id).unwrap();
+                record.sent_at = 0;
+                m.insert(*id, record);
+            }
+        });
+        // relays in flight and the latest one of each destination are kept
+        assert_eq!(tracker.prune(), 2);
+        assert!(tracker.get(ids[0]).is_none());
+        assert!(tracker.get(ids[1]).is_none());
+        assert!(tracker.get(ids[2]).is_some());
+        assert_eq!(tracker.last_relayed(1, other).value, Some(3.0));
+        assert_eq!(tracker.prune(), 0);
+    }
+
+    #[test]
+    fn test_threshold_deviation() {
+        let threshold = RelayThreshold {
+            relative_deviation: Some(0.01),
+            absolute_deviation: Some(5.0),
+            heartbeat_sec: None,
+        };
+        // nothing relayed yet
+        assert!(threshold.is_hit(&LastRelay::default(), Some(100.0), 10));
+        let last = LastRelay {
+            value: Some(100.0),
+            relayed_at: 10,
+        };
+        assert!(!threshold.is_hit(&last, Some(100.5), 1000));
+        assert!(threshold.is_hit(&last, Some(101.0), 11));
+        assert!(threshold.is_hit(&last, Some(98.9), 11));
+        // absolute deviation applies to values around zero
+        let last = LastRelay {
+            value: Some(0.0),
+            relayed_at: 10,
+        };
+        assert!(!threshold.is_hit(&last, Some(0.0), 11));
+        assert!(threshold.is_hit(&last, Some(0.001), 11));
+        // values which cannot be measured are relayed
+        assert!(threshold.is_hit(&last, None, 11));
+    }
+
+    #[test]
+    fn test_threshold_heartbeat() {
+        let threshold = RelayThreshold {
+            heartbeat_sec: Some(60),
+            ..Default::default()
+        };
+        let last = LastRelay {
+            value: Some(100.0),
+            relayed_at: 10,
+        };
+        assert!(!threshold.is_hit(&last, Some(200.0), 69));
+        assert!(threshold.is_hit(&last, Some(100.0), 70));
+
+        let threshold = RelayThreshold {
+            absolute_deviation: Some(1.0),
+            heartbeat_sec: Some(60),
+            ..Default::default()
+        };
+        assert!(threshold.is_hit(&last, Some(101.0), 11));
+        assert!(threshold.is_hit(&last, Some(100.0), 70));
+        assert!(!threshold.is_hit(&last, Some(100.5), 69));
+    }
+
+    #[test]
+    fn test_destinations() {
+        let tracker = tracker().with_timeout(0);
+        let other = "0x0000000000000000000000000000000000000002";
+        assert_eq!(tracker.last_relayed(1, CONTRACT), LastRelay::default());
+        let first = tracker.record(
+            1,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(1),
+            &options(5, 100, 10),
+            Some(1.5),
+            None,
+        );
+        let second = tracker.record(
+            1,
+            sender(),
+            other,
+            H256::from_low_u64_be(2),
+            &options(6, 100, 10),
+            Some(2.5),
+            None,
+        );
+        let third = tracker.record(
+            137,
+            sender(),
+            CONTRACT,
+            H256::from_low_u64_be(3),
+            &options(0, 100, 10),
+            Some(3.5),
+            None,
+        );
+        assert_eq!(tracker.latest(1, CONTRACT).unwrap().0, first);
+        assert_eq!(tracker.latest(1, other).unwrap().0, second);
+        assert_eq!(tracker.latest(137, CONTRACT).unwrap().0, third);
+        assert_eq!(tracker.stuck(1, other).unwrap().0, second);
+        assert_eq!(tracker.last_relayed(1, other).value, Some(2.5));
+
+        // reverted relays don't count as relayed
+        tracker.resolve(
+            second,
+            Some(RelayReceipt {
+                block_number: 10,
+                succeeded: false,
+                fee: None,
+            }),
+            7,
+        );
+        assert_eq!(tracker.last_relayed(1, other), LastRelay::default());
+        assert_eq!(tracker.last_relayed(1, CONTRACT).value, Some(1.5));
+
+        let status = tracker.destination_status(1, other.to_string(), "update_state");
+        assert_eq!(status.last_relay_id, Some(second));
+        assert_eq!(status.last_relay.unwrap().status, RelayStatus::Reverted);
+        let status = tracker.destination_status(10, other.to_string(), "update_state");
+        assert_eq!(status.last_relay_id, None);
+    }
+}
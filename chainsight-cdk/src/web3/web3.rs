@@ -2,8 +2,9 @@ use std::{borrow::Cow, collections::HashMap, marker::PhantomData};
 
 use crate::{
     core::Env,
-    indexer::{Error, Event, Indexer, IndexingConfig},
-    storage::{KeyValuesStore, Persist},
+    indexer::{Error, Event, Indexer, IndexingConfig, InstructionBudget},
+    storage::{self, KeyValuesStore, Persist, RetentionPolicy, StoreIndex, Token},
+    web3::{BlockHeaderProcessor, BlockNumberProcessor, TransformProcessor},
 };
 use async_trait::async_trait;
 use candid::{CandidType, Decode, Encode};
@@ -12,16 +13,26 @@ use ic_solidity_bindgen::types::EventLog;
 use ic_stable_structures::{storable::Bound, Storable};
 use ic_web3_rs::{
     futures::future::BoxFuture,
-    transports::ic_http_client::{CallOptions, CallOptionsBuilder},
+    transports::{
+        ic_http_client::{CallOptions, CallOptionsBuilder},
+        ICHttp,
+    },
+    types::{H256, U64},
+    Transport,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 pub struct Web3Indexer<E>
 where
     E: Event<EventLog>,
 {
     _phantom: PhantomData<E>,
     finder: Web3LogFinder,
+    block_finder: Option<Web3BlockFinder>,
+    head_finder: Option<Web3HeadFinder>,
+    contract: String,
     storage: KeyValuesStore,
+    indexes: Vec<(&'static str, StoreIndex)>,
 }
 
 #[derive(Default, Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
@@ -52,12 +63,150 @@ pub struct Web3LogFinder {
     find: FindFunc,
 }
 impl Web3LogFinder {
-    async fn find(&self, args: (u64, u64)) -> Result<HashMap<u64, Vec<EventLog>>, Error> {
-        (self.find)(args.0, args.1, self.call_options.clone()).await
+    /// Finds logs in `from..=to`, bisecting the range while the provider rejects it as too large.
+    /// Returns the logs and the narrowest span that had to be requested.
+    async fn find(&self, args: (u64, u64)) -> Result<(HashMap<u64, Vec<EventLog>>, u64), Error> {
+        let mut ranges = vec![args];
+        let mut result = HashMap::new();
+        let mut accepted = args.1 - args.0;
+        while let Some((from, to)) = ranges.pop() {
+            match (self.find)(from, to, self.call_options.clone()).await {
+                Ok(logs) => result.extend(logs),
+                // a single block can't be split any further
+                Err(e) if to == from && is_range_too_large(&e) => {
+                    return Err(Error::Web3Error(format!(
+                        "Logs of block {} exceed the provider limit: {}",
+                        from, e
+                    )))
+                }
+                Err(e) if is_range_too_large(&e) => {
+                    let mid = from + (to - from) / 2;
+                    accepted = accepted.min(mid - from);
+                    ranges.push((mid + 1, to));
+                    ranges.push((from, mid));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((result, accepted))
     }
 }
+
+/// Messages of providers rejecting `eth_getLogs` ranges, e.g. Infura, Alchemy, Ankr, QuickNode and Geth,
+/// and of HTTPS outcalls whose response is too large.
+/// Generic ones such as "limit exceeded" are left out as rate limits use them too.
+const RANGE_TOO_LARGE_ERRORS: [&str; 10] = [
+    "query returned more than",
+    "log response size exceeded",
+    "query exceeds max results",
+    "block range is too wide",
+    "block range too large",
+    "block range limit exceeded",
+    "exceed maximum block range",
+    "eth_getlogs is limited to",
+    "range is too large",
+    "http body exceeds size limit",
+];
+
+/// Whether the provider rejected an `eth_getLogs` call because the range yields too many logs
+/// or the response exceeds its size limit.
+pub fn is_range_too_large(err: &Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    RANGE_TOO_LARGE_ERRORS.iter().any(|e| msg.contains(e))
+}
 pub type FindFunc =
     fn(u64, u64, CallOptions) -> BoxFuture<'static, Result<HashMap<u64, Vec<EventLog>>, Error>>;
+
+#[derive(Default, Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub number: u64,
+    pub hash: String,
+    pub parent_hash: String,
+}
+
+#[derive(Clone)]
+pub struct Web3BlockFinder {
+    call_options: CallOptions,
+    find: BlockFunc,
+}
+impl Web3BlockFinder {
+    async fn find(&self, number: u64) -> Result<Option<BlockHeader>, Error> {
+        (self.find)(number, self.call_options.clone()).await
+    }
+}
+pub type BlockFunc = fn(u64, CallOptions) -> BoxFuture<'static, Result<Option<BlockHeader>, Error>>;
+
+#[derive(Clone)]
+pub struct Web3HeadFinder {
+    call_options: CallOptions,
+    find: HeadFunc,
+}
+impl Web3HeadFinder {
+    async fn find(&self) -> Result<u64, Error> {
+        (self.find)(self.call_options.clone()).await
+    }
+}
+pub type HeadFunc = fn(CallOptions) -> BoxFuture<'static, Result<u64, Error>>;
+
+/// Name of the cursor at the last block scanned for logs, whether it had any or not.
+const SCANNED_CHECKPOINT: &str = "scanned_block";
+/// Prefix of the indexer state holding the learned chunk size by contract.
+const CHUNK_SIZE_STATE_PREFIX: &str = "chunk_size:";
+/// Name of the indexer state holding the last observed chain head, kept across upgrades.
+const OBSERVED_HEAD_STATE: &str = "observed_head";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcBlockHeader {
+    number: U64,
+    hash: H256,
+    parent_hash: H256,
+}
+
+/// Fetches the header of the block at `number` with `eth_getBlockByNumber`.
+/// Returns `None` if the block does not exist yet.
+pub async fn find_block_header(
+    url: &str,
+    number: u64,
+    call_options: CallOptions,
+) -> Result<Option<BlockHeader>, Error> {
+    let transport = ICHttp::new(url, None).map_err(|e| Error::Web3Error(e.to_string()))?;
+    let result = transport
+        .execute(
+            "eth_getBlockByNumber",
+            vec![json!(format!("0x{:x}", number)), json!(false)],
+            call_options,
+        )
+        .await
+        .map_err(|e| Error::Web3Error(e.to_string()))?;
+    if result.is_null() {
+        return Ok(None);
+    }
+    let header: RpcBlockHeader =
+        serde_json::from_value(result).map_err(|e| Error::Web3Error(e.to_string()))?;
+    Ok(Some(BlockHeader {
+        number: header.number.as_u64(),
+        hash: hash_to_string(&header.hash),
+        parent_hash: hash_to_string(&header.parent_hash),
+    }))
+}
+
+/// Fetches the latest block number with `eth_blockNumber`.
+pub async fn find_block_number(url: &str, call_options: CallOptions) -> Result<u64, Error> {
+    let transport = ICHttp::new(url, None).map_err(|e| Error::Web3Error(e.to_string()))?;
+    let result = transport
+        .execute("eth_blockNumber", vec![], call_options)
+        .await
+        .map_err(|e| Error::Web3Error(e.to_string()))?;
+    let number: U64 =
+        serde_json::from_value(result).map_err(|e| Error::Web3Error(e.to_string()))?;
+    Ok(number.as_u64())
+}
+
+fn hash_to_string(hash: &H256) -> String {
+    format!("0x{}", hex::encode(hash.as_bytes()))
+}
+
 impl<E> Web3Indexer<E>
 where
     E: Event<EventLog> + Persist,
@@ -72,12 +221,91 @@ where
                 },
                 find,
             },
+            block_finder: None,
+            head_finder: None,
+            contract: String::new(),
             storage: KeyValuesStore::new(1),
+            indexes: vec![],
         }
     }
+    /// Sets the finder for block headers, required when `IndexingConfig.confirmations` is set.
+    pub fn with_block_finder(mut self, find: BlockFunc, call_options: Option<CallOptions>) -> Self {
+        self.block_finder = Some(Web3BlockFinder {
+            call_options: match call_options {
+                Some(options) => options,
+                None => Self::default_block_finder_call_options(),
+            },
+            find,
+        });
+        self
+    }
+    /// Sets the finder for the chain head; indexing ranges are then clamped to the head minus confirmations.
+    pub fn with_head_finder(mut self, find: HeadFunc, call_options: Option<CallOptions>) -> Self {
+        self.head_finder = Some(Web3HeadFinder {
+            call_options: match call_options {
+                Some(options) => options,
+                None => Self::default_head_finder_call_options(),
+            },
+            find,
+        });
+        self
+    }
+    /// Sets the contract whose logs are indexed, used as the key of the learned chunk size.
+    pub fn with_contract(mut self, contract: String) -> Self {
+        self.contract = contract;
+        self
+    }
+    /// Sets the indexes of the event fields, named as in `Data`, kept along with the events.
+    pub fn with_indexes(mut self, indexes: Vec<(&'static str, StoreIndex)>) -> Self {
+        self.indexes = indexes;
+        self
+    }
     fn finder(&self) -> Web3LogFinder {
         self.finder.clone()
     }
+    /// Chunk size to use, lowered from the configured one after the provider rejected larger ranges.
+    /// The learned size is kept in the indexer state in stable memory.
+    fn chunk_size(&self, configured: u64) -> u64 {
+        storage::get_indexer_state(&self.chunk_size_state())
+            .map_or(configured, |learned| configured.min(learned))
+    }
+    fn adapt_chunk_size(&self, requested: u64, accepted: u64, configured: u64) {
+        let next = if accepted < requested {
+            accepted
+        } else {
+            // grow back after a successful call
+            self.chunk_size(configured).max(1).saturating_mul(2)
+        };
+        if next >= configured {
+            storage::remove_indexer_state(&self.chunk_size_state());
+        } else {
+            storage::set_indexer_state(&self.chunk_size_state(), next);
+        }
+    }
+    fn chunk_size_state(&self) -> String {
+        format!("{}{}", CHUNK_SIZE_STATE_PREFIX, self.contract)
+    }
+    fn block_finder(&self) -> Result<Web3BlockFinder, Error> {
+        self.block_finder.clone().ok_or(Error::IndexerError(
+            "block finder is required to track confirmations".to_string(),
+        ))
+    }
+    fn default_head_finder_call_options() -> CallOptions {
+        CallOptionsBuilder::default()
+            .max_resp(None)
+            .cycles(None)
+            .transform(Some(BlockNumberProcessor.context()))
+            .build()
+            .unwrap()
+    }
+    fn default_block_finder_call_options() -> CallOptions {
+        CallOptionsBuilder::default()
+            .max_resp(None)
+            .cycles(None)
+            .transform(Some(BlockHeaderProcessor.context()))
+            .build()
+            .unwrap()
+    }
     fn default_indexer_call_options() -> CallOptions {
         CallOptionsBuilder::default()
             .max_resp(None)
@@ -93,13 +321,7 @@ where
             .unwrap()
     }
     pub fn between(&self, from: u64, to: u64) -> Result<HashMap<u64, Vec<E>>, Error> {
-        Ok(self.storage.between(from, to).into_iter().fold(
-            HashMap::new(),
-            |mut acc, (block_number, tokens)| {
-                acc.insert(block_number, tokens);
-                acc
-            },
-        ))
+        Ok(self.storage.between(from, to)?)
     }
     pub fn on_update(&self, logs: HashMap<u64, Vec<EventLog>>) {
         logs.iter().for_each(|(block_number, logs)| {
@@ -112,18 +334,171 @@ where
                     })
                 })
                 .collect();
-            self.storage.set(*block_number, tokens)
+            self.storage
+                .set_indexed(*block_number, tokens, &self.indexes)
         })
     }
 
+    /// Events whose field equals `value`, looked up by the index of the field set by `with_indexes`.
+    pub fn find(&self, field: &str, value: &Token) -> Result<HashMap<u64, Vec<E>>, Error> {
+        let (_, index) = self
+            .indexes
+            .iter()
+            .find(|(name, _)| *name == field)
+            .ok_or_else(|| Error::OtherError(format!("No index of {}", field)))?;
+        Ok(self.storage.find(field, value, index)?)
+    }
+
+    /// Removes up to `limit` blocks of events beyond `policy` and returns the number of removed blocks.
+    /// Events have no timestamp, so only the number of blocks and the block distance are limited.
+    pub fn prune(&self, policy: &RetentionPolicy, limit: u64) -> u64 {
+        self.storage
+            .prune::<E>(policy, 0, limit, &self.indexes, |_| None)
+    }
+
     pub fn get_last_indexed(&self) -> Result<u64, Error> {
-        let last = self.storage.last();
-        if let Some(last) = last {
-            Ok(last.0)
+        let last_event = self.storage.last().map(|(k, _)| k);
+        let last_block = storage::last_block_hash().map(|(k, _)| k);
+        let last_scanned =
+            storage::get_checkpoint(SCANNED_CHECKPOINT).and_then(|c| c.key.parse::<u64>().ok());
+        if let Some(last) = last_event.max(last_block).max(last_scanned) {
+            Ok(last)
         } else {
             Err(Error::OtherError("No last indexed".to_string()))
         }
     }
+
+    /// Number of blocks between the last observed chain head and the last indexed block.
+    pub fn get_lag(&self) -> Result<u64, Error> {
+        let head = storage::get_indexer_state(OBSERVED_HEAD_STATE)
+            .ok_or(Error::OtherError("No chain head observed".to_string()))?;
+        Ok(head.saturating_sub(self.get_last_indexed().unwrap_or(0)))
+    }
+
+    /// Checks the parent hash of the block following the last indexed one.
+    /// On mismatch, rolls back to the latest tracked block still on the canonical chain.
+    async fn rollback_on_reorg(&self, confirmations: u64) -> Result<(), Error> {
+        let (last, last_hash) = match storage::last_block_hash() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let finder = self.block_finder()?;
+        let next = match finder.find(last + 1).await? {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        if next.parent_hash == last_hash {
+            return Ok(());
+        }
+
+        let window_start = last.saturating_sub(confirmations);
+        let mut ancestor = None;
+        for (number, hash) in storage::block_hashes_between(window_start, last)
+            .into_iter()
+            .rev()
+        {
+            if let Some(header) = finder.find(number).await? {
+                if header.hash == hash {
+                    ancestor = Some((number, Some(header)));
+                    break;
+                }
+            }
+        }
+        let (ancestor, header) = match ancestor {
+            Some(v) => v,
+            None => {
+                // the reorg is deeper than the confirmation depth; rewind the whole window
+                let ancestor = window_start.saturating_sub(1);
+                (ancestor, finder.find(ancestor).await?)
+            }
+        };
+        self.rollback(ancestor)?;
+        if let Some(header) = header {
+            storage::set_block_hash(ancestor, header.hash);
+        }
+        Ok(())
+    }
+
+    /// Indexes the next chunk and returns whether the indexer has caught up with the head.
+    /// Ranges end at the head minus the confirmations, so a head finder is required with confirmations.
+    /// Always caught up without a head finder, as the head is unknown.
+    async fn index_chunk(&self, cfg: &IndexingConfig) -> Result<bool, Error> {
+        if let Some(confirmations) = cfg.confirmations {
+            if self.head_finder.is_none() {
+                return Err(Error::IndexerError(
+                    "head finder is required to keep ranges within the confirmation depth"
+                        .to_string(),
+                ));
+            }
+            self.rollback_on_reorg(confirmations).await?;
+        }
+        let last_indexed = self.get_last_indexed().unwrap_or(0);
+        let configured_chunk_size = cfg.chunk_size.unwrap_or(500);
+        let from = cfg.start_from.max(last_indexed + 1);
+        let mut to = from + self.chunk_size(configured_chunk_size);
+        let mut caught_up = true;
+        if let Some(head_finder) = &self.head_finder {
+            let head = head_finder.find().await?;
+            storage::set_indexer_state(OBSERVED_HEAD_STATE, head);
+            let safe_head = head.saturating_sub(cfg.confirmations.unwrap_or(0));
+            if from > safe_head {
+                return Ok(true);
+            }
+            caught_up = to >= safe_head;
+            to = to.min(safe_head);
+        }
+        let (elements, accepted) = self.finder().find((from, to)).await?;
+        self.adapt_chunk_size(to - from, accepted, configured_chunk_size);
+        if let Some(confirmations) = cfg.confirmations {
+            self.track_blocks(&elements, from, to, confirmations)
+                .await?;
+        }
+        // the cursor moves past chunks without logs too
+        storage::commit_checkpoint(SCANNED_CHECKPOINT, to.to_string(), || {
+            self.on_update(elements)
+        });
+        Ok(caught_up)
+    }
+
+    fn rollback(&self, ancestor: u64) -> Result<(), Error> {
+        storage::set_checkpoint(SCANNED_CHECKPOINT, ancestor.to_string());
+        self.storage
+            .between::<E>(ancestor + 1, u64::MAX)?
+            .into_keys()
+            .for_each(|k| self.storage.remove_indexed(k, &self.indexes));
+        storage::remove_block_hashes(ancestor + 1, u64::MAX);
+        Ok(())
+    }
+
+    /// Records the hashes of the blocks of `from..=to` within the confirmation depth,
+    /// so that the common ancestor of a reorg is found in ranges without logs too.
+    /// Hashes of blocks with logs come with the logs, the others are fetched.
+    async fn track_blocks(
+        &self,
+        logs: &HashMap<u64, Vec<EventLog>>,
+        from: u64,
+        to: u64,
+        confirmations: u64,
+    ) -> Result<(), Error> {
+        let finder = self.block_finder()?;
+        for number in from.max(to.saturating_sub(confirmations))..=to {
+            let hash = match logs
+                .get(&number)
+                .and_then(|logs| logs.first())
+                .and_then(|l| l.log.block_hash)
+            {
+                Some(hash) => Some(hash_to_string(&hash)),
+                None => finder.find(number).await?.map(|header| header.hash),
+            };
+            if let Some(hash) = hash {
+                storage::set_block_hash(number, hash);
+            }
+        }
+        if let Some(last) = to.checked_sub(confirmations + 1) {
+            storage::remove_block_hashes(0, last);
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -132,13 +507,13 @@ where
     E: Event<EventLog> + Persist,
 {
     async fn index(&self, cfg: IndexingConfig) -> Result<(), Error> {
-        let last_indexed = self.get_last_indexed().unwrap_or(0);
-        let chunk_size = cfg.chunk_size.unwrap_or(500);
-        let from = cfg.start_from.max(last_indexed + 1);
-        let to = from + chunk_size;
-        let elements = self.finder().find((from, to)).await?;
-        self.on_update(elements);
-        Ok(())
+        let mut budget = InstructionBudget::new(cfg.instruction_budget);
+        loop {
+            let caught_up = self.index_chunk(&cfg).await?;
+            if caught_up || !budget.has_room() {
+                return Ok(());
+            }
+        }
     }
 }
 
@@ -204,4 +579,313 @@ mod tests {
         );
         assert_eq!(indexer.between(0, 100).unwrap(), HashMap::new());
     }
+
+    thread_local! {
+        static CHAIN: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+        static HEAD: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+    fn block_hash(number: u64) -> String {
+        CHAIN.with(|c| c.borrow()[number as usize].clone())
+    }
+    fn set_chain(fork: &str, from: u64) {
+        CHAIN.with(|c| {
+            let mut chain = c.borrow_mut();
+            chain.resize(30, String::new());
+            for n in from..30 {
+                chain[n as usize] = format!(
+                    "0x{}",
+                    hex::encode(H256::from_low_u64_be(n).as_bytes())
+                )
+                .replacen("0x00", &format!("0x{}", fork), 1);
+            }
+        });
+    }
+    fn find_logs(
+        from: u64,
+        to: u64,
+        _: CallOptions,
+    ) -> BoxFuture<'static, Result<HashMap<u64, Vec<EventLog>>, Error>> {
+        // an event is emitted at block 8
+        let result = (from..=to)
+            .filter(|n| *n == 8)
+            .map(|n| {
+                let log: ic_web3_rs::types::Log = serde_json::from_value(json!({
+                    "address": "0x0000000000000000000000000000000000000000",
+                    "topics": [],
+                    "data": "0x",
+                    "blockHash": block_hash(n),
+                    "blockNumber": format!("0x{:x}", n),
+                }))
+                .unwrap();
+                let event = EventLog {
+                    event: ic_web3_rs::ethabi::Log { params: vec![] },
+                    log,
+                };
+                (n, vec![event])
+            })
+            .collect();
+        Box::pin(async move { Ok(result) })
+    }
+    fn find_block(
+        number: u64,
+        _: CallOptions,
+    ) -> BoxFuture<'static, Result<Option<BlockHeader>, Error>> {
+        let header = (number < 30).then(|| BlockHeader {
+            number,
+            hash: block_hash(number),
+            parent_hash: if number == 0 {
+                String::new()
+            } else {
+                block_hash(number - 1)
+            },
+        });
+        Box::pin(async move { Ok(header) })
+    }
+    fn find_head(_: CallOptions) -> BoxFuture<'static, Result<u64, Error>> {
+        let head = HEAD.with(|h| h.get());
+        Box::pin(async move { Ok(head) })
+    }
+    fn tracked_hash(number: u64) -> Option<String> {
+        storage::get_block_hash(number)
+    }
+
+    #[test]
+    fn test_indexer_rollback_on_reorg() {
+        set_chain("aa", 0);
+        HEAD.with(|h| h.set(30));
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_block_finder(find_block, Some(CallOptions::default()))
+            .with_head_finder(find_head, Some(CallOptions::default()));
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(4),
+            confirmations: Some(10),
+            instruction_budget: None,
+        };
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 10);
+        assert_eq!(tracked_hash(8), Some(block_hash(8)));
+        // blocks without logs are tracked too
+        assert_eq!(tracked_hash(6), Some(block_hash(6)));
+
+        // blocks from 7 are replaced, so 6 is the common ancestor
+        let orphaned = block_hash(8);
+        set_chain("bb", 7);
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 11);
+        assert_eq!(tracked_hash(6), Some(block_hash(6)));
+        assert_ne!(tracked_hash(8), Some(orphaned));
+        assert_eq!(tracked_hash(8), Some(block_hash(8)));
+        assert_eq!(tracked_hash(11), Some(block_hash(11)));
+        assert_eq!(indexer.between(0, 12).unwrap().len(), 1);
+
+        // hashes deeper than the confirmation depth are pruned
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 20);
+        assert_eq!(tracked_hash(8), None);
+        assert_eq!(tracked_hash(10), Some(block_hash(10)));
+    }
+
+    #[test]
+    fn test_indexer_find_by_index() {
+        set_chain("aa", 0);
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_indexes(vec![("value", StoreIndex::new(22))]);
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(10),
+            confirmations: None,
+            instruction_budget: None,
+        };
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        let found = indexer.find("value", &Token::from("dummy")).unwrap();
+        assert_eq!(found.keys().collect::<Vec<_>>(), vec![&8]);
+        assert!(indexer.find("other", &Token::from("dummy")).is_err());
+
+        // pruned events are removed from the index too
+        let policy = RetentionPolicy {
+            max_entries: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(indexer.prune(&policy, 10), 1);
+        assert!(indexer
+            .find("value", &Token::from("dummy"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_indexer_requires_block_finder_with_confirmations() {
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()));
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(4),
+            confirmations: Some(10),
+            instruction_budget: None,
+        };
+        assert!(futures::executor::block_on(indexer.index(cfg)).is_err());
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_block_finder(find_block, Some(CallOptions::default()));
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(4),
+            confirmations: Some(10),
+            instruction_budget: None,
+        };
+        assert!(futures::executor::block_on(indexer.index(cfg)).is_err());
+    }
+
+    #[test]
+    fn test_indexer_clamps_range_to_head() {
+        set_chain("aa", 0);
+        HEAD.with(|h| h.set(12));
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_block_finder(find_block, Some(CallOptions::default()))
+            .with_head_finder(find_head, Some(CallOptions::default()));
+        assert!(indexer.get_lag().is_err());
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(100),
+            confirmations: Some(3),
+            instruction_budget: None,
+        };
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 9);
+        assert_eq!(indexer.get_lag().unwrap(), 3);
+        // in stable memory, so the lag is known right after an upgrade
+        assert_eq!(storage::get_indexer_state(OBSERVED_HEAD_STATE), Some(12));
+
+        // nothing to index until the head moves forward
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 9);
+
+        HEAD.with(|h| h.set(20));
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 17);
+        assert_eq!(indexer.get_lag().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_indexer_moves_past_empty_chunks() {
+        set_chain("aa", 0);
+        HEAD.with(|h| h.set(25));
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_head_finder(find_head, Some(CallOptions::default()));
+        let cfg = IndexingConfig {
+            start_from: 10,
+            chunk_size: Some(4),
+            confirmations: None,
+            instruction_budget: Some(1),
+        };
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 25);
+        assert_eq!(indexer.between(0, 26).unwrap().len(), 0);
+
+        HEAD.with(|h| h.set(27));
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 27);
+    }
+
+    #[test]
+    fn test_indexer_catch_up() {
+        set_chain("aa", 0);
+        HEAD.with(|h| h.set(25));
+        let indexer = Web3Indexer::<SampleStruct>::new(find_logs, Some(CallOptions::default()))
+            .with_block_finder(find_block, Some(CallOptions::default()))
+            .with_head_finder(find_head, Some(CallOptions::default()));
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(4),
+            confirmations: Some(2),
+            instruction_budget: Some(u64::MAX),
+        };
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        assert_eq!(indexer.get_last_indexed().unwrap(), 23);
+        assert_eq!(indexer.between(0, 24).unwrap().len(), 1);
+    }
+
+    fn find_logs_limited(
+        from: u64,
+        to: u64,
+        call_options: CallOptions,
+    ) -> BoxFuture<'static, Result<HashMap<u64, Vec<EventLog>>, Error>> {
+        if to - from > 3 {
+            return Box::pin(async move {
+                Err(Error::OtherError(
+                    "query returned more than 10000 results".to_string(),
+                ))
+            });
+        }
+        find_logs(from, to, call_options)
+    }
+
+    #[test]
+    fn test_is_range_too_large() {
+        assert!(is_range_too_large(&Error::OtherError(
+            "(code: -32005, message: query returned more than 10000 results, data: None)"
+                .to_string()
+        )));
+        assert!(is_range_too_large(&Error::OtherError(
+            "Http body exceeds size limit of 2000000 bytes.".to_string()
+        )));
+        assert!(!is_range_too_large(&Error::OtherError(
+            "connection refused".to_string()
+        )));
+        assert!(!is_range_too_large(&Error::OtherError(
+            "(code: 429, message: Too many requests, daily limit exceeded)".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_indexer_bisects_rejected_ranges() {
+        set_chain("aa", 0);
+        let indexer =
+            Web3Indexer::<SampleStruct>::new(find_logs_limited, Some(CallOptions::default()))
+                .with_contract("0x01".to_string());
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(20),
+            confirmations: None,
+            instruction_budget: None,
+        };
+        futures::executor::block_on(indexer.index(cfg.clone())).unwrap();
+        assert_eq!(indexer.between(0, 22).unwrap().len(), 1);
+        assert_eq!(indexer.chunk_size(20), 2);
+        // checkpoints hold cursors only
+        assert!(storage::checkpoints()
+            .iter()
+            .all(|(name, _)| !name.starts_with(CHUNK_SIZE_STATE_PREFIX)));
+
+        // grows back after a successful call
+        futures::executor::block_on(indexer.index(cfg)).unwrap();
+        assert_eq!(indexer.chunk_size(20), 4);
+        assert_eq!(
+            Web3Indexer::<SampleStruct>::new(find_logs_limited, Some(CallOptions::default()))
+                .chunk_size(20),
+            20
+        );
+    }
+
+    #[test]
+    fn test_indexer_fails_on_rejected_block() {
+        let indexer = Web3Indexer::<SampleStruct>::new(
+            |_, _, _| {
+                Box::pin(async move {
+                    Err(Error::OtherError(
+                        "query returned more than 10000 results".to_string(),
+                    ))
+                })
+            },
+            Some(CallOptions::default()),
+        );
+        let cfg = IndexingConfig {
+            start_from: 1,
+            chunk_size: Some(4),
+            confirmations: None,
+            instruction_budget: None,
+        };
+        assert!(futures::executor::block_on(indexer.index(cfg)).is_err());
+    }
 }